@@ -6,13 +6,16 @@ import { join } from "path";
 
 import {
   ExtensionContext,
+  Location,
+  Position,
+  Range,
   RelativePattern,
+  Selection,
   Uri,
   window,
   workspace,
   WorkspaceFolder,
   Disposable,
-  ConfigurationChangeEvent,
   FileSystemWatcher,
   commands,
   ConfigurationTarget,
@@ -27,8 +30,14 @@ import {
   TransportKind,
   MessageActionItem,
   DocumentSelector,
+  DidChangeConfigurationNotification,
 } from "vscode-languageclient/node";
 
+interface LspRange {
+  start: { line: number; character: number };
+  end: { line: number; character: number };
+}
+
 const extensionName = "Kanata Configuration Language";
 const extensionId = "vscode-kanata";
 const outputChannel = window.createOutputChannel(extensionName);
@@ -66,6 +75,48 @@ export async function activate(ctx: ExtensionContext): Promise<void> {
   );
   ctx.subscriptions.push(cmd1);
 
+  // Used by the server to let user pick a name for a newly created symbol
+  // (e.g. after extracting a deflayer slot into an alias).
+  const cmd2 = commands.registerCommand(
+    "vscode-kanata.renameSymbolAt",
+    async (uri: string, position: { line: number; character: number }) => {
+      const editor = await window.showTextDocument(Uri.parse(uri));
+      const pos = new Position(position.line, position.character);
+      editor.selection = new Selection(pos, pos);
+      await commands.executeCommand("editor.action.rename");
+    },
+  );
+  ctx.subscriptions.push(cmd2);
+
+  // Used by the server in code lenses that list references of a symbol.
+  const cmd3 = commands.registerCommand(
+    "vscode-kanata.showReferences",
+    async (
+      uri: string,
+      position: { line: number; character: number },
+      locations: { uri: string; range: LspRange }[],
+    ) => {
+      await commands.executeCommand(
+        "editor.action.showReferences",
+        Uri.parse(uri),
+        new Position(position.line, position.character),
+        locations.map(
+          (x) =>
+            new Location(
+              Uri.parse(x.uri),
+              new Range(
+                x.range.start.line,
+                x.range.start.character,
+                x.range.end.line,
+                x.range.end.character,
+              ),
+            ),
+        ),
+      );
+    },
+  );
+  ctx.subscriptions.push(cmd3);
+
   ext = new Extension(ctx);
   await ext.start();
   ctx.subscriptions.push(ext);
@@ -86,28 +137,25 @@ class Extension implements Disposable {
     this.toDisposeOnRestart = [];
     this.ctx = ctx;
 
+    // Kanata files in added folders need to be opened and watched too.
     this.ctx.subscriptions.push(
-      workspace.onDidChangeConfiguration(this.restart()),
+      workspace.onDidChangeWorkspaceFolders(async () => {
+        outputChannel.appendLine("workspace folders have changed!");
+        await this.stop();
+        this.dispose();
+        await this.start();
+      }),
     );
   }
 
   async start() {
-    const openedWorkspaces = workspace.workspaceFolders;
-    let root: WorkspaceFolder | undefined;
-    if (openedWorkspaces) {
-      if (openedWorkspaces.length >= 2) {
-        await window.showInformationMessage(
-          "Multiple workspaces are currently not supported, only the first workspace folder will be regarded.",
-        );
-      }
-      root = openedWorkspaces.at(0);
-    }
+    const openedWorkspaces = workspace.workspaceFolders ?? [];
 
     outputChannel.appendLine(
-      `starting with ${openedWorkspaces?.length || 0} opened workspaces`,
+      `starting with ${openedWorkspaces.length} opened workspaces`,
     );
 
-    await this.startClient(root);
+    await this.startClient(openedWorkspaces);
   }
 
   async stop() {
@@ -117,21 +165,20 @@ class Extension implements Disposable {
     await this.client?.stop();
   }
 
-  async startClient(root: WorkspaceFolder | undefined) {
+  async startClient(folders: readonly WorkspaceFolder[]) {
     const serverModulePath = this.ctx.asAbsolutePath(join("out", "server.js"));
 
-    let deleteWatcher: FileSystemWatcher | undefined = undefined;
-    let changeWatcher: FileSystemWatcher | undefined = undefined;
+    const deleteWatchers: FileSystemWatcher[] = [];
 
-    if (root !== undefined) {
-      deleteWatcher = workspace.createFileSystemWatcher(
-        kanataFilesInFolderPattern(root.uri),
+    for (const folder of folders) {
+      const deleteWatcher = workspace.createFileSystemWatcher(
+        kanataFilesInFolderPattern(folder.uri),
         true, // ignoreCreateEvents
         true, // ignoreChangeEvents
         false, // ignoreDeleteEvents
       );
-      changeWatcher = workspace.createFileSystemWatcher(
-        kanataFilesInFolderPattern(root.uri),
+      const changeWatcher = workspace.createFileSystemWatcher(
+        kanataFilesInFolderPattern(folder.uri),
         false, // ignoreCreateEvents
         false, // ignoreChangeEvents
         true, // ignoreDeleteEvents
@@ -144,6 +191,7 @@ class Extension implements Disposable {
       this.toDisposeOnRestart.push(changeWatcher.onDidCreate(openDocument));
       // [didChange]: https://code.visualstudio.com/api/references/vscode-api#workspace.onDidChangeTextDocument
       this.toDisposeOnRestart.push(changeWatcher.onDidChange(openDocument));
+      deleteWatchers.push(deleteWatcher);
     }
 
     const serverOpts: ServerOptions = {
@@ -153,25 +201,15 @@ class Extension implements Disposable {
 
     const clientOpts: LanguageClientOptions = {
       documentSelector: docSelector,
-      synchronize: { fileEvents: deleteWatcher },
+      synchronize: {
+        fileEvents: deleteWatchers,
+      },
       diagnosticCollectionName: extensionName,
-      workspaceFolder: root,
       outputChannel,
       initializationOptions: {
-        mainConfigFile: workspace
-          .getConfiguration()
-          .get<string>("vscode-kanata.mainConfigFile", ""),
-        includesAndWorkspaces: workspace
-          .getConfiguration()
-          .get<string>("vscode-kanata.includesAndWorkspaces", ""),
-        localKeysVariant: getLocalKeysVariant() as string,
-        format: getFormatterSettings(),
-        envVariables: workspace.getConfiguration().get<{
-          [id: string]: string;
-        }>("vscode-kanata.environmentVariables", {}),
-        dimInactiveConfigItems: workspace
-          .getConfiguration()
-          .get<boolean>("vscode-kanata.dimInactiveConfigItems", true),
+        ...getServerSettings(true),
+        // Commands like `vscode-kanata.showReferences` are registered above.
+        supportsClientCommands: true,
       },
     };
 
@@ -189,28 +227,30 @@ class Extension implements Disposable {
 
     this.toDisposeOnRestart.push(this.client);
 
-    if (root !== undefined) {
-      await openKanataFilesInFolder(root.uri);
-    } else {
-      // When file is opened in non-workspace mode, vscode will automatically
-      // call textDocument/didOpen, so no need to do anything here.
-    }
-  }
-
-  restart() {
-    return async (e: ConfigurationChangeEvent) => {
-      if (e.affectsConfiguration("vscode-kanata")) {
-        outputChannel.appendLine("vscode-kanata configuration has changed!");
-        await this.stop();
-        this.dispose();
-        outputChannel.clear();
-        await this.start();
-      } else {
-        outputChannel.appendLine(
-          "Settings changed but vscode-kanata configuration hasn't changed",
+    // Changed settings are sent to the server, which applies them without restarting.
+    // They're sent resolved the same way as on startup, since the server can't
+    // tell the OS when `localKeysVariant` is `auto`.
+    const client = this.client;
+    this.toDisposeOnRestart.push(
+      workspace.onDidChangeConfiguration(async (event) => {
+        if (!event.affectsConfiguration(extensionId)) {
+          return;
+        }
+        const settings = getServerSettings(
+          event.affectsConfiguration(`${extensionId}.localKeysVariant`),
         );
-      }
-    };
+        await client.sendNotification(
+          DidChangeConfigurationNotification.type,
+          { settings: { [extensionId]: settings } },
+        );
+      }),
+    );
+
+    // When file is opened in non-workspace mode, vscode will automatically
+    // call textDocument/didOpen, so no need to do anything there.
+    for (const folder of folders) {
+      await openKanataFilesInFolder(folder.uri);
+    }
   }
 
   dispose() {
@@ -262,8 +302,31 @@ type LocalKeysVariant =
   | "deflocalkeys-macos"
   | "deflocalkeys-winiov2";
 
+// Gets settings sent to the server, both on startup and when they change.
+function getServerSettings(promptIfUndetectable: boolean) {
+  return {
+    mainConfigFile: workspace
+      .getConfiguration()
+      .get<string | string[]>("vscode-kanata.mainConfigFile", ""),
+    includesAndWorkspaces: workspace
+      .getConfiguration()
+      .get<string>("vscode-kanata.includesAndWorkspaces", ""),
+    localKeysVariant: getLocalKeysVariant(promptIfUndetectable) as string,
+    format: getFormatterSettings(),
+    envVariables: workspace.getConfiguration().get<{
+      [id: string]: string;
+    }>("vscode-kanata.environmentVariables", {}),
+    dimInactiveConfigItems: workspace
+      .getConfiguration()
+      .get<boolean>("vscode-kanata.dimInactiveConfigItems", true),
+    logLevel: workspace
+      .getConfiguration()
+      .get<string>("vscode-kanata.logLevel", "info"),
+  };
+}
+
 // Gets localkeys variant from config and when set to auto, detects it based on current OS.
-function getLocalKeysVariant(): LocalKeysVariant {
+function getLocalKeysVariant(promptIfUndetectable: boolean): LocalKeysVariant {
   const localKeysVariant = workspace
     .getConfiguration()
     .get<string>("vscode-kanata.localKeysVariant", "");
@@ -275,11 +338,13 @@ function getLocalKeysVariant(): LocalKeysVariant {
       case "darwin":
         return "deflocalkeys-macos";
       default: // Catches both unsupported systems as well as windows, since there are 3 possible variants for windows.
-        showLocalkeysManualInterventionNeeded()
-          .then(null)
-          .catch((e) => {
-            outputChannel.appendLine(`error: ${e}`);
-          });
+        if (promptIfUndetectable) {
+          showLocalkeysManualInterventionNeeded()
+            .then(null)
+            .catch((e) => {
+              outputChannel.appendLine(`error: ${e}`);
+            });
+        }
         // Use 'deflocalkeys-win' as a fallback, since that's the most common variant, I guess.
         return "deflocalkeys-win";
     }
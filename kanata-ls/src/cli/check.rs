@@ -0,0 +1,222 @@
+use std::{
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
+
+use anyhow::{anyhow, bail, Context};
+use lsp_types::{Diagnostic, PublishDiagnosticsParams, Url};
+
+use crate::{
+    diagnostic_from_inactive_code, diagnostics_from_parse_error,
+    helpers::{slice_rc_str, CustomParseError, Diagnostics, KlsParserOutput},
+    Config, DefLocalKeysVariant, Kanata,
+};
+
+use super::sarif::sarif_log;
+
+enum OutputFormat {
+    /// rustc-style errors.
+    Text,
+    /// `PublishDiagnosticsParams` per file, one per line.
+    Json,
+    /// SARIF 2.1.0 log, e.g. for GitHub code scanning.
+    Sarif,
+}
+
+pub fn run(args: &[String]) -> anyhow::Result<ExitCode> {
+    let mut env_vars = vec![];
+    let mut def_local_keys_variant = Config::default().def_local_keys_variant;
+    let mut format = OutputFormat::Text;
+    let mut paths = vec![];
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--env" => {
+                let Some(var) = args.next() else {
+                    bail!("check: --env requires a KEY=VAL argument");
+                };
+                let Some((key, value)) = var.split_once('=') else {
+                    bail!("check: invalid --env argument, expected KEY=VAL: {var}");
+                };
+                env_vars.push((key.to_string(), value.to_string()));
+            }
+            "--localkeys" => {
+                let Some(variant) = args.next() else {
+                    bail!("check: --localkeys requires a variant, e.g. deflocalkeys-linux");
+                };
+                def_local_keys_variant = parse_local_keys_variant(variant)?;
+            }
+            "--format" => {
+                format = match args.next().map(String::as_str) {
+                    Some("text") => OutputFormat::Text,
+                    Some("json") => OutputFormat::Json,
+                    Some("sarif") => OutputFormat::Sarif,
+                    _ => bail!("check: --format requires one of: text, json, sarif"),
+                };
+            }
+            x if x.starts_with("--") => bail!("check: unknown option: {x}"),
+            x => paths.push(x),
+        }
+    }
+    if paths.is_empty() {
+        bail!("check: no files given");
+    }
+
+    let kanata = Kanata::new(def_local_keys_variant, env_vars);
+    let mut errors = vec![];
+    let mut diagnostics = Diagnostics::new();
+    for path in paths {
+        let path = Path::new(path);
+        let src = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        let output = kanata.parse_from_disk(path, &src);
+        add_diagnostics(&mut diagnostics, path, &output)?;
+        if let KlsParserOutput::Err { errors: errs } = output {
+            errors.extend(errs);
+        }
+    }
+
+    match format {
+        OutputFormat::Text => {
+            for err in &errors {
+                eprintln!("{}", render_error(err));
+            }
+            if !errors.is_empty() {
+                eprintln!(
+                    "error: could not check config due to {} previous error{}",
+                    errors.len(),
+                    if errors.len() == 1 { "" } else { "s" }
+                );
+            }
+        }
+        OutputFormat::Json => {
+            for params in diagnostics.values() {
+                println!("{}", serde_json::to_string(params)?);
+            }
+        }
+        OutputFormat::Sarif => {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&sarif_log(&diagnostics))?
+            );
+        }
+    }
+
+    if !errors.is_empty() {
+        return Ok(ExitCode::FAILURE);
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Adds diagnostics of a parsed main config file, including inactive code hints,
+/// grouped by file. The main file is always added, even if it has no diagnostics.
+fn add_diagnostics(
+    diagnostics: &mut Diagnostics,
+    main_cfg_file: &Path,
+    output: &KlsParserOutput,
+) -> anyhow::Result<()> {
+    let mut add = |file_name: &str, diags: Vec<Diagnostic>| -> anyhow::Result<()> {
+        let url = file_url(main_cfg_file, file_name)?;
+        diagnostics
+            .entry(url.clone())
+            .or_insert_with(|| PublishDiagnosticsParams::new(url, vec![], None))
+            .diagnostics
+            .extend(diags);
+        Ok(())
+    };
+
+    add(&main_cfg_file.to_string_lossy(), vec![])?;
+    match output {
+        KlsParserOutput::Ok { inactive_codes, .. } => {
+            for inactive in inactive_codes {
+                add(
+                    &inactive.span.file_name(),
+                    vec![diagnostic_from_inactive_code(inactive)],
+                )?;
+            }
+        }
+        KlsParserOutput::Err { errors } => {
+            for err in errors {
+                add(&err.span.file_name(), diagnostics_from_parse_error(err))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns URL of a file from a span. Included files are relative to the main file.
+fn file_url(main_cfg_file: &Path, span_file_name: &str) -> anyhow::Result<Url> {
+    let path = if Path::new(span_file_name) == main_cfg_file {
+        main_cfg_file.to_path_buf()
+    } else {
+        main_cfg_file
+            .parent()
+            .unwrap_or(Path::new(""))
+            .join(span_file_name)
+    };
+    let path: PathBuf = match path.canonicalize() {
+        Ok(path) => path,
+        Err(_) => std::env::current_dir()?.join(path),
+    };
+    Url::from_file_path(&path).map_err(|_| anyhow!("invalid path: {}", path.display()))
+}
+
+fn parse_local_keys_variant(s: &str) -> anyhow::Result<DefLocalKeysVariant> {
+    let name = if s.starts_with("deflocalkeys-") {
+        s.to_string()
+    } else {
+        format!("deflocalkeys-{s}")
+    };
+    serde_json::from_value(serde_json::Value::String(name)).map_err(|_| {
+        anyhow!("check: unknown --localkeys variant: {s} (expected one of: win, wintercept, linux, macos, winiov2)")
+    })
+}
+
+/// Formats an error the way rustc does, with the line it points to underlined.
+fn render_error(err: &CustomParseError) -> String {
+    let span = &err.span;
+    let mut out = format!("error: {}\n", err.msg);
+    if span.file_content.is_empty() {
+        out.push_str(&format!("  --> {}", span.file_name()));
+        return out;
+    }
+
+    let line_end = span.file_content[span.start.line_beginning..]
+        .find('\n')
+        .map(|i| span.start.line_beginning + i)
+        .unwrap_or(span.file_content.len());
+    let line = slice_rc_str(&span.file_content, span.start.line_beginning, line_end)
+        .trim_end_matches('\r');
+    let before_span = slice_rc_str(
+        &span.file_content,
+        span.start.line_beginning,
+        span.start.absolute,
+    );
+    // Multi-line spans are underlined only until the end of their first line.
+    let underlined = slice_rc_str(
+        &span.file_content,
+        span.start.absolute,
+        span.end.absolute.min(line_end),
+    );
+
+    let line_number = (span.start.line + 1).to_string();
+    let gutter = " ".repeat(line_number.len());
+    out.push_str(&format!(
+        "{gutter}--> {}:{}:{}\n",
+        span.file_name(),
+        line_number,
+        before_span.chars().count() + 1
+    ));
+    out.push_str(&format!("{gutter} |\n"));
+    out.push_str(&format!("{line_number} | {}\n", line.replace('\t', "    ")));
+    out.push_str(&format!(
+        "{gutter} | {}{}",
+        " ".repeat(display_width(before_span)),
+        "^".repeat(display_width(underlined).max(1))
+    ));
+    out
+}
+
+fn display_width(s: &str) -> usize {
+    s.chars().map(|c| if c == '\t' { 4 } else { 1 }).sum()
+}
@@ -0,0 +1,161 @@
+use itertools::Itertools;
+
+/// Number of unchanged lines shown around each change.
+const CONTEXT_LINES: usize = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Line<'a> {
+    Unchanged(&'a str),
+    Removed(&'a str),
+    Added(&'a str),
+}
+
+/// Returns a unified diff (like `diff -u`) between `old` and `new`,
+/// or an empty string if they're the same.
+pub fn unified_diff(old: &str, new: &str, old_name: &str, new_name: &str) -> String {
+    let old_lines = old.split_inclusive('\n').collect_vec();
+    let new_lines = new.split_inclusive('\n').collect_vec();
+    let lines = diff_lines(&old_lines, &new_lines);
+
+    let changed = lines
+        .iter()
+        .positions(|line| !matches!(line, Line::Unchanged(_)))
+        .collect_vec();
+    if changed.is_empty() {
+        return String::new();
+    }
+
+    // Group changes that are close enough to share context lines into hunks.
+    let mut hunks: Vec<(usize, usize)> = vec![];
+    for i in changed {
+        let start = i.saturating_sub(CONTEXT_LINES);
+        let end = (i + CONTEXT_LINES + 1).min(lines.len());
+        match hunks.last_mut() {
+            Some((_, last_end)) if *last_end >= start => *last_end = end,
+            _ => hunks.push((start, end)),
+        }
+    }
+
+    let mut out = format!("--- {old_name}\n+++ {new_name}\n");
+    for (start, end) in hunks {
+        let old_before = lines[..start]
+            .iter()
+            .filter(|line| !matches!(line, Line::Added(_)))
+            .count();
+        let new_before = lines[..start]
+            .iter()
+            .filter(|line| !matches!(line, Line::Removed(_)))
+            .count();
+        let old_len = lines[start..end]
+            .iter()
+            .filter(|line| !matches!(line, Line::Added(_)))
+            .count();
+        let new_len = lines[start..end]
+            .iter()
+            .filter(|line| !matches!(line, Line::Removed(_)))
+            .count();
+        // Empty ranges start at the line before them.
+        let hunk_start = |before: usize, len: usize| if len == 0 { before } else { before + 1 };
+        out.push_str(&format!(
+            "@@ -{},{} +{},{} @@\n",
+            hunk_start(old_before, old_len),
+            old_len,
+            hunk_start(new_before, new_len),
+            new_len,
+        ));
+        for line in &lines[start..end] {
+            let (prefix, text) = match line {
+                Line::Unchanged(text) => (' ', text),
+                Line::Removed(text) => ('-', text),
+                Line::Added(text) => ('+', text),
+            };
+            out.push(prefix);
+            out.push_str(text);
+            if !text.ends_with('\n') {
+                out.push_str("\n\\ No newline at end of file\n");
+            }
+        }
+    }
+    out
+}
+
+/// Diffs lines using their longest common subsequence.
+fn diff_lines<'a>(old: &[&'a str], new: &[&'a str]) -> Vec<Line<'a>> {
+    let prefix_len = old
+        .iter()
+        .zip(new.iter())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let suffix_len = old[prefix_len..]
+        .iter()
+        .rev()
+        .zip(new[prefix_len..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let old_mid = &old[prefix_len..old.len() - suffix_len];
+    let new_mid = &new[prefix_len..new.len() - suffix_len];
+
+    // lcs[i][j] is the length of the LCS of `old_mid[i..]` and `new_mid[j..]`.
+    let width = new_mid.len() + 1;
+    let mut lcs = vec![0u32; (old_mid.len() + 1) * width];
+    for i in (0..old_mid.len()).rev() {
+        for j in (0..new_mid.len()).rev() {
+            lcs[i * width + j] = if old_mid[i] == new_mid[j] {
+                lcs[(i + 1) * width + j + 1] + 1
+            } else {
+                lcs[(i + 1) * width + j].max(lcs[i * width + j + 1])
+            };
+        }
+    }
+
+    let mut result = old[..prefix_len]
+        .iter()
+        .map(|line| Line::Unchanged(line))
+        .collect_vec();
+    let (mut i, mut j) = (0, 0);
+    while i < old_mid.len() || j < new_mid.len() {
+        if i < old_mid.len() && j < new_mid.len() && old_mid[i] == new_mid[j] {
+            result.push(Line::Unchanged(old_mid[i]));
+            i += 1;
+            j += 1;
+        } else if i < old_mid.len()
+            && (j == new_mid.len() || lcs[(i + 1) * width + j] >= lcs[i * width + j + 1])
+        {
+            result.push(Line::Removed(old_mid[i]));
+            i += 1;
+        } else {
+            result.push(Line::Added(new_mid[j]));
+            j += 1;
+        }
+    }
+    result.extend(
+        old[old.len() - suffix_len..]
+            .iter()
+            .map(|line| Line::Unchanged(line)),
+    );
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unified_diff() {
+        assert_eq!(unified_diff("a\nb\n", "a\nb\n", "x", "x"), "");
+
+        let old = "1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n11\n12\n";
+        let new = "1\n2\n3\n4\nfive\n6\n7\n8\n9\n10\n12\n13";
+        assert_eq!(
+            unified_diff(old, new, "a.kbd", "a.kbd"),
+            "--- a.kbd\n+++ a.kbd\n\
+             @@ -2,11 +2,11 @@\n 2\n 3\n 4\n-5\n+five\n 6\n 7\n 8\n 9\n 10\n-11\n 12\n+13\n\
+             \\ No newline at end of file\n"
+        );
+
+        assert_eq!(
+            unified_diff("a\n", "", "x", "x"),
+            "--- x\n+++ x\n@@ -1,1 +0,0 @@\n-a\n"
+        );
+    }
+}
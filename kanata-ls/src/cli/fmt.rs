@@ -0,0 +1,309 @@
+use std::{
+    collections::HashMap,
+    io::Read,
+    iter,
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
+
+use anyhow::{anyhow, bail, Context};
+use lsp_types::{FormattingOptions, TextDocumentItem, Url};
+
+use crate::{
+    formatter::{
+        defsrc_layout::{get_defsrc_layout, LineEndingSequence},
+        ext_tree::{parse_into_ext_tree_and_root_span, ExtParseTree},
+        Formatter,
+    },
+    helpers::{expand_tilde, path_to_url, Documents},
+    ExtensionFormatterOptions, WorkspaceOptions,
+};
+
+use super::diff::unified_diff;
+
+const STDIN_NAME: &str = "<stdin>";
+
+pub fn run(args: &[String]) -> anyhow::Result<ExitCode> {
+    let mut check = false;
+    let mut options = ExtensionFormatterOptions {
+        enable: true,
+        use_defsrc_layout_on_deflayers: true,
+    };
+    let mut tab_size = 4;
+    let mut paths = vec![];
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--check" => check = true,
+            "--no-defsrc-layout" => options.use_defsrc_layout_on_deflayers = false,
+            "--tab-size" => {
+                tab_size = args
+                    .next()
+                    .and_then(|x| x.parse().ok())
+                    .ok_or_else(|| anyhow!("fmt: --tab-size requires a number"))?;
+            }
+            "-" => paths.push("-"),
+            x if x.starts_with('-') => bail!("fmt: unknown option: {x}"),
+            x => paths.push(x),
+        }
+    }
+    if paths.is_empty() {
+        paths.push(".");
+    }
+
+    let formatter = Formatter {
+        options,
+        remove_extra_empty_lines: false,
+    };
+
+    let files = paths
+        .iter()
+        .filter(|x| **x != "-")
+        .map(|x| kbd_files(Path::new(x)))
+        .collect::<anyhow::Result<Vec<_>>>()?
+        .concat();
+    let main_config_files = main_config_files(&files);
+
+    let mut unformatted_count = 0;
+    for path in paths {
+        if path == "-" {
+            let mut src = String::new();
+            std::io::stdin()
+                .read_to_string(&mut src)
+                .context("failed to read stdin")?;
+            let formatted = format(&formatter, &src, tab_size, None, None)
+                .with_context(|| format!("failed to format {STDIN_NAME}"))?;
+            if !check {
+                print!("{formatted}");
+            } else if formatted != src {
+                print!("{}", unified_diff(&src, &formatted, STDIN_NAME, STDIN_NAME));
+                unformatted_count += 1;
+            }
+            continue;
+        }
+
+        for file in kbd_files(Path::new(path))? {
+            let name = file.display();
+            let src =
+                std::fs::read_to_string(&file).with_context(|| format!("failed to read {name}"))?;
+            let main_config_file = file
+                .canonicalize()
+                .ok()
+                .and_then(|x| main_config_files.get(&x));
+            let formatted = format(&formatter, &src, tab_size, Some(&file), main_config_file)
+                .with_context(|| format!("failed to format {name}"))?;
+            if formatted == src {
+                continue;
+            }
+            if check {
+                let name = name.to_string();
+                print!("{}", unified_diff(&src, &formatted, &name, &name));
+                unformatted_count += 1;
+            } else {
+                std::fs::write(&file, formatted)
+                    .with_context(|| format!("failed to write {name}"))?;
+                println!("formatted {name}");
+            }
+        }
+    }
+
+    if unformatted_count > 0 {
+        eprintln!(
+            "kanata-ls: {unformatted_count} file{} would be reformatted",
+            if unformatted_count == 1 { "" } else { "s" }
+        );
+        return Ok(ExitCode::FAILURE);
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Formats `src` the same way as the language server does. `path` is used to find
+/// included files, which `defsrc` may be in. Without it, includes aren't followed.
+/// If the file is included by `main_config_file`, `defsrc` of that config is used.
+fn format(
+    formatter: &Formatter,
+    src: &str,
+    tab_size: u32,
+    path: Option<&Path>,
+    main_config_file: Option<&PathBuf>,
+) -> anyhow::Result<String> {
+    let (mut tree, _) = parse_into_ext_tree_and_root_span(src).map_err(|e| anyhow!("{}", e.msg))?;
+
+    let defsrc_layout = match path {
+        Some(path) => defsrc_layout_from_disk(
+            main_config_file.map_or(path, |x| x.as_path()),
+            path,
+            src,
+            &tree,
+            tab_size,
+        ),
+        None => get_defsrc_layout(
+            &WorkspaceOptions::Single { root: None },
+            &Documents::new(),
+            tab_size,
+            &Url::parse("file:///").expect("valid url"),
+            &tree,
+        ),
+    }
+    .unwrap_or_else(|e| {
+        eprintln!(
+            "kanata-ls: warning: not applying defsrc layout to {}: {e:#}",
+            path.map(|p| p.display().to_string())
+                .unwrap_or(STDIN_NAME.to_string())
+        );
+        None
+    });
+
+    let line_endings = if src.contains("\r\n") {
+        LineEndingSequence::CRLF
+    } else {
+        LineEndingSequence::LF
+    };
+
+    formatter.format(
+        &mut tree,
+        &FormattingOptions {
+            tab_size,
+            insert_spaces: true,
+            ..Default::default()
+        },
+        defsrc_layout.as_deref(),
+        line_endings,
+    );
+    Ok(tree.to_string())
+}
+
+/// Finds `defsrc` layout in the main config file or in the files it includes,
+/// which `path` (with content `src`) is one of, or the main config file itself.
+fn defsrc_layout_from_disk(
+    main_config_file: &Path,
+    path: &Path,
+    src: &str,
+    tree: &ExtParseTree,
+    tab_size: u32,
+) -> anyhow::Result<Option<Vec<Vec<usize>>>> {
+    let path = path.canonicalize()?;
+    let main_config_file = main_config_file.canonicalize()?;
+    let (Some(dir), Some(file_name)) = (main_config_file.parent(), main_config_file.file_name())
+    else {
+        bail!("invalid path: {}", main_config_file.display());
+    };
+    let project_root =
+        Url::from_directory_path(dir).map_err(|_| anyhow!("invalid path: {}", dir.display()))?;
+    let file_uri =
+        Url::from_file_path(&path).map_err(|_| anyhow!("invalid path: {}", path.display()))?;
+
+    let main_config_tree = if main_config_file == path {
+        tree.clone()
+    } else {
+        let text = std::fs::read_to_string(&main_config_file)
+            .with_context(|| format!("failed to read {}", main_config_file.display()))?;
+        parse_into_ext_tree_and_root_span(&text)
+            .map_err(|e| anyhow!("{}: {}", main_config_file.display(), e.msg))?
+            .0
+    };
+
+    let mut documents = Documents::new();
+    for file in main_config_tree
+        .includes()?
+        .into_iter()
+        .chain(iter::once(file_name.into()))
+    {
+        let uri = path_to_url(&file, &project_root)?;
+        let text = if uri == file_uri {
+            src.to_string()
+        } else {
+            let file = dir.join(expand_tilde(&file));
+            std::fs::read_to_string(&file)
+                .with_context(|| format!("failed to read {}", file.display()))?
+        };
+        documents.insert(
+            uri.clone(),
+            TextDocumentItem {
+                uri,
+                language_id: "kanata".to_string(),
+                version: 0,
+                text,
+            },
+        );
+    }
+
+    get_defsrc_layout(
+        &WorkspaceOptions::Workspace {
+            main_config_file: file_name.into(),
+            project_root,
+        },
+        &documents,
+        tab_size,
+        &file_uri,
+        tree,
+    )
+}
+
+/// Maps files included by other files to the files including them (main config files),
+/// so that included files are formatted with `defsrc` of their main config file,
+/// like in the editor. Files that include others are looked for among `files`
+/// and `.kbd` files next to them. Paths are canonical.
+fn main_config_files(files: &[PathBuf]) -> HashMap<PathBuf, PathBuf> {
+    let mut candidates: Vec<PathBuf> = files
+        .iter()
+        .filter_map(|file| file.canonicalize().ok())
+        .flat_map(|file| {
+            let siblings = file
+                .parent()
+                .and_then(|dir| std::fs::read_dir(dir).ok())
+                .into_iter()
+                .flatten()
+                .filter_map(|entry| Some(entry.ok()?.path()))
+                .filter(|x| x.extension().is_some_and(|ext| ext == "kbd"));
+            iter::once(file.clone()).chain(siblings).collect::<Vec<_>>()
+        })
+        .collect();
+    candidates.sort();
+    candidates.dedup();
+
+    let mut result = HashMap::new();
+    for candidate in candidates {
+        let Some(dir) = candidate.parent() else {
+            continue;
+        };
+        let Some(tree) = std::fs::read_to_string(&candidate)
+            .ok()
+            .and_then(|text| Some(parse_into_ext_tree_and_root_span(&text).ok()?.0))
+        else {
+            continue;
+        };
+        // Includes are relative to the main config file, like in kanata.
+        for include in tree.includes().unwrap_or_default() {
+            if let Ok(included) = dir.join(expand_tilde(&include)).canonicalize() {
+                result.entry(included).or_insert_with(|| candidate.clone());
+            }
+        }
+    }
+    result
+}
+
+/// Returns `path` if it's a file, or all `.kbd` files in it if it's a directory.
+/// Hidden directories, like `.git`, are skipped.
+fn kbd_files(path: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    if !path.is_dir() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+    let mut files = vec![];
+    let mut entries = std::fs::read_dir(path)
+        .with_context(|| format!("failed to read directory {}", path.display()))?
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let entry_path = entry.path();
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        if entry_path.is_dir() {
+            files.extend(kbd_files(&entry_path)?);
+        } else if entry_path.extension().is_some_and(|ext| ext == "kbd") {
+            files.push(entry_path);
+        }
+    }
+    Ok(files)
+}
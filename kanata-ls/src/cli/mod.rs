@@ -0,0 +1,61 @@
+//! Subcommands available when kanata-ls is run from the command line with arguments,
+//! instead of being started by an editor.
+
+use std::process::ExitCode;
+
+use crate::logger::{self, LogLevel};
+
+mod check;
+mod diff;
+mod fmt;
+mod organize;
+mod sarif;
+
+const USAGE: &str = "\
+Usage: kanata-ls [COMMAND]
+
+Runs the language server over stdio when no command is given.
+
+Commands:
+  check [--env KEY=VAL]... [--localkeys VARIANT] [--format FORMAT] <FILE>...
+      Parse and validate configs, reading included files from disk.
+      Exits with a non-zero code if any errors were found.
+      VARIANT is one of: win, wintercept, linux, macos, winiov2.
+      FORMAT is one of:
+        text   errors in rustc style, on stderr (default)
+        json   LSP PublishDiagnosticsParams of each file, one per line
+        sarif  SARIF 2.1.0 log, e.g. for GitHub code scanning
+  fmt [--check] [--no-defsrc-layout] [--tab-size N] [PATH|-]...
+      Format files in place, like the language server does. Directories are
+      searched for .kbd files; the current directory is used if none given.
+      With --check, print a diff instead and exit with a non-zero code
+      if any file isn't formatted. `-` formats stdin to stdout.
+  organize [--by-first-use] <FILE>...
+      Merge defalias blocks and sort defalias/defvar entries in place.
+      Sorts alphabetically, or by first use in layers with --by-first-use.
+  help
+      Print this message.";
+
+/// Runs a subcommand if `args` (without the program name) start with one.
+/// Returns `None` otherwise, so that the language server gets started instead,
+/// even if a client passes its own arguments (e.g. `--stdio`).
+pub fn run(args: &[String]) -> Option<ExitCode> {
+    let (command, args) = args.split_first()?;
+    // Only warnings and errors of the server are printed alongside the output.
+    // The language server sets its own level on initialization.
+    logger::set_level(LogLevel::Warn);
+    let result = match command.as_str() {
+        "check" => check::run(args),
+        "fmt" => fmt::run(args),
+        "organize" => organize::run(args),
+        "help" | "--help" | "-h" => {
+            println!("{USAGE}");
+            Ok(ExitCode::SUCCESS)
+        }
+        _ => return None,
+    };
+    Some(result.unwrap_or_else(|e| {
+        eprintln!("kanata-ls: error: {e:#}");
+        ExitCode::FAILURE
+    }))
+}
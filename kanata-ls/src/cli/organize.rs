@@ -0,0 +1,37 @@
+use std::process::ExitCode;
+
+use anyhow::{anyhow, bail, Context};
+
+use crate::formatter::{
+    ext_tree::parse_into_ext_tree_and_root_span, sort_definitions::DefinitionsOrder,
+};
+
+pub fn run(args: &[String]) -> anyhow::Result<ExitCode> {
+    let mut order = DefinitionsOrder::Alphabetical;
+    let mut paths = vec![];
+    for arg in args {
+        match arg.as_str() {
+            "--by-first-use" => order = DefinitionsOrder::FirstUseInLayers,
+            x if x.starts_with("--") => bail!("organize: unknown option: {x}"),
+            x => paths.push(x),
+        }
+    }
+    if paths.is_empty() {
+        bail!("organize: no files given");
+    }
+
+    for path in paths {
+        let src =
+            std::fs::read_to_string(path).with_context(|| format!("failed to read {path}"))?;
+        let (mut tree, _) = parse_into_ext_tree_and_root_span(&src)
+            .map_err(|e| anyhow!("failed to parse {path}: {}", e.msg))?;
+        tree.sort_definitions(order)
+            .with_context(|| format!("failed to organize {path}"))?;
+        let organized = tree.to_string();
+        if organized != src {
+            std::fs::write(path, organized).with_context(|| format!("failed to write {path}"))?;
+            println!("organized {path}");
+        }
+    }
+    Ok(ExitCode::SUCCESS)
+}
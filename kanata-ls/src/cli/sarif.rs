@@ -0,0 +1,114 @@
+//! Conversion of diagnostics to [SARIF 2.1.0](https://docs.oasis-open.org/sarif/sarif/v2.1.0/sarif-v2.1.0.html),
+//! the format that GitHub code scanning and other review tools read.
+
+use std::path::Path;
+
+use lsp_types::{Diagnostic, DiagnosticSeverity, DiagnosticTag, Url};
+use serde_json::{json, Value};
+
+use crate::{helpers::Diagnostics, KANATA_PARSER_HELP};
+
+const RULE_PARSE_ERROR: &str = "kanata-parser/error";
+const RULE_EXTENSION_ERROR: &str = "kanata-ls/error";
+const RULE_INACTIVE_CODE: &str = "kanata-parser/inactive-code";
+
+pub fn sarif_log(diagnostics: &Diagnostics) -> Value {
+    let cwd = std::env::current_dir()
+        .and_then(|dir| dir.canonicalize())
+        .ok();
+    let results: Vec<Value> = diagnostics
+        .values()
+        .flat_map(|params| {
+            let uri = artifact_uri(&params.uri, cwd.as_deref());
+            params
+                .diagnostics
+                .iter()
+                .filter_map(move |diagnostic| sarif_result(diagnostic, &uri))
+        })
+        .collect();
+
+    json!({
+        "$schema": "https://json.schemastore.org/sarif-2.1.0.json",
+        "version": "2.1.0",
+        "runs": [{
+            "tool": {
+                "driver": {
+                    "name": "kanata-ls",
+                    "informationUri": "https://github.com/rszyma/vscode-kanata",
+                    "rules": [
+                        {
+                            "id": RULE_PARSE_ERROR,
+                            "shortDescription": { "text": "Config error reported by kanata parser" },
+                            "help": { "text": KANATA_PARSER_HELP },
+                        },
+                        {
+                            "id": RULE_EXTENSION_ERROR,
+                            "shortDescription": { "text": "Config can't be analyzed by kanata-ls" },
+                        },
+                        {
+                            "id": RULE_INACTIVE_CODE,
+                            "shortDescription": { "text": "Config item inactive on the current platform or environment" },
+                            "defaultConfiguration": { "level": "note" },
+                        },
+                    ],
+                },
+            },
+            // LSP positions are in UTF-16 code units too.
+            "columnKind": "utf16CodeUnits",
+            "results": results,
+        }],
+    })
+}
+
+/// Diagnostics without a source (the hint where to ask for help) are left out,
+/// their text is in the rule's help instead.
+fn sarif_result(diagnostic: &Diagnostic, uri: &str) -> Option<Value> {
+    let is_inactive_code = diagnostic
+        .tags
+        .as_ref()
+        .is_some_and(|tags| tags.contains(&DiagnosticTag::UNNECESSARY));
+    let rule_id = match diagnostic.source.as_deref()? {
+        _ if is_inactive_code => RULE_INACTIVE_CODE,
+        "kanata-ls" => RULE_EXTENSION_ERROR,
+        _ => RULE_PARSE_ERROR,
+    };
+    let level = match diagnostic.severity {
+        Some(DiagnosticSeverity::ERROR) | None => "error",
+        Some(DiagnosticSeverity::WARNING) => "warning",
+        Some(_) => "note",
+    };
+    let range = diagnostic.range;
+    Some(json!({
+        "ruleId": rule_id,
+        "level": level,
+        "message": { "text": diagnostic.message },
+        "locations": [{
+            "physicalLocation": {
+                "artifactLocation": { "uri": uri },
+                "region": {
+                    "startLine": range.start.line + 1,
+                    "startColumn": range.start.character + 1,
+                    "endLine": range.end.line + 1,
+                    "endColumn": range.end.character + 1,
+                },
+            },
+        }],
+        "properties": { "source": diagnostic.source },
+    }))
+}
+
+/// Paths under the current directory are made relative to it,
+/// so that they match paths in the repository.
+fn artifact_uri(url: &Url, cwd: Option<&Path>) -> String {
+    let relative_path = url.to_file_path().ok().and_then(|path| {
+        let relative = path.strip_prefix(cwd?).ok()?;
+        Some(
+            relative
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/"),
+        )
+    });
+    relative_path.unwrap_or_else(|| url.to_string())
+}
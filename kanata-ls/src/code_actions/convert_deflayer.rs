@@ -0,0 +1,257 @@
+use std::collections::HashMap;
+
+use itertools::Itertools;
+use lsp_types::{CodeAction, CodeActionKind, Position, TextEdit, Url};
+
+use crate::{
+    formatter::{
+        defsrc_layout::LineEndingSequence,
+        ext_tree::{
+            parse_into_ext_tree_and_root_span, Comment, Expr, ExtParseTree, Metadata, NodeList,
+            ParseTreeNode,
+        },
+    },
+    log,
+};
+
+use super::code_action;
+
+/// `deflayermap` key that maps all `defsrc` keys not mapped explicitly.
+const MAP_DEFSRC_KEYS_WILDCARD: &str = "_";
+/// `deflayermap` keys that map keys not in `defsrc` (which `deflayer` can't express).
+/// `__` maps only those, while `___` maps every key not mapped explicitly.
+const MAP_NON_DEFSRC_KEYS_WILDCARDS: [&str; 2] = ["__", "___"];
+
+/// Comments attached to `node`, each one prefixed with a space. Line comments keep
+/// their newline, so that nothing written after them gets commented out.
+fn comments_of(node: &ParseTreeNode) -> String {
+    node.pre_metadata
+        .iter()
+        .chain(&node.post_metadata)
+        .filter_map(|x| match x {
+            Metadata::Comment(Comment::LineComment(x)) => {
+                Some(format!(" {}\n", x.trim_end_matches(['\r', '\n'])))
+            }
+            Metadata::Comment(Comment::BlockComment(x)) => Some(format!(" {x}")),
+            Metadata::Whitespace(_) => None,
+        })
+        .collect()
+}
+
+fn deflayer_to_deflayermap(items: &NodeList, defsrc_keys: &[String]) -> Option<String> {
+    let layer_name = match &items.get(1)?.expr {
+        Expr::Atom(x) => x,
+        Expr::List(_) => return None,
+    };
+    let slots: Vec<&ParseTreeNode> = items.iter().skip(2).collect();
+    if slots.len() != defsrc_keys.len() {
+        log!("convert deflayer: deflayer vs defsrc item count mismatch");
+        return None;
+    }
+
+    let mut result = format!("(deflayermap ({layer_name})");
+    for node in items.iter().take(2) {
+        result.push_str(comments_of(node).trim_end_matches('\n'));
+    }
+    for (key, node) in defsrc_keys.iter().zip(slots) {
+        let action = node.expr.to_string();
+        let comments = comments_of(node);
+        if action != "_" {
+            result.push_str(&format!("\n  {key} {action}"));
+        } else if comments.is_empty() {
+            continue;
+        } else {
+            // Keep comments of transparent keys, even though the keys are dropped.
+            result.push_str("\n ");
+        }
+        result.push_str(comments.trim_end_matches('\n'));
+    }
+    result.push_str("\n)");
+    Some(result)
+}
+
+fn deflayermap_to_deflayer(
+    items: &NodeList,
+    defsrc_keys: &[String],
+    defsrc_layout: Option<&[Vec<usize>]>,
+) -> Option<String> {
+    let layer_name = match &items.get(1)?.expr {
+        Expr::List(name) if name.len() == 1 => name.get(0)?.expr.to_string(),
+        _ => return None,
+    };
+    let pairs: Vec<&ParseTreeNode> = items.iter().skip(2).collect();
+    let pairs = pairs.chunks_exact(2);
+    if !pairs.remainder().is_empty() {
+        return None;
+    }
+
+    // Comments that don't belong to any slot go right after the layer name.
+    let mut header_comments: String = items.iter().take(2).map(comments_of).collect();
+    let mut default_action = "_".to_string();
+    let mut slots: Vec<Option<(String, String)>> = vec![None; defsrc_keys.len()];
+    for pair in pairs {
+        let (key, action) = (pair[0].expr.to_string(), pair[1].expr.to_string());
+        let comments = comments_of(pair[0]) + &comments_of(pair[1]);
+        if key == MAP_DEFSRC_KEYS_WILDCARD {
+            default_action = action;
+            header_comments.push_str(&comments);
+            continue;
+        }
+        if MAP_NON_DEFSRC_KEYS_WILDCARDS.contains(&key.as_str()) {
+            log!("convert deflayermap: can't express mapping of non-defsrc keys in deflayer");
+            return None;
+        }
+        match defsrc_keys.iter().position(|x| *x == key) {
+            Some(i) => slots[i] = Some((action, comments)),
+            None => {
+                log!("convert deflayermap: key not in defsrc: {}", key);
+                return None;
+            }
+        }
+    }
+
+    let slots: String = slots
+        .into_iter()
+        .map(|x| x.unwrap_or_else(|| (default_action.clone(), String::new())))
+        .map(|(action, comments)| format!(" {action}{comments}"))
+        .collect();
+    let header_comments = header_comments.trim_end_matches('\n');
+    let deflayer = format!(
+        "(deflayer {layer_name}{header_comments}\n {}\n)",
+        slots.trim_end_matches('\n')
+    );
+
+    let layout = match defsrc_layout {
+        Some(x) if x.len() == defsrc_keys.len() => x,
+        _ => return Some(deflayer),
+    };
+    let mut tree = match parse_into_ext_tree_and_root_span(&deflayer) {
+        Ok((tree, _)) => tree,
+        Err(_) => return Some(deflayer),
+    };
+    tree.use_defsrc_layout_on_deflayers(layout, 4, true, LineEndingSequence::LF);
+    Some(tree.to_string())
+}
+
+/// Returns a code action that converts `deflayer` at `pos` into `deflayermap`,
+/// or `deflayermap` into `deflayer`.
+pub fn convert_deflayer(
+    tree: &ExtParseTree,
+    doc_uri: &Url,
+    pos: Position,
+    defsrc_keys: &[String],
+    defsrc_layout: Option<&[Vec<usize>]>,
+) -> Option<CodeAction> {
+    let block_index = *tree.path_to_node_by_lsp_position(pos).ok()?.first()? as usize;
+    if defsrc_keys.iter().duplicates().next().is_some() {
+        log!("convert deflayer: defsrc has duplicate keys");
+        return None;
+    }
+    let (title, new_text) = match tree.toplevel_block(block_index)? {
+        ("deflayer", items) => (
+            "Convert to deflayermap",
+            deflayer_to_deflayermap(items, defsrc_keys)?,
+        ),
+        ("deflayermap", items) => (
+            "Convert to deflayer",
+            deflayermap_to_deflayer(items, defsrc_keys, defsrc_layout)?,
+        ),
+        _ => return None,
+    };
+    let range = tree.lsp_range_of_node(&[block_index]).ok()?;
+    Some(code_action(
+        title.to_string(),
+        CodeActionKind::REFACTOR_REWRITE,
+        HashMap::from([(doc_uri.clone(), vec![TextEdit { range, new_text }])]),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{code_actions::apply_edits, formatter::ext_tree::parse_into_ext_tree};
+
+    fn convert(src: &str, pos: Position) -> Option<String> {
+        let uri = Url::parse("file:///main.kbd").unwrap();
+        let tree = parse_into_ext_tree(src).unwrap();
+        let defsrc_keys = tree.defsrc_keys().unwrap().unwrap();
+        let layout = tree.defsrc_layout(4).unwrap().unwrap();
+        let action = convert_deflayer(&tree, &uri, pos, &defsrc_keys, Some(&layout))?;
+        Some(apply_edits(
+            src,
+            &action.edit.unwrap().changes.unwrap()[&uri],
+        ))
+    }
+
+    #[test]
+    fn test_convert_deflayer() {
+        assert_eq!(
+            convert(
+                "(defsrc\n  a b\n  c d\n)\n(deflayer nav _ lft\n  (tap-hold 1 1 x y) _)",
+                Position::new(4, 3),
+            )
+            .unwrap(),
+            "(defsrc\n  a b\n  c d\n)\n(deflayermap (nav)\n  b lft\n  c (tap-hold 1 1 x y)\n)",
+        );
+        assert_eq!(
+            convert(
+                "(defsrc\n  a b\n  c d\n)\n(deflayermap (nav)\n  b lft\n  c (tap-hold 1 1 x y)\n)",
+                Position::new(5, 2),
+            )
+            .unwrap(),
+            "(defsrc\n  a b\n  c d\n)\n(deflayer nav\n  _ lft\n  (tap-hold 1 1 x y) _\n)",
+        );
+        assert_eq!(
+            convert(
+                "(defsrc a b)\n(deflayermap (nav) _ XX b lft)",
+                Position::new(1, 1),
+            )
+            .unwrap(),
+            "(defsrc a b)\n(deflayer nav\n  XX lft)",
+        );
+        // Can't be expressed as deflayer.
+        assert_eq!(
+            convert("(defsrc a b)\n(deflayermap (nav) c x)", Position::new(1, 1)),
+            None
+        );
+        for wildcard in MAP_NON_DEFSRC_KEYS_WILDCARDS {
+            assert_eq!(
+                convert(
+                    &format!("(defsrc a b)\n(deflayermap (nav) {wildcard} x)"),
+                    Position::new(1, 1)
+                ),
+                None
+            );
+        }
+        // A duplicated defsrc key can't be told apart in deflayermap.
+        assert_eq!(
+            convert("(defsrc a a)\n(deflayer nav x y)", Position::new(1, 1)),
+            None
+        );
+        // Item count mismatch.
+        assert_eq!(
+            convert("(defsrc a b)\n(deflayer nav x)", Position::new(1, 1)),
+            None
+        );
+    }
+
+    #[test]
+    fn test_convert_deflayer_keeps_comments() {
+        assert_eq!(
+            convert(
+                "(defsrc a b c)\n(deflayer nav ;; nav\n  x #| b |# _ ;; c\n  y ;; end\n)",
+                Position::new(1, 1),
+            )
+            .unwrap(),
+            "(defsrc a b c)\n(deflayermap (nav) ;; nav\n  a x #| b |#\n  ;; c\n  c y ;; end\n)",
+        );
+        assert_eq!(
+            convert(
+                "(defsrc a b)\n(deflayermap (nav) ;; nav\n  b y ;; b\n  _ x #| rest |#\n)",
+                Position::new(1, 1),
+            )
+            .unwrap(),
+            "(defsrc a b)\n(deflayer nav ;; nav\n #| rest |#\n  x y ;; b\n)",
+        );
+    }
+}
@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+
+use lsp_types::{CodeAction, CodeActionKind, Command, Position, TextEdit, Url};
+
+use crate::formatter::ext_tree::{Expr, ExtParseTree};
+
+use super::{add_alias_entry, code_action, position_after_edit, RENAME_SYMBOL_AT_COMMAND};
+
+/// Name given to the extracted alias, before user renames it.
+const DEFAULT_ALIAS_NAME: &str = "new-alias";
+
+/// Returns path to the innermost list at `pos`, if it's located in a `deflayer` slot.
+fn list_in_deflayer_slot_at(tree: &ExtParseTree, pos: Position) -> Option<Vec<usize>> {
+    let mut path: Vec<usize> = tree
+        .path_to_node_by_lsp_position(pos)
+        .ok()?
+        .into_iter()
+        .map(|x| x as usize)
+        .collect();
+    if let Ok(Expr::Atom(_)) = tree.get_node_by_path(&path) {
+        path.pop();
+    }
+    match tree.toplevel_block(*path.first()?) {
+        Some(("deflayer", _)) => {}
+        _ => return None,
+    };
+    // Skip "deflayer" keyword and layer name.
+    if path.len() < 2 || path[1] < 2 {
+        return None;
+    }
+    Some(path)
+}
+
+/// Returns a code action that moves the list expression at `pos` in a `deflayer` slot
+/// into `defalias`, and replaces it with a reference to the new alias.
+/// After the edit is applied, client is asked to start renaming the new alias,
+/// if it supports client-side commands.
+pub fn extract_alias(
+    tree: &ExtParseTree,
+    doc_uri: &Url,
+    pos: Position,
+    is_alias_defined: &dyn Fn(&str) -> bool,
+    supports_client_commands: bool,
+) -> Option<CodeAction> {
+    let path = list_in_deflayer_slot_at(tree, pos)?;
+    let expr = tree.get_node_by_path(&path).ok()?.to_string();
+    let expr_range = tree.lsp_range_of_node(&path).ok()?;
+
+    let name = (1..)
+        .map(|i| match i {
+            1 => DEFAULT_ALIAS_NAME.to_string(),
+            i => format!("{DEFAULT_ALIAS_NAME}-{i}"),
+        })
+        .find(|x| !is_alias_defined(x))?;
+
+    let alias_edit = add_alias_entry(tree, &format!("{name} {expr}"), path[0])?;
+    let reference_edit = TextEdit {
+        range: expr_range,
+        new_text: format!("@{name}"),
+    };
+
+    // Position of the alias name (after "@") in the document with edits applied.
+    let mut rename_pos = Position::new(expr_range.start.line, expr_range.start.character + 1);
+    if alias_edit.range.end <= expr_range.start {
+        rename_pos = position_after_edit(rename_pos, &alias_edit);
+    }
+
+    let mut action = code_action(
+        format!("Extract into alias `{name}`"),
+        CodeActionKind::REFACTOR_EXTRACT,
+        HashMap::from([(doc_uri.clone(), vec![alias_edit, reference_edit])]),
+    );
+    if !supports_client_commands {
+        return Some(action);
+    }
+    action.command = Some(Command {
+        title: "Rename alias".to_string(),
+        command: RENAME_SYMBOL_AT_COMMAND.to_string(),
+        arguments: Some(vec![
+            serde_json::to_value(doc_uri).ok()?,
+            serde_json::to_value(rename_pos).ok()?,
+        ]),
+    });
+    Some(action)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{code_actions::apply_edits, formatter::ext_tree::parse_into_ext_tree};
+
+    #[test]
+    fn test_extract_alias() {
+        let uri = Url::parse("file:///main.kbd").unwrap();
+        let cases = [
+            (
+                "(defsrc a b)\n(deflayer base (tap-hold 200 200 a lctl) b)",
+                Position::new(1, 20),
+                Some((
+                    "(defsrc a b)\n(defalias\n  new-alias-2 (tap-hold 200 200 a lctl)\n)\n\n(deflayer base @new-alias-2 b)",
+                    Position::new(5, 16),
+                )),
+            ),
+            (
+                "(defalias\n  x y\n)\n(deflayer base a (multi b (layer-switch new-alias)))",
+                Position::new(3, 28),
+                Some((
+                    "(defalias\n  x y\n  new-alias-2 (layer-switch new-alias)\n)\n(deflayer base a (multi b @new-alias-2))",
+                    Position::new(4, 27),
+                )),
+            ),
+            // Only lists in deflayer slots can be extracted.
+            ("(deflayer base a b)", Position::new(0, 15), None),
+            ("(defalias x (multi a b))", Position::new(0, 14), None),
+        ];
+
+        for (src, pos, expected) in cases {
+            let tree = parse_into_ext_tree(src).unwrap();
+            let action = extract_alias(&tree, &uri, pos, &|name| name == "new-alias", true);
+            let (expected_src, expected_rename_pos) = match expected {
+                Some(x) => x,
+                None => {
+                    assert!(action.is_none(), "{src}");
+                    continue;
+                }
+            };
+            let action = action.expect(src);
+
+            let edits = &action.edit.unwrap().changes.unwrap()[&uri];
+            let result = apply_edits(src, edits);
+            assert_eq!(result, expected_src);
+
+            let args = action.command.unwrap().arguments.unwrap();
+            let rename_pos: Position = serde_json::from_value(args[1].clone()).unwrap();
+            assert_eq!(rename_pos, expected_rename_pos, "{src}");
+        }
+    }
+
+    #[test]
+    fn test_extract_alias_without_client_commands() {
+        let uri = Url::parse("file:///main.kbd").unwrap();
+        let tree = parse_into_ext_tree("(defsrc a)\n(deflayer base (multi a b))").unwrap();
+        let action = extract_alias(&tree, &uri, Position::new(1, 16), &|_| false, false)
+            .expect("list in deflayer slot");
+        assert!(action.edit.is_some());
+        assert!(action.command.is_none());
+    }
+}
@@ -0,0 +1,287 @@
+use std::collections::HashMap;
+
+use lsp_types::{CodeAction, CodeActionKind, Command, Position, Range, TextEdit, Url};
+
+use crate::formatter::ext_tree::{Expr, ExtParseTree, NodeList};
+
+use super::{code_action, RENAME_SYMBOL_AT_COMMAND};
+
+/// Names given to the extracted template/variable, before user renames it.
+const DEFAULT_TEMPLATE_NAME: &str = "new-template";
+const DEFAULT_VARIABLE_NAME: &str = "new-var";
+
+/// Returns a string describing structure of the list: nesting and the first item
+/// of every list, which usually says what the list does (e.g. `tap-hold`).
+/// Lists with the same shape differ only in atoms that aren't the first item.
+fn shape(list: &NodeList) -> String {
+    let items: Vec<String> = list
+        .iter()
+        .enumerate()
+        .map(|(i, node)| match &node.expr {
+            Expr::List(xs) => shape(xs),
+            Expr::Atom(x) if i == 0 => x.clone(),
+            Expr::Atom(_) => "_".to_string(),
+        })
+        .collect();
+    format!("({})", items.join(" "))
+}
+
+/// Returns atoms that aren't the first item of a list, depth-first.
+fn variable_atoms(list: &NodeList) -> Vec<&str> {
+    let mut result = vec![];
+    for (i, node) in list.iter().enumerate() {
+        match &node.expr {
+            Expr::List(xs) => result.extend(variable_atoms(xs)),
+            Expr::Atom(_) if i == 0 => {}
+            Expr::Atom(x) => result.push(x.as_str()),
+        }
+    }
+    result
+}
+
+/// Replaces atoms returned by [`variable_atoms`] using `replace` (called with atom index).
+fn replace_variable_atoms(list: &mut NodeList, replace: &mut dyn FnMut(usize) -> Option<String>) {
+    fn go(list: &mut NodeList, replace: &mut dyn FnMut(usize) -> Option<String>, i: &mut usize) {
+        for (j, node) in list.iter_mut().enumerate() {
+            match &mut node.expr {
+                Expr::List(xs) => go(xs, replace, i),
+                Expr::Atom(_) if j == 0 => {}
+                Expr::Atom(x) => {
+                    if let Some(new) = replace(*i) {
+                        *x = new;
+                    }
+                    *i += 1;
+                }
+            }
+        }
+    }
+    go(list, replace, &mut 0)
+}
+
+/// Collects paths of all lists that aren't top-level blocks.
+/// Template definitions are skipped, because they're already templates.
+fn nested_lists(tree: &ExtParseTree) -> Vec<(Vec<usize>, &NodeList)> {
+    fn go<'a>(
+        list: &'a NodeList,
+        path: &mut Vec<usize>,
+        out: &mut Vec<(Vec<usize>, &'a NodeList)>,
+    ) {
+        for (i, node) in list.iter().enumerate() {
+            if let Expr::List(xs) = &node.expr {
+                path.push(i);
+                out.push((path.clone(), xs));
+                go(xs, path, out);
+                path.pop();
+            }
+        }
+    }
+
+    let mut result = vec![];
+    for (i, node) in tree.0.iter().enumerate() {
+        if let Some(("deftemplate", _)) = tree.toplevel_block(i) {
+            continue;
+        }
+        if let Expr::List(xs) = &node.expr {
+            go(xs, &mut vec![i], &mut result);
+        }
+    }
+    result
+}
+
+fn unique_name(base: &str, is_defined: &dyn Fn(&str) -> bool) -> Option<String> {
+    (1..)
+        .map(|i| match i {
+            1 => base.to_string(),
+            i => format!("{base}-{i}"),
+        })
+        .find(|x| !is_defined(x))
+}
+
+/// Returns a code action that extracts lists with the same shape as the list at `pos`
+/// into a `deftemplate`, with parameters for atoms that differ between them.
+/// If the lists are identical, they're extracted into a `defvar` instead.
+/// After the edit is applied, client is asked to start renaming the new template
+/// or variable, if it supports client-side commands.
+pub fn extract_template(
+    tree: &ExtParseTree,
+    doc_uri: &Url,
+    pos: Position,
+    is_template_defined: &dyn Fn(&str) -> bool,
+    is_variable_defined: &dyn Fn(&str) -> bool,
+    supports_client_commands: bool,
+) -> Option<CodeAction> {
+    let lists = nested_lists(tree);
+    let shapes: Vec<String> = lists.iter().map(|(_, list)| shape(list)).collect();
+    let path_at_pos: Vec<usize> = tree
+        .path_to_node_by_lsp_position(pos)
+        .ok()?
+        .into_iter()
+        .map(|x| x as usize)
+        .collect();
+
+    // Start from the innermost list at `pos` and go outwards,
+    // until a list that is repeated somewhere else is found.
+    let (similar, list): (Vec<&(Vec<usize>, &NodeList)>, &NodeList) = lists
+        .iter()
+        .zip(&shapes)
+        .filter(|((path, _), _)| path_at_pos.starts_with(path))
+        .rev()
+        .find_map(|((_, list), list_shape)| {
+            let similar: Vec<_> = lists
+                .iter()
+                .zip(&shapes)
+                .filter(|(_, x)| *x == list_shape)
+                .map(|(x, _)| x)
+                .collect();
+            (similar.len() >= 2).then_some((similar, *list))
+        })?;
+
+    let atoms_of_each: Vec<Vec<&str>> = similar.iter().map(|(_, x)| variable_atoms(x)).collect();
+    let differing: Vec<usize> = (0..atoms_of_each[0].len())
+        .filter(|&i| atoms_of_each.iter().any(|x| x[i] != atoms_of_each[0][i]))
+        .collect();
+
+    let first_block_index = similar.iter().map(|(path, _)| path[0]).min()?;
+    let insert_pos = tree.lsp_range_of_node(&[first_block_index]).ok()?.start;
+
+    let (title, definition, name_pos, call_sites): (String, String, Position, Vec<String>) =
+        if differing.is_empty() {
+            let name = unique_name(DEFAULT_VARIABLE_NAME, is_variable_defined)?;
+            (
+                format!(
+                    "Extract {} identical expressions into variable `{name}`",
+                    similar.len()
+                ),
+                format!("(defvar\n  {name} {}\n)\n\n", Expr::List(list.clone())),
+                Position::new(insert_pos.line + 1, 2),
+                vec![format!("${name}"); similar.len()],
+            )
+        } else {
+            let name = unique_name(DEFAULT_TEMPLATE_NAME, is_template_defined)?;
+            let params: Vec<String> = (1..=differing.len()).map(|i| format!("arg{i}")).collect();
+            let mut body = list.clone();
+            replace_variable_atoms(&mut body, &mut |i| {
+                let param_index = differing.iter().position(|x| *x == i)?;
+                Some(format!("${}", params[param_index]))
+            });
+            let call_sites = atoms_of_each
+                .iter()
+                .map(|atoms| {
+                    let args: Vec<&str> = differing.iter().map(|i| atoms[*i]).collect();
+                    format!("(t! {name} {})", args.join(" "))
+                })
+                .collect();
+            (
+                format!(
+                    "Extract {} similar expressions into template `{name}`",
+                    similar.len()
+                ),
+                format!(
+                    "(deftemplate {name} ({})\n  {}\n)\n\n",
+                    params.join(" "),
+                    Expr::List(body)
+                ),
+                Position::new(
+                    insert_pos.line,
+                    insert_pos.character + "(deftemplate ".len() as u32,
+                ),
+                call_sites,
+            )
+        };
+
+    let mut edits = vec![TextEdit {
+        range: Range::new(insert_pos, insert_pos),
+        new_text: definition,
+    }];
+    for ((path, _), new_text) in similar.iter().zip(call_sites) {
+        edits.push(TextEdit {
+            range: tree.lsp_range_of_node(path).ok()?,
+            new_text,
+        });
+    }
+
+    let mut action = code_action(
+        title,
+        CodeActionKind::REFACTOR_EXTRACT,
+        HashMap::from([(doc_uri.clone(), edits)]),
+    );
+    if !supports_client_commands {
+        return Some(action);
+    }
+    // Definition is inserted before any of the replaced expressions,
+    // so its position isn't affected by them.
+    action.command = Some(Command {
+        title: "Rename".to_string(),
+        command: RENAME_SYMBOL_AT_COMMAND.to_string(),
+        arguments: Some(vec![
+            serde_json::to_value(doc_uri).ok()?,
+            serde_json::to_value(name_pos).ok()?,
+        ]),
+    });
+    Some(action)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{code_actions::apply_edits, formatter::ext_tree::parse_into_ext_tree};
+
+    fn extract(src: &str, pos: Position) -> Option<(String, Position)> {
+        let uri = Url::parse("file:///main.kbd").unwrap();
+        let tree = parse_into_ext_tree(src).unwrap();
+        let action = extract_template(&tree, &uri, pos, &|_| false, &|_| false, true)?;
+        let result = apply_edits(src, &action.edit.unwrap().changes.unwrap()[&uri]);
+        let args = action.command.unwrap().arguments.unwrap();
+        Some((result, serde_json::from_value(args[1].clone()).unwrap()))
+    }
+
+    #[test]
+    fn test_extract_template() {
+        assert_eq!(
+            extract(
+                "(defsrc a s)\n(deflayer base\n  (tap-hold 200 200 a lmet)\n  (tap-hold 200 200 s lalt)\n)",
+                Position::new(2, 5),
+            ),
+            Some((
+                "(defsrc a s)\n(deftemplate new-template (arg1 arg2)\n  (tap-hold 200 200 $arg1 $arg2)\n)\n\n(deflayer base\n  (t! new-template a lmet)\n  (t! new-template s lalt)\n)".to_string(),
+                Position::new(1, 13),
+            ))
+        );
+        // The innermost list is repeated only once, so the outer one is used.
+        assert_eq!(
+            extract(
+                "(defalias\n  x (multi a (layer-while-held nav))\n  y (multi b (layer-while-held nav))\n)",
+                Position::new(1, 15),
+            ),
+            Some((
+                "(defvar\n  new-var (layer-while-held nav)\n)\n\n(defalias\n  x (multi a $new-var)\n  y (multi b $new-var)\n)".to_string(),
+                Position::new(1, 2),
+            ))
+        );
+        assert_eq!(
+            extract(
+                "(defalias\n  x (tap-hold 1 1 a b)\n  y (multi a b)\n)",
+                Position::new(1, 5),
+            ),
+            None,
+        );
+    }
+
+    #[test]
+    fn test_extract_template_without_client_commands() {
+        let uri = Url::parse("file:///main.kbd").unwrap();
+        let tree =
+            parse_into_ext_tree("(defsrc a b)\n(deflayer base (multi a b) (multi b a))").unwrap();
+        let action = extract_template(
+            &tree,
+            &uri,
+            Position::new(1, 16),
+            &|_| false,
+            &|_| false,
+            false,
+        )
+        .expect("similar lists");
+        assert!(action.edit.is_some());
+        assert!(action.command.is_none());
+    }
+}
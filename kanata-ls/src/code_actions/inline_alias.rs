@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+
+use lsp_types::{CodeAction, CodeActionKind, Position, Range, TextEdit, Url};
+
+use crate::{
+    formatter::ext_tree::{parse_into_ext_tree_and_root_span, Expr, ExtParseTree},
+    helpers::{DefinitionLocations, Documents, ReferenceKind, ReferenceLocations},
+    log, navigation,
+};
+
+use super::code_action;
+
+/// An alias entry in `defalias`.
+#[derive(Debug, PartialEq)]
+struct AliasEntry {
+    /// Action that the alias stands for, as written in the config.
+    action: String,
+    /// Range that removes the whole entry, along with whitespace before it.
+    removal_range: Range,
+}
+
+/// Finds the `defalias` entry of an alias whose name is located at `name_pos`.
+fn alias_entry(tree: &ExtParseTree, name_pos: Position) -> Option<AliasEntry> {
+    let path: Vec<usize> = tree
+        .path_to_node_by_lsp_position(name_pos)
+        .ok()?
+        .into_iter()
+        .map(|x| x as usize)
+        .collect();
+    let (&name_index, block_path) = path.split_last()?;
+    match tree.get_node_by_path(&path).ok()? {
+        Expr::Atom(_) => {}
+        Expr::List(_) => return None,
+    };
+    let block_head = [block_path, &[0]].concat();
+    let first_name_index = match tree.get_node_by_path(&block_head).ok()? {
+        Expr::Atom(x) if x == "defalias" => 1,
+        // Skip the environment condition.
+        Expr::Atom(x) if x == "defaliasenvcond" => 2,
+        _ => return None,
+    };
+    if name_index < first_name_index || (name_index - first_name_index) % 2 != 0 {
+        return None;
+    }
+
+    let action_path = [block_path, &[name_index + 1]].concat();
+    let action = tree.get_node_by_path(&action_path).ok()?.to_string();
+    let previous_end = tree
+        .lsp_range_of_node(&[block_path, &[name_index - 1]].concat())
+        .ok()?
+        .end;
+    let action_end = tree.lsp_range_of_node(&action_path).ok()?.end;
+    Some(AliasEntry {
+        action,
+        removal_range: Range::new(previous_end, action_end),
+    })
+}
+
+fn alias_entry_in_doc(
+    documents: &Documents,
+    doc_uri: &Url,
+    name_pos: Position,
+) -> Option<AliasEntry> {
+    let text = &documents.get(doc_uri)?.text;
+    let (tree, _) = parse_into_ext_tree_and_root_span(text)
+        .map_err(|e| log!("inline alias: failed to parse {}: {}", doc_uri, e.msg))
+        .ok()?;
+    alias_entry(&tree, name_pos)
+}
+
+/// On an alias reference, returns a code action that replaces the reference with
+/// the action the alias stands for. On an alias definition, returns a code action that
+/// does that for all references, and removes the definition.
+pub fn inline_alias(
+    pos: &Position,
+    source_doc: &Url,
+    documents: &Documents,
+    definition_locations_by_doc: &HashMap<Url, DefinitionLocations>,
+    reference_locations_by_doc: &HashMap<Url, ReferenceLocations>,
+    search_all_docs: bool, // Need to be set `true` for workspace mode and `false` otherwise.
+    path_to_url_fn: &dyn Fn(&str) -> anyhow::Result<Url>,
+) -> Option<CodeAction> {
+    let definition = definition_locations_by_doc
+        .get(source_doc)
+        .and_then(|defs| defs.get_definition_at_position(pos));
+
+    if let Some(definition) = definition {
+        if definition.ref_kind != ReferenceKind::Alias {
+            return None;
+        }
+        let entry = alias_entry_in_doc(documents, source_doc, definition.range.start)?;
+        let references = navigation::references_for_definition_at_pos(
+            pos,
+            source_doc,
+            definition_locations_by_doc,
+            reference_locations_by_doc,
+            search_all_docs,
+        )?;
+
+        let mut changes: HashMap<Url, Vec<TextEdit>> = HashMap::new();
+        for reference in references {
+            let url = path_to_url_fn(&reference.target_filename)
+                .map_err(|e| log!("inline alias: {}", e))
+                .ok()?;
+            changes.entry(url).or_default().push(TextEdit {
+                range: reference.target_range,
+                new_text: entry.action.clone(),
+            });
+        }
+        changes
+            .entry(source_doc.clone())
+            .or_default()
+            .push(TextEdit {
+                range: entry.removal_range,
+                new_text: String::new(),
+            });
+
+        return Some(code_action(
+            format!("Inline all uses of alias `{}`", definition.ref_name),
+            CodeActionKind::REFACTOR_INLINE,
+            changes,
+        ));
+    }
+
+    let link = navigation::goto_definition_for_token_at_pos(
+        pos,
+        source_doc,
+        definition_locations_by_doc,
+        reference_locations_by_doc,
+        search_all_docs,
+    )?;
+    if link.kind != ReferenceKind::Alias {
+        return None;
+    }
+    let definition_doc = path_to_url_fn(&link.target_filename)
+        .map_err(|e| log!("inline alias: {}", e))
+        .ok()?;
+    let entry = alias_entry_in_doc(documents, &definition_doc, link.target_range.start)?;
+
+    Some(code_action(
+        format!("Inline alias `{}`", link.name),
+        CodeActionKind::REFACTOR_INLINE,
+        HashMap::from([(
+            source_doc.clone(),
+            vec![TextEdit {
+                range: link.source_range,
+                new_text: entry.action,
+            }],
+        )]),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formatter::ext_tree::parse_into_ext_tree;
+
+    #[test]
+    fn test_alias_entry() {
+        let tree = parse_into_ext_tree(
+            "(defalias\n  a b\n  ;; comment\n  c (multi a\n    b)\n)\n(deflayer x @a)",
+        )
+        .unwrap();
+
+        assert_eq!(
+            alias_entry(&tree, Position::new(1, 2)),
+            Some(AliasEntry {
+                action: "b".to_string(),
+                removal_range: Range::new(Position::new(0, 9), Position::new(1, 5)),
+            })
+        );
+        assert_eq!(
+            alias_entry(&tree, Position::new(3, 2)),
+            Some(AliasEntry {
+                action: "(multi a\n    b)".to_string(),
+                removal_range: Range::new(Position::new(1, 5), Position::new(4, 6)),
+            })
+        );
+        // Not an alias name.
+        assert_eq!(alias_entry(&tree, Position::new(1, 4)), None);
+        assert_eq!(alias_entry(&tree, Position::new(6, 12)), None);
+    }
+}
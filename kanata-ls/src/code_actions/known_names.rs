@@ -0,0 +1,162 @@
+//! Names that kanata recognizes, used for suggesting replacements of misspelled ones.
+//! Names are checked by kanata-parser itself where it exposes them, so that suggestions
+//! don't drift from it.
+
+/// Characters that names of keys and actions are made of.
+const NAME_CHARS: &str = "abcdefghijklmnopqrstuvwxyz0123456789-";
+
+pub fn is_key_name(name: &str) -> bool {
+    kanata_parser::keys::str_to_oscode(name).is_some()
+}
+
+pub fn is_list_action(name: &str) -> bool {
+    kanata_parser::cfg::list_actions::is_list_action(name)
+}
+
+/// Actions that can be used as `deflayer` items without parentheses, besides key names.
+/// kanata-parser doesn't expose names of such actions, so they're listed here.
+const LAYER_ITEM_ACTIONS: &[&str] = &[
+    "_",
+    "‗",
+    "≝",
+    "XX",
+    "✗",
+    "∅",
+    "•",
+    "use-defsrc",
+    "lrld",
+    "lrld-next",
+    "lrnx",
+    "lrld-prev",
+    "lrpv",
+    "sldr",
+    "scnl",
+    "mlft",
+    "mouseleft",
+    "mrgt",
+    "mouseright",
+    "mmid",
+    "mousemid",
+    "mfwd",
+    "mouseforward",
+    "mbck",
+    "mousebackward",
+    "mltp",
+    "mrtp",
+    "mmtp",
+    "mftp",
+    "mbtp",
+    "rpt",
+    "repeat",
+    "rpt-key",
+    "rpt-any",
+    "dynamic-macro-record-stop",
+    "reverse-release-order",
+    "nop0",
+    "nop1",
+    "nop2",
+    "nop3",
+    "nop4",
+    "nop5",
+    "nop6",
+    "nop7",
+    "nop8",
+    "nop9",
+];
+
+/// Whether `name` can be used as a `deflayer` item, either as a key or an action like `XX`.
+pub fn is_layer_item(name: &str) -> bool {
+    is_key_name(name) || LAYER_ITEM_ACTIONS.contains(&name)
+}
+
+/// All names that differ from `name` by a single character, inserted, removed or replaced,
+/// or by two adjacent characters being swapped.
+fn single_edits(name: &str) -> Vec<String> {
+    let chars: Vec<char> = name.chars().collect();
+    let with = |start: usize, middle: &str, end: usize| -> String {
+        let mut edit: String = chars[..start].iter().collect();
+        edit.push_str(middle);
+        edit.extend(&chars[end..]);
+        edit
+    };
+    let mut edits = vec![];
+    for i in 0..=chars.len() {
+        for c in NAME_CHARS.chars() {
+            edits.push(with(i, &c.to_string(), i));
+            if i < chars.len() {
+                edits.push(with(i, &c.to_string(), i + 1));
+            }
+        }
+        if i < chars.len() {
+            edits.push(with(i, "", i + 1));
+        }
+        if i + 1 < chars.len() {
+            edits.push(with(i, &format!("{}{}", chars[i + 1], chars[i]), i + 2));
+        }
+    }
+    edits.sort();
+    edits.dedup();
+    edits.retain(|x| x != name && !x.is_empty());
+    edits
+}
+
+/// Number of single-character edits needed to turn `a` into `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev_row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut row = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = prev_row[j] + usize::from(ca != *cb);
+            row.push(substitution.min(prev_row[j + 1] + 1).min(row[j] + 1));
+        }
+        prev_row = row;
+    }
+    prev_row[b.len()]
+}
+
+/// Returns up to `max_results` names accepted by `is_known` that are a single typo away
+/// from `name`, best match first.
+pub fn closest_names(
+    name: &str,
+    is_known: impl Fn(&str) -> bool,
+    max_results: usize,
+) -> Vec<String> {
+    let mut matches: Vec<(usize, String)> = single_edits(name)
+        .into_iter()
+        .filter(|x| is_known(x))
+        .map(|x| (edit_distance(name, &x), x))
+        .collect();
+    matches.sort();
+    matches
+        .into_iter()
+        .take(max_results)
+        .map(|(_, x)| x)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_closest_names() {
+        let known = ["tap-hold", "tap-dance", "multi"];
+        let is_known = |x: &str| known.contains(&x);
+        assert_eq!(closest_names("tap-hlod", is_known, 3), vec!["tap-hold"]);
+        assert_eq!(closest_names("mult", is_known, 3), vec!["multi"]);
+        assert_eq!(closest_names("tap-dnace", is_known, 3), vec!["tap-dance"]);
+        assert!(closest_names("multi", is_known, 3).is_empty());
+        assert!(closest_names("foo", is_known, 3).is_empty());
+    }
+
+    #[test]
+    fn test_is_layer_item() {
+        assert!(is_layer_item("XX"));
+        assert!(is_layer_item("lrld"));
+        assert!(is_layer_item("nop9"));
+        assert!(!is_layer_item("nop10"));
+        assert!(!is_layer_item("tap-hold"));
+        assert_eq!(closest_names("lrdl", is_layer_item, 3), vec!["lrld"]);
+    }
+}
@@ -0,0 +1,180 @@
+use std::collections::{HashMap, HashSet};
+
+use lsp_types::{CodeAction, CodeActionKind, Position, Range, TextEdit, Url, WorkspaceEdit};
+
+use crate::formatter::ext_tree::ExtParseTree;
+
+pub mod convert_deflayer;
+pub mod extract_alias;
+pub mod extract_template;
+pub mod inline_alias;
+mod known_names;
+pub mod organize_definitions;
+pub mod quick_fix;
+
+/// Client-side command that starts renaming the symbol at given position.
+/// Arguments: document URI and position.
+const RENAME_SYMBOL_AT_COMMAND: &str = "vscode-kanata.renameSymbolAt";
+
+/// Checks if code actions of given `kind` were asked for.
+/// No filter (`None`) means that all kinds were asked for.
+pub fn is_kind_requested(only: &Option<Vec<CodeActionKind>>, kind: &CodeActionKind) -> bool {
+    match only {
+        Some(kinds) => kinds
+            .iter()
+            .any(|x| x == kind || kind.as_str().starts_with(&format!("{}.", x.as_str()))),
+        None => true,
+    }
+}
+
+fn code_action(
+    title: String,
+    kind: CodeActionKind,
+    changes: HashMap<Url, Vec<TextEdit>>,
+) -> CodeAction {
+    CodeAction {
+        title,
+        kind: Some(kind),
+        diagnostics: None,
+        edit: Some(WorkspaceEdit {
+            changes: Some(changes),
+            document_changes: None,
+            change_annotations: None,
+        }),
+        command: None,
+        is_preferred: None,
+        disabled: None,
+        data: None,
+    }
+}
+
+/// Merges actions with the same title, which come from each main config file that
+/// includes the document, e.g. when inlining an alias used by several of them.
+/// Actions that replace the same range with different text are dropped,
+/// since it's not clear which one is meant.
+pub fn merge_actions(actions: impl IntoIterator<Item = CodeAction>) -> Vec<CodeAction> {
+    let mut merged: Vec<CodeAction> = vec![];
+    let mut conflicting_titles: HashSet<String> = HashSet::new();
+    for action in actions {
+        let Some(existing) = merged.iter_mut().find(|x| x.title == action.title) else {
+            merged.push(action);
+            continue;
+        };
+        let existing_changes = existing
+            .edit
+            .get_or_insert_with(WorkspaceEdit::default)
+            .changes
+            .get_or_insert_with(HashMap::new);
+        let changes = action.edit.and_then(|x| x.changes).unwrap_or_default();
+        for (url, edits) in changes {
+            let existing_edits = existing_changes.entry(url).or_default();
+            for edit in edits {
+                match existing_edits.iter().find(|x| x.range == edit.range) {
+                    Some(x) if x.new_text != edit.new_text => {
+                        conflicting_titles.insert(action.title.clone());
+                    }
+                    Some(_) => {}
+                    None => existing_edits.push(edit),
+                }
+            }
+        }
+    }
+    merged.retain(|x| !conflicting_titles.contains(&x.title));
+    merged
+}
+
+fn indent_of(range: &Range) -> String {
+    " ".repeat(range.start.character as usize)
+}
+
+/// Returns an edit that adds `entry` (alias name and action) to the last `defalias` block
+/// in the tree. If there's no `defalias` block, it creates one right before the top-level
+/// block at `fallback_block_index`.
+///
+/// The last block is used, because the action may reference other aliases,
+/// which have to be declared before it.
+fn add_alias_entry(
+    tree: &ExtParseTree,
+    entry: &str,
+    fallback_block_index: usize,
+) -> Option<TextEdit> {
+    if let Some((block_index, items)) = tree.toplevel_blocks("defalias").last() {
+        let last_range = tree
+            .lsp_range_of_node(&[block_index, items.len() - 1])
+            .ok()?;
+        let indent = if items.len() > 2 {
+            let last_name = tree
+                .lsp_range_of_node(&[block_index, items.len() - 2])
+                .ok()?;
+            indent_of(&last_name)
+        } else {
+            "  ".to_string()
+        };
+        return Some(TextEdit {
+            range: Range::new(last_range.end, last_range.end),
+            new_text: format!("\n{indent}{entry}"),
+        });
+    }
+
+    let block_start = tree.lsp_range_of_node(&[fallback_block_index]).ok()?.start;
+    Some(TextEdit {
+        range: Range::new(block_start, block_start),
+        new_text: format!("(defalias\n  {entry}\n)\n\n"),
+    })
+}
+
+/// Returns where `pos` ends up after `edit` is applied.
+/// `edit` has to end before (or at) `pos`.
+fn position_after_edit(pos: Position, edit: &TextEdit) -> Position {
+    let Range { start, end } = edit.range;
+    let inserted_lines = edit.new_text.matches('\n').count() as u32;
+    let last_inserted_line_len = edit
+        .new_text
+        .rsplit('\n')
+        .next()
+        .map(|x| x.encode_utf16().count() as u32)
+        .unwrap_or_default();
+
+    if pos.line > end.line {
+        return Position::new(
+            pos.line - (end.line - start.line) + inserted_lines,
+            pos.character,
+        );
+    }
+    // `pos` is on the last line of replaced text.
+    let chars_after_edit = pos.character - end.character;
+    if inserted_lines == 0 {
+        Position::new(
+            start.line,
+            start.character + last_inserted_line_len + chars_after_edit,
+        )
+    } else {
+        Position::new(
+            start.line + inserted_lines,
+            last_inserted_line_len + chars_after_edit,
+        )
+    }
+}
+
+/// Applies non-overlapping `edits` to `src`.
+#[cfg(test)]
+pub fn apply_edits(src: &str, edits: &[TextEdit]) -> String {
+    let offset = |pos: Position| -> usize {
+        let line_start: usize = src
+            .split_inclusive('\n')
+            .take(pos.line as usize)
+            .map(str::len)
+            .sum();
+        line_start + pos.character as usize
+    };
+    let mut edits = edits.to_vec();
+    edits.sort_by_key(|x| x.range.start);
+    let mut result = src.to_string();
+    for edit in edits.iter().rev() {
+        result.replace_range(
+            offset(edit.range.start)..offset(edit.range.end),
+            &edit.new_text,
+        );
+    }
+    result
+}
@@ -0,0 +1,56 @@
+use std::collections::HashMap;
+
+use lsp_types::{CodeAction, CodeActionKind, Range, TextEdit, Url};
+
+use crate::{
+    formatter::{ext_tree::ExtParseTree, sort_definitions::DefinitionsOrder},
+    log,
+};
+
+use super::code_action;
+
+// Not `source.organizeImports`, because editors may run it on save, and sorting
+// shouldn't happen unless asked for. It can still be enabled on save by its own kind.
+pub const SOURCE_SORT_DEFINITIONS_ALPHABETICALLY: CodeActionKind =
+    CodeActionKind::new("source.sortDefinitions.alphabetical");
+pub const SOURCE_SORT_DEFINITIONS_BY_FIRST_USE: CodeActionKind =
+    CodeActionKind::new("source.sortDefinitions.byFirstUse");
+
+/// Returns a code action that merges `defalias` blocks and sorts `defalias`/`defvar`
+/// entries in given `order`. Returns `None` if that wouldn't change anything.
+pub fn organize_definitions(
+    tree: &ExtParseTree,
+    doc_uri: &Url,
+    doc_range: Range,
+    order: DefinitionsOrder,
+) -> Option<CodeAction> {
+    let mut sorted = tree.clone();
+    sorted
+        .sort_definitions(order)
+        .map_err(|e| log!("organize definitions: {}", e))
+        .ok()?;
+    if sorted == *tree {
+        return None;
+    }
+    let (title, kind) = match order {
+        DefinitionsOrder::Alphabetical => (
+            "Sort aliases and variables alphabetically",
+            SOURCE_SORT_DEFINITIONS_ALPHABETICALLY,
+        ),
+        DefinitionsOrder::FirstUseInLayers => (
+            "Sort aliases and variables by first use in layers",
+            SOURCE_SORT_DEFINITIONS_BY_FIRST_USE,
+        ),
+    };
+    Some(code_action(
+        title.to_string(),
+        kind,
+        HashMap::from([(
+            doc_uri.clone(),
+            vec![TextEdit {
+                range: doc_range,
+                new_text: sorted.to_string(),
+            }],
+        )]),
+    ))
+}
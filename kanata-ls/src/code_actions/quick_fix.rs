@@ -0,0 +1,408 @@
+use std::collections::HashMap;
+
+use lsp_types::{CodeAction, CodeActionKind, Diagnostic, Position, Range, TextEdit, Url};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    formatter::ext_tree::{Expr, ExtParseTree},
+    log,
+};
+
+use super::{
+    add_alias_entry, code_action, indent_of,
+    known_names::{closest_names, is_layer_item, is_list_action},
+};
+
+/// Action used as a body of newly created aliases. It does nothing,
+/// so the config stays valid until user fills it in.
+const PLACEHOLDER_ACTION: &str = "XX";
+
+/// A mechanical fix for a parse error. It's attached to the diagnostic as `data`,
+/// and turned into edits once the client asks for code actions.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "fix", rename_all = "kebab-case")]
+pub enum QuickFix {
+    CreateAlias { name: String },
+    CreateLayer { name: String },
+    FixDeflayerLength,
+    ReplaceUnknownName { name: String },
+}
+
+/// Returns the first atom of `text`, if `text` is either an atom or a list starting with one.
+fn head_atom(text: &str) -> Option<&str> {
+    let text = text.strip_prefix('(').unwrap_or(text).trim_start();
+    let end = text
+        .find(|c: char| c.is_whitespace() || c == '(' || c == ')')
+        .unwrap_or(text.len());
+    Some(&text[..end]).filter(|x| !x.is_empty())
+}
+
+// Beginnings of kanata-parser error messages that have a quick fix.
+const UNKNOWN_ALIAS_ERROR: &str = "Referenced unknown alias";
+const UNKNOWN_LAYER_ERROR: &str = "layer name is not declared in any deflayer";
+const UNKNOWN_ACTION_ERROR: &str = "Unknown action type";
+const UNKNOWN_KEY_ERROR: &str = "Unknown key/action";
+const UNKNOWN_DEFSRC_KEY_ERROR: &str = "Unknown key in defsrc";
+/// e.g. "Layer base has 3 item(s), but requires 2 to match defsrc"
+const DEFLAYER_LENGTH_ERROR_SUFFIX: &str = "to match defsrc";
+
+/// Classifies kanata parse error by its message and the text it points at.
+/// `span_text` is the part of the document covered by the error span.
+pub fn quick_fix_for_parse_error(msg: &str, span_text: &str) -> Option<QuickFix> {
+    if let Some(name) = span_text.strip_prefix('@') {
+        if msg.starts_with(UNKNOWN_ALIAS_ERROR) && head_atom(name) == Some(name) {
+            return Some(QuickFix::CreateAlias {
+                name: name.to_string(),
+            });
+        }
+        return None;
+    }
+
+    if msg.starts_with("Layer ") && msg.ends_with(DEFLAYER_LENGTH_ERROR_SUFFIX) {
+        return Some(QuickFix::FixDeflayerLength);
+    }
+
+    let name = head_atom(span_text)?;
+    if name.starts_with('$') {
+        return None;
+    }
+    if msg.starts_with(UNKNOWN_LAYER_ERROR) && name == span_text {
+        return Some(QuickFix::CreateLayer {
+            name: name.to_string(),
+        });
+    }
+    if [
+        UNKNOWN_ACTION_ERROR,
+        UNKNOWN_KEY_ERROR,
+        UNKNOWN_DEFSRC_KEY_ERROR,
+    ]
+    .iter()
+    .any(|x| msg.starts_with(x))
+    {
+        return Some(QuickFix::ReplaceUnknownName {
+            name: name.to_string(),
+        });
+    }
+    None
+}
+
+/// Returns path to the node at the start of `range`. If it starts on a list,
+/// that's the path of the first item of the list.
+fn path_at_range_start(tree: &ExtParseTree, range: &Range) -> Option<Vec<usize>> {
+    let next_char = Position::new(range.start.line, range.start.character + 1);
+    let path = tree
+        .path_to_node_by_lsp_position(range.start)
+        .or_else(|_| tree.path_to_node_by_lsp_position(next_char))
+        .map_err(|e| log!("quick fix: no node at {:?}: {}", range.start, e))
+        .ok()?;
+    Some(path.into_iter().map(|x| x as usize).collect())
+}
+
+fn create_alias(tree: &ExtParseTree, name: &str, diagnostic_range: &Range) -> Option<TextEdit> {
+    let path = path_at_range_start(tree, diagnostic_range)?;
+    let entry = format!("{name} {PLACEHOLDER_ACTION}");
+
+    // Referenced from another alias: aliases have to be declared before use,
+    // so put the new one right above the alias that uses it.
+    if let (Some(("defalias", _)), Some(&index)) = (tree.toplevel_block(path[0]), path.get(1)) {
+        let name_index = if index % 2 == 0 {
+            index.checked_sub(1)?
+        } else {
+            index
+        };
+        let name_range = tree.lsp_range_of_node(&[path[0], name_index]).ok()?;
+        return Some(TextEdit {
+            range: Range::new(name_range.start, name_range.start),
+            new_text: format!("{entry}\n{}", indent_of(&name_range)),
+        });
+    }
+
+    add_alias_entry(tree, &entry, path[0])
+}
+
+fn create_layer(tree: &ExtParseTree, name: &str, defsrc_len: usize) -> Option<TextEdit> {
+    let after_index = match tree.toplevel_blocks("deflayer").last() {
+        Some((i, _)) => i,
+        None => tree.0.len().checked_sub(1)?,
+    };
+    let end = tree.lsp_range_of_node(&[after_index]).ok()?.end;
+    let slots = vec!["_"; defsrc_len].join(" ");
+    Some(TextEdit {
+        range: Range::new(end, end),
+        new_text: format!("\n\n(deflayer {name}\n  {slots}\n)"),
+    })
+}
+
+fn fix_deflayer_length(
+    tree: &ExtParseTree,
+    diagnostic_range: &Range,
+    defsrc_len: usize,
+) -> Option<(String, TextEdit)> {
+    let block_index = *path_at_range_start(tree, diagnostic_range)?.first()?;
+    let items = match tree.toplevel_block(block_index) {
+        Some(("deflayer", items)) => items,
+        _ => return None,
+    };
+    // Skip "deflayer" keyword and layer name.
+    let slot_count = items.len().checked_sub(2)?;
+    let last = tree
+        .lsp_range_of_node(&[block_index, items.len() - 1])
+        .ok()?;
+
+    if slot_count < defsrc_len {
+        let missing = defsrc_len - slot_count;
+        Some((
+            format!("Add {missing} `_` to match defsrc"),
+            TextEdit {
+                range: Range::new(last.end, last.end),
+                new_text: " _".repeat(missing),
+            },
+        ))
+    } else if slot_count > defsrc_len {
+        let excess = slot_count - defsrc_len;
+        let last_kept = tree
+            .lsp_range_of_node(&[block_index, defsrc_len + 1])
+            .ok()?;
+        Some((
+            format!("Remove {excess} item(s) not present in defsrc"),
+            TextEdit {
+                range: Range::new(last_kept.end, last.end),
+                new_text: String::new(),
+            },
+        ))
+    } else {
+        None
+    }
+}
+
+fn replace_unknown_name(
+    tree: &ExtParseTree,
+    name: &str,
+    diagnostic_range: &Range,
+) -> Vec<(String, TextEdit)> {
+    let path = match path_at_range_start(tree, diagnostic_range) {
+        Some(x) => x,
+        None => return vec![],
+    };
+    match tree.get_node_by_path(&path) {
+        Ok(Expr::Atom(x)) if x == name => {}
+        _ => return vec![], // document changed since the diagnostic was published
+    };
+    let range = match tree.lsp_range_of_node(&path) {
+        Ok(x) => x,
+        Err(_) => return vec![],
+    };
+
+    let is_list_head = path.len() > 1 && path.last() == Some(&0);
+    let names = if is_list_head {
+        closest_names(name, is_list_action, 3)
+    } else {
+        closest_names(name, is_layer_item, 3)
+    };
+    names
+        .into_iter()
+        .map(|x| {
+            (
+                format!("Replace with `{x}`"),
+                TextEdit { range, new_text: x },
+            )
+        })
+        .collect()
+}
+
+/// Returns code actions fixing the error reported in `diagnostic`, if it has a [`QuickFix`] attached.
+/// `defsrc_len` is needed for fixes that create or resize layers.
+pub fn code_actions_for_diagnostic(
+    diagnostic: &Diagnostic,
+    doc_uri: &Url,
+    tree: &ExtParseTree,
+    defsrc_len: Option<usize>,
+) -> Vec<CodeAction> {
+    let quick_fix = match diagnostic
+        .data
+        .clone()
+        .map(serde_json::from_value::<QuickFix>)
+    {
+        Some(Ok(x)) => x,
+        _ => return vec![],
+    };
+
+    let fixes: Vec<(String, TextEdit)> = match &quick_fix {
+        QuickFix::CreateAlias { name } => create_alias(tree, name, &diagnostic.range)
+            .map(|edit| (format!("Create alias `{name}`"), edit))
+            .into_iter()
+            .collect(),
+        QuickFix::CreateLayer { name } => defsrc_len
+            .and_then(|len| create_layer(tree, name, len))
+            .map(|edit| (format!("Create layer `{name}`"), edit))
+            .into_iter()
+            .collect(),
+        QuickFix::FixDeflayerLength => defsrc_len
+            .and_then(|len| fix_deflayer_length(tree, &diagnostic.range, len))
+            .into_iter()
+            .collect(),
+        QuickFix::ReplaceUnknownName { name } => {
+            replace_unknown_name(tree, name, &diagnostic.range)
+        }
+    };
+
+    let is_single_fix = fixes.len() == 1;
+    fixes
+        .into_iter()
+        .map(|(title, edit)| CodeAction {
+            diagnostics: Some(vec![diagnostic.clone()]),
+            is_preferred: Some(is_single_fix),
+            ..code_action(
+                title,
+                CodeActionKind::QUICKFIX,
+                HashMap::from([(doc_uri.clone(), vec![edit])]),
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{code_actions::apply_edits, formatter::ext_tree::parse_into_ext_tree};
+
+    fn apply_fix(src: &str, diagnostic_range: Range, fix: QuickFix, defsrc_len: usize) -> String {
+        let tree = parse_into_ext_tree(src).unwrap();
+        let diagnostic = Diagnostic {
+            range: diagnostic_range,
+            data: Some(serde_json::to_value(fix).unwrap()),
+            ..Default::default()
+        };
+        let uri = Url::parse("file:///main.kbd").unwrap();
+        let actions = code_actions_for_diagnostic(&diagnostic, &uri, &tree, Some(defsrc_len));
+        let action = actions.first().expect("at least one code action");
+        apply_edits(
+            src,
+            &action.edit.as_ref().unwrap().changes.as_ref().unwrap()[&uri],
+        )
+    }
+
+    fn range(line: u32, start: u32, end: u32) -> Range {
+        Range::new(Position::new(line, start), Position::new(line, end))
+    }
+
+    #[test]
+    fn test_quick_fix_for_parse_error() {
+        let cases = [
+            (
+                "Referenced unknown alias foo. Note that order of declarations matter.",
+                "@foo",
+                Some(QuickFix::CreateAlias { name: "foo".into() }),
+            ),
+            (
+                "layer name is not declared in any deflayer: nav",
+                "nav",
+                Some(QuickFix::CreateLayer { name: "nav".into() }),
+            ),
+            (
+                "Layer base has 3 item(s), but requires 2 to match defsrc",
+                "(deflayer base a b c)",
+                Some(QuickFix::FixDeflayerLength),
+            ),
+            (
+                "Unknown action type: tap-hlod",
+                "(tap-hlod 200 200 a b)",
+                Some(QuickFix::ReplaceUnknownName {
+                    name: "tap-hlod".into(),
+                }),
+            ),
+            (
+                "Unknown key/action: lft",
+                "lft",
+                Some(QuickFix::ReplaceUnknownName { name: "lft".into() }),
+            ),
+            ("Unknown variable $x", "$x", None),
+            ("Expected an alias name", "@", None),
+            // Unrelated errors that happen to mention unknown or invalid things.
+            ("Invalid number: 2OO", "2OO", None),
+            ("Unknown defcfg option: foo", "foo", None),
+            ("Alias name is invalid: foo", "@foo", None),
+        ];
+        for (msg, span_text, expected) in cases {
+            assert_eq!(quick_fix_for_parse_error(msg, span_text), expected, "{msg}");
+        }
+    }
+
+    #[test]
+    fn test_code_actions_for_diagnostic() {
+        assert_eq!(
+            apply_fix(
+                "(defalias\n  a b\n)\n(deflayer base @c d)",
+                range(3, 15, 17),
+                QuickFix::CreateAlias { name: "c".into() },
+                2,
+            ),
+            "(defalias\n  a b\n  c XX\n)\n(deflayer base @c d)",
+        );
+        assert_eq!(
+            apply_fix(
+                "(defalias\n  a b\n)\n(defalias\n  x @a\n)\n(deflayer base @c d)",
+                range(6, 15, 17),
+                QuickFix::CreateAlias { name: "c".into() },
+                2,
+            ),
+            "(defalias\n  a b\n)\n(defalias\n  x @a\n  c XX\n)\n(deflayer base @c d)",
+        );
+        assert_eq!(
+            apply_fix(
+                "(defalias\n  a @c\n)",
+                range(1, 4, 6),
+                QuickFix::CreateAlias { name: "c".into() },
+                2,
+            ),
+            "(defalias\n  c XX\n  a @c\n)",
+        );
+        assert_eq!(
+            apply_fix(
+                "(defsrc a b)\n(deflayer base @c d)",
+                range(1, 15, 17),
+                QuickFix::CreateAlias { name: "c".into() },
+                2,
+            ),
+            "(defsrc a b)\n(defalias\n  c XX\n)\n\n(deflayer base @c d)",
+        );
+        assert_eq!(
+            apply_fix(
+                "(defsrc a b)\n(deflayer base (layer-switch nav) b)",
+                range(1, 29, 32),
+                QuickFix::CreateLayer { name: "nav".into() },
+                2,
+            ),
+            "(defsrc a b)\n(deflayer base (layer-switch nav) b)\n\n(deflayer nav\n  _ _\n)",
+        );
+        assert_eq!(
+            apply_fix(
+                "(defsrc a b c)\n(deflayer base a)",
+                range(1, 0, 17),
+                QuickFix::FixDeflayerLength,
+                3,
+            ),
+            "(defsrc a b c)\n(deflayer base a _ _)",
+        );
+        assert_eq!(
+            apply_fix(
+                "(defsrc a)\n(deflayer base a b  c)",
+                range(1, 0, 22),
+                QuickFix::FixDeflayerLength,
+                1,
+            ),
+            "(defsrc a)\n(deflayer base a)",
+        );
+        assert_eq!(
+            apply_fix(
+                "(defsrc a)\n(deflayer base (tap-hlod 1 1 a b))",
+                range(1, 15, 33),
+                QuickFix::ReplaceUnknownName {
+                    name: "tap-hlod".into(),
+                },
+                1,
+            ),
+            "(defsrc a)\n(deflayer base (tap-hold 1 1 a b))",
+        );
+    }
+}
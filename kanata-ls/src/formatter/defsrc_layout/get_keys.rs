@@ -2,7 +2,7 @@ use super::{parse_into_ext_tree_and_root_span, ExtParseTree};
 use crate::{path_to_url, WorkspaceOptions};
 use anyhow::{anyhow, Ok};
 use lsp_types::{TextDocumentItem, Url};
-use std::{collections::BTreeMap, iter};
+use std::collections::BTreeMap;
 
 pub fn get_defsrc_keys(
     workspace_options: &WorkspaceOptions,
@@ -10,10 +10,46 @@ pub fn get_defsrc_keys(
     file_uri: &Url,      // of current file
     tree: &ExtParseTree, // of current file
 ) -> anyhow::Result<Option<Vec<String>>> {
+    // make sure that all includes collectively contain only 1 defsrc
+    let mut defsrc_keys = None;
+    for (file_url, tree) in config_trees(workspace_options, documents, file_uri, tree)? {
+        if let Some(keys) = tree
+            .defsrc_keys()
+            .map_err(|e| anyhow!("tree.defsrc_keys for '{file_url}' failed: {e}"))?
+        {
+            defsrc_keys = Some(keys);
+        }
+    }
+    Ok(defsrc_keys)
+}
+
+/// Obtains all `deflayer` blocks of the config that the current file is a part of,
+/// as pairs of layer name and slot contents. Blocks from the main config file
+/// come first, followed by blocks from the included files.
+pub fn get_deflayers(
+    workspace_options: &WorkspaceOptions,
+    documents: &BTreeMap<Url, TextDocumentItem>,
+    file_uri: &Url,      // of current file
+    tree: &ExtParseTree, // of current file
+) -> anyhow::Result<Vec<(String, Vec<String>)>> {
+    Ok(config_trees(workspace_options, documents, file_uri, tree)?
+        .iter()
+        .flat_map(|(_, tree)| tree.deflayers())
+        .collect())
+}
+
+/// Returns trees of all files that make up the config that the current file is a part of.
+/// In workspace mode, the main config file is first, and included files follow in order.
+pub fn config_trees(
+    workspace_options: &WorkspaceOptions,
+    documents: &BTreeMap<Url, TextDocumentItem>,
+    file_uri: &Url,      // of current file
+    tree: &ExtParseTree, // of current file
+) -> anyhow::Result<Vec<(Url, ExtParseTree)>> {
     match workspace_options {
         WorkspaceOptions::Single { .. } => {
             if tree.includes()?.is_empty() {
-                tree.defsrc_keys()
+                Ok(vec![(file_uri.clone(), tree.clone())])
             } else {
                 // This is an error, because we don't know if those included files
                 // and current file collectively don't contain >=2 `defsrc` blocks.
@@ -56,30 +92,83 @@ pub fn get_defsrc_keys(
                 .collect::<anyhow::Result<Vec<_>>>()
                 .map_err(|e| anyhow!("path_to_url: {e}"))?;
 
-            // make sure that all includes collectively contain only 1 defsrc
-            let mut defsrc_keys = None;
-            for file_url in includes.iter().chain(iter::once(&main_config_file_url)) {
+            let mut trees = vec![(main_config_file_url, main_tree)];
+            for file_url in includes {
                 let doc = documents
-                    .get(file_url)
+                    .get(&file_url)
                     .ok_or_else(|| anyhow!("document '{file_url}' is not loaded"))?;
 
                 let tree = parse_into_ext_tree_and_root_span(&doc.text)
                     .map(|x| x.0)
                     .map_err(|e| {
                         anyhow!(
-                            "parse_into_ext_tree_and_root_span failed for file '{file_uri}': {}",
+                            "parse_into_ext_tree_and_root_span failed for file '{file_url}': {}",
                             e.msg
                         )
                     })?;
 
-                if let Some(layout) = tree
-                    .defsrc_keys()
-                    .map_err(|e| anyhow!("tree.defsrc_keys for '{file_url}' failed: {e}"))?
-                {
-                    defsrc_keys = Some(layout);
-                }
+                trees.push((file_url, tree));
             }
-            Ok(defsrc_keys)
+            Ok(trees)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    const MAIN_FILE: &str = "main.kbd";
+
+    fn new_btree(items: &[(&str, &str)]) -> BTreeMap<Url, TextDocumentItem> {
+        let mut btree = BTreeMap::new();
+        for item in items {
+            let uri = Url::from_str(&format!("file:///{}", item.0)).unwrap();
+            let doc = TextDocumentItem {
+                uri: uri.clone(),
+                language_id: "kanata".to_string(),
+                version: 0,
+                text: item.1.to_string(),
+            };
+            btree.insert(uri, doc);
         }
+        btree
+    }
+
+    #[test]
+    fn deflayers_of_included_file_in_workspace() {
+        let items = &[
+            (MAIN_FILE, "(deflayer base 3 4) (include included.kbd)"),
+            (
+                "included.kbd",
+                "(defsrc 1  2) (deflayer numbers 5 (tap-hold 1 1 a b))",
+            ),
+        ];
+        let workspace_options = WorkspaceOptions::Workspace {
+            main_config_file: MAIN_FILE.into(),
+            project_root: Url::from_str("file:///").unwrap(),
+        };
+        let documents = new_btree(items);
+        let file_uri = Url::from_str(&format!("file:///{}", items[1].0)).unwrap();
+        let tree = parse_into_ext_tree_and_root_span(items[1].1).unwrap().0;
+
+        let keys = get_defsrc_keys(&workspace_options, &documents, &file_uri, &tree)
+            .unwrap()
+            .ok_or("should be some")
+            .unwrap();
+        assert_eq!(keys, vec!["1", "2"]);
+
+        let layers = get_deflayers(&workspace_options, &documents, &file_uri, &tree).unwrap();
+        assert_eq!(
+            layers,
+            vec![
+                ("base".to_string(), vec!["3".to_string(), "4".to_string()]),
+                (
+                    "numbers".to_string(),
+                    vec!["5".to_string(), "(tap-hold 1 1 a b)".to_string()]
+                ),
+            ]
+        );
     }
 }
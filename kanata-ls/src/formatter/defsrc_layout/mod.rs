@@ -270,6 +270,41 @@ impl ExtParseTree {
 
         Ok(Some(result))
     }
+
+    /// Obtains all `deflayer` blocks in given [`ExtParseTree`], as pairs
+    /// of layer name and slot contents.
+    /// * It doesn't search includes.
+    /// * Blocks without a valid layer name are skipped.
+    pub fn deflayers(&self) -> Vec<(String, Vec<String>)> {
+        let mut result = vec![];
+
+        for top_level_item in self.0.iter() {
+            let top_level_list = match &top_level_item.expr {
+                Expr::Atom(_) => continue,
+                Expr::List(list) => list,
+            };
+
+            match top_level_list.get(0).map(|x| &x.expr) {
+                Some(Expr::Atom(x)) if x == "deflayer" => {}
+                _ => continue,
+            };
+
+            let layer_name = match top_level_list.get(1).map(|x| &x.expr) {
+                Some(Expr::Atom(x)) => x.clone(),
+                _ => continue,
+            };
+
+            let slots = top_level_list
+                .iter()
+                .skip(2)
+                .map(|x| x.expr.to_string())
+                .collect();
+
+            result.push((layer_name, slots));
+        }
+
+        result
+    }
 }
 
 /// Format metadata for a definition layer node based on specified constraints.
@@ -659,6 +694,23 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_deflayers() {
+        let input = "(defsrc 1 2) (deflayer base 3 @a) (deflayer) (deflayer (x) 1 2) (deflayer nav _ (layer-switch base))";
+        let tree = parse_into_ext_tree(input).expect("parses");
+
+        assert_eq!(
+            tree.deflayers(),
+            vec![
+                ("base".to_string(), vec!["3".to_string(), "@a".to_string()]),
+                (
+                    "nav".to_string(),
+                    vec!["_".to_string(), "(layer-switch base)".to_string()]
+                ),
+            ]
+        );
+    }
+
     /// Regression test for https://github.com/rszyma/vscode-kanata/issues/51
     #[test]
     fn test_deflayer_slot_expand() {
@@ -62,13 +62,16 @@ impl ExtParseTree {
         }
     }
 
-    pub fn _get_node_by_path(&self, path: &[usize]) -> anyhow::Result<&Expr> {
+    pub fn get_node_by_path(&self, path: &[usize]) -> anyhow::Result<&Expr> {
         let mut head: &NodeList = &self.0;
-        let last_path_index = path.len() - 1;
+        let last_path_index = path
+            .len()
+            .checked_sub(1)
+            .ok_or_else(|| anyhow!("path is empty"))?;
         for (path_index, &i) in path.iter().enumerate() {
             let node = match head.get(i) {
                 Some(x) => x,
-                None => panic!("path out-of-bounds on path index {path_index} "),
+                None => return Err(anyhow!("path out-of-bounds on path index {path_index}")),
             };
             if path_index == last_path_index {
                 return Ok(&node.expr);
@@ -87,6 +90,35 @@ impl ExtParseTree {
         unreachable!()
     }
 
+    /// Returns name and items of the top-level block at given index.
+    pub fn toplevel_block(&self, index: usize) -> Option<(&str, &NodeList)> {
+        let node_list = match self.0.get(index) {
+            Some(ParseTreeNode {
+                expr: Expr::List(node_list),
+                ..
+            }) => node_list,
+            _ => return None,
+        };
+        match node_list.get(0) {
+            Some(ParseTreeNode {
+                expr: Expr::Atom(atom),
+                ..
+            }) => Some((atom.as_str(), node_list)),
+            _ => None,
+        }
+    }
+
+    /// Returns indexes and items of all top-level blocks with given name.
+    pub fn toplevel_blocks<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> impl Iterator<Item = (usize, &'a NodeList)> {
+        (0..self.0.len()).filter_map(move |i| match self.toplevel_block(i) {
+            Some((block_name, node_list)) if block_name == name => Some((i, node_list)),
+            _ => None,
+        })
+    }
+
     pub fn includes(&self) -> anyhow::Result<Vec<PathBuf>> {
         let mut result = vec![];
         for top_level_block in self.0.iter() {
@@ -124,6 +156,24 @@ impl ExtParseTree {
     ) -> anyhow::Result<Vec<u32>> {
         self.0.path_to_node_by_lsp_pos(pos, &mut 0, &mut 0)
     }
+
+    /// Returns LSP range of the node at given `path`.
+    /// Inverse of [`ExtParseTree::path_to_node_by_lsp_position`].
+    pub fn lsp_range_of_node(&self, path: &[usize]) -> anyhow::Result<lsp_types::Range> {
+        self.0
+            .lsp_range_of_node(path, &mut lsp_types::Position::default())
+    }
+
+    /// Calls `visit` with path, expression and LSP range of every node in the tree,
+    /// parents before their children. Unlike calling [`ExtParseTree::lsp_range_of_node`]
+    /// for each node, this goes through the tree only once.
+    pub fn visit_nodes_with_ranges(
+        &self,
+        visit: &mut dyn FnMut(&[usize], &Expr, lsp_types::Range),
+    ) {
+        self.0
+            .visit_nodes_with_ranges(visit, &mut vec![], &mut lsp_types::Position::default())
+    }
 }
 
 impl Display for ExtParseTree {
@@ -314,6 +364,86 @@ impl NodeList {
         }
         Err(anyhow!("no match in this path"))
     }
+
+    fn lsp_range_of_node(
+        &self,
+        path: &[usize],
+        pos: &mut lsp_types::Position,
+    ) -> anyhow::Result<lsp_types::Range> {
+        let (&target_index, rest_of_path) =
+            path.split_first().ok_or_else(|| anyhow!("path is empty"))?;
+
+        for (i, node) in self.iter().enumerate() {
+            for m in &node.pre_metadata {
+                advance_lsp_position(pos, &m.to_string());
+            }
+
+            if i == target_index {
+                if rest_of_path.is_empty() {
+                    let start = *pos;
+                    advance_lsp_position(pos, &node.expr.to_string());
+                    return Ok(lsp_types::Range::new(start, *pos));
+                }
+                return match &node.expr {
+                    Expr::Atom(_) => Err(anyhow!(
+                        "atom found in the middle of path, while it's only allowed at the end"
+                    )),
+                    Expr::List(xs) => {
+                        advance_lsp_position(pos, "(");
+                        xs.lsp_range_of_node(rest_of_path, pos)
+                    }
+                };
+            }
+
+            advance_lsp_position(pos, &node.expr.to_string());
+            for m in &node.post_metadata {
+                advance_lsp_position(pos, &m.to_string());
+            }
+        }
+
+        Err(anyhow!("path out-of-bounds"))
+    }
+
+    fn visit_nodes_with_ranges(
+        &self,
+        visit: &mut dyn FnMut(&[usize], &Expr, lsp_types::Range),
+        path: &mut Vec<usize>,
+        pos: &mut lsp_types::Position,
+    ) {
+        for (i, node) in self.iter().enumerate() {
+            for m in &node.pre_metadata {
+                advance_lsp_position(pos, &m.to_string());
+            }
+
+            let start = *pos;
+            let mut end = start;
+            advance_lsp_position(&mut end, &node.expr.to_string());
+            path.push(i);
+            visit(path, &node.expr, lsp_types::Range::new(start, end));
+            if let Expr::List(xs) = &node.expr {
+                advance_lsp_position(pos, "(");
+                xs.visit_nodes_with_ranges(visit, path, pos);
+            }
+            path.pop();
+            *pos = end;
+
+            for m in &node.post_metadata {
+                advance_lsp_position(pos, &m.to_string());
+            }
+        }
+    }
+}
+
+/// Moves `pos` to the end of `text`, as if `text` was written starting at `pos`.
+fn advance_lsp_position(pos: &mut lsp_types::Position, text: &str) {
+    for n in text.encode_utf16() {
+        if n == b'\n' as u16 {
+            pos.line += 1;
+            pos.character = 0;
+        } else {
+            pos.character += 1;
+        }
+    }
 }
 
 impl Default for NodeList {
@@ -816,6 +946,47 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_lsp_range_of_node() {
+        let tree = parse_into_ext_tree("((1 2) 345  6)\n(a\n  (b 🌍)  c)").expect("parses");
+        let test_table = [
+            (vec![0], (0, 0), (0, 14), false),
+            (vec![0, 0], (0, 1), (0, 6), false),
+            (vec![0, 0, 1], (0, 4), (0, 5), true),
+            (vec![0, 1], (0, 7), (0, 10), true),
+            (vec![0, 2], (0, 12), (0, 13), true),
+            (vec![1], (1, 0), (2, 12), false),
+            (vec![1, 1], (2, 2), (2, 8), false),
+            (vec![1, 1, 1], (2, 5), (2, 7), true), // 🌍 is 2 utf-16 code units long
+            (vec![1, 2], (2, 10), (2, 11), true),
+        ];
+        for (path, start, end, is_atom) in test_table {
+            let range = tree.lsp_range_of_node(&path).expect("finds range");
+            assert_eq!(
+                range,
+                lsp_types::Range::new(
+                    lsp_types::Position::new(start.0, start.1),
+                    lsp_types::Position::new(end.0, end.1)
+                ),
+                "path: {path:?}"
+            );
+            if is_atom {
+                // Should be the inverse of `path_to_node_by_lsp_position`.
+                let path_back = tree
+                    .path_to_node_by_lsp_position(range.start)
+                    .expect("finds path");
+                assert_eq!(
+                    path_back,
+                    path.iter().map(|x| *x as u32).collect::<Vec<_>>()
+                );
+            }
+        }
+
+        tree.lsp_range_of_node(&[0, 3]).expect_err("out-of-bounds");
+        tree.lsp_range_of_node(&[0, 1, 0])
+            .expect_err("atom in the middle of path");
+    }
+
     #[test]
     fn test_path_to_node_by_lsp_position_multiline() {
         let pos: lsp_types::Position = lsp_types::Position::new(4, 3);
@@ -5,6 +5,7 @@ use self::defsrc_layout::LineEndingSequence;
 
 pub mod defsrc_layout;
 mod remove_excessive_newlines;
+pub mod sort_definitions;
 
 pub struct Formatter {
     // Additional options
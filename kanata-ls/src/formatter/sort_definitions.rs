@@ -0,0 +1,413 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, bail};
+
+use super::ext_tree::*;
+
+/// Order of name/value pairs in `defalias` and `defvar` blocks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DefinitionsOrder {
+    Alphabetical,
+    /// Order in which names are first used in `deflayer`/`deflayermap` blocks.
+    /// Names not used in any layer go last, in alphabetical order.
+    FirstUseInLayers,
+}
+
+/// Name/value pair of a `defalias`/`defvar` block, with comments attached to it.
+struct Entry {
+    /// Metadata before the name: line break, indentation and comments above the pair.
+    leading: Vec<Metadata>,
+    /// Post-metadata of the name separates it from the value.
+    name: ParseTreeNode,
+    value: Expr,
+    /// Metadata after the value on the same line, e.g. a trailing comment.
+    trailing: Vec<Metadata>,
+}
+
+impl Entry {
+    fn name(&self) -> &str {
+        match &self.name.expr {
+            Expr::Atom(x) => x,
+            Expr::List(_) => unreachable!("checked in Block::from_list"),
+        }
+    }
+}
+
+/// A `defalias`/`defvar` block split into entries.
+struct Block {
+    keyword: ParseTreeNode,
+    /// Metadata after the keyword on the same line.
+    keyword_trailing: Vec<Metadata>,
+    entries: Vec<Entry>,
+    /// Metadata before the closing paren.
+    tail: Vec<Metadata>,
+}
+
+impl Block {
+    fn from_list(list: &NodeList) -> anyhow::Result<Self> {
+        let nodes: Vec<ParseTreeNode> = list.iter().cloned().collect();
+        let (keyword, pairs) = nodes.split_first().ok_or_else(|| anyhow!("empty block"))?;
+        let mut keyword = keyword.clone();
+        let (keyword_trailing, mut pending) =
+            split_off_trailing(std::mem::take(&mut keyword.post_metadata));
+
+        let pairs = pairs.chunks_exact(2);
+        if !pairs.remainder().is_empty() {
+            bail!("block has a name without a value");
+        }
+        let mut entries = vec![];
+        for pair in pairs {
+            let (name, mut value) = (pair[0].clone(), pair[1].clone());
+            if let Expr::List(_) = name.expr {
+                bail!("expected a name, found a list");
+            }
+            let (trailing, rest) = split_off_trailing(std::mem::take(&mut value.post_metadata));
+            entries.push(Entry {
+                leading: std::mem::replace(&mut pending, rest),
+                name,
+                value: value.expr,
+                trailing,
+            });
+        }
+
+        Ok(Block {
+            keyword,
+            keyword_trailing,
+            entries,
+            tail: pending,
+        })
+    }
+
+    fn into_list(self) -> NodeList {
+        let mut keyword = self.keyword;
+        keyword.post_metadata = self.keyword_trailing;
+        let mut nodes = vec![];
+        // Node that metadata following it gets attached to.
+        let mut last = keyword;
+        for entry in self.entries {
+            append_after_trailing(&mut last.post_metadata, entry.leading);
+            nodes.push(last);
+            nodes.push(entry.name);
+            last = ParseTreeNode {
+                pre_metadata: vec![],
+                expr: entry.value,
+                post_metadata: entry.trailing,
+            };
+        }
+        append_after_trailing(&mut last.post_metadata, self.tail);
+        nodes.push(last);
+        NodeList::NonEmptyList(nodes)
+    }
+
+    /// Indentation of entries, used for comments moved into this block.
+    fn indent(&self) -> String {
+        self.entries
+            .first()
+            .map(|x| &x.leading)
+            .and_then(|leading| match leading.last() {
+                Some(Metadata::Whitespace(x)) if x.contains('\n') => {
+                    x.rsplit('\n').next().map(str::to_string)
+                }
+                _ => None,
+            })
+            .unwrap_or_else(|| "  ".to_string())
+    }
+}
+
+/// Splits metadata that follows a node into comments on the same line as the node,
+/// and the rest. Newline ending a line comment is moved to the rest,
+/// so that the rest starts on a new line, unless the next node is on the same line.
+fn split_off_trailing(metadata: Vec<Metadata>) -> (Vec<Metadata>, Vec<Metadata>) {
+    let mut trailing = vec![];
+    let mut rest = vec![];
+    let mut metadata = metadata.into_iter();
+    while let Some(m) = metadata.next() {
+        match m {
+            Metadata::Comment(Comment::LineComment(x)) if x.ends_with('\n') => {
+                let comment = x.trim_end_matches('\n').to_string();
+                trailing.push(Metadata::Comment(Comment::LineComment(comment)));
+                match metadata.next() {
+                    Some(Metadata::Whitespace(ws)) => {
+                        rest.push(Metadata::Whitespace(format!("\n{ws}")))
+                    }
+                    Some(other) => rest.extend([Metadata::Whitespace("\n".to_string()), other]),
+                    None => rest.push(Metadata::Whitespace("\n".to_string())),
+                };
+                break;
+            }
+            Metadata::Comment(Comment::BlockComment(ref x)) | Metadata::Whitespace(ref x)
+                if x.contains('\n') =>
+            {
+                rest.push(m);
+                break;
+            }
+            m => trailing.push(m),
+        }
+    }
+    rest.extend(metadata);
+    // Whitespace that isn't followed by a comment separates the node from the next one.
+    while let Some(Metadata::Whitespace(_)) = trailing.last() {
+        rest.insert(0, trailing.pop().expect("not empty"));
+    }
+    (trailing, rest)
+}
+
+/// Appends `metadata` to `trailing`, making sure it isn't commented out
+/// by a line comment that ended up at the end of `trailing`.
+fn append_after_trailing(trailing: &mut Vec<Metadata>, metadata: Vec<Metadata>) {
+    let ends_with_line_comment = matches!(
+        trailing.last(),
+        Some(Metadata::Comment(Comment::LineComment(_)))
+    );
+    let starts_with_newline = matches!(
+        metadata.first(),
+        Some(Metadata::Whitespace(x)) if x.starts_with('\n')
+    );
+    if ends_with_line_comment && !starts_with_newline {
+        trailing.push(Metadata::Whitespace("\n".to_string()));
+    }
+    trailing.extend(metadata);
+}
+
+/// Turns comments into metadata placing each of them on its own line.
+fn comments_on_own_lines(metadata: Vec<Metadata>, indent: &str) -> Vec<Metadata> {
+    let mut result = vec![];
+    for m in metadata {
+        let comment = match m {
+            Metadata::Comment(Comment::LineComment(x)) => {
+                Comment::LineComment(x.trim_end_matches('\n').to_string())
+            }
+            Metadata::Comment(x) => x,
+            Metadata::Whitespace(_) => continue,
+        };
+        result.push(Metadata::Whitespace(format!("\n{indent}")));
+        result.push(Metadata::Comment(comment));
+    }
+    result
+}
+
+/// Collects names with given prefix (e.g. `@` for aliases) used in `expr`.
+fn used_names<'a>(expr: &'a Expr, prefix: char, out: &mut Vec<&'a str>) {
+    match expr {
+        Expr::Atom(x) => out.extend(x.strip_prefix(prefix)),
+        Expr::List(xs) => {
+            for x in xs.iter() {
+                used_names(&x.expr, prefix, out);
+            }
+        }
+    }
+}
+
+/// Sorts entries by `key`, but keeps entries after the entries they use,
+/// since aliases and variables need to be defined before they're used.
+fn sort_entries(
+    entries: Vec<Entry>,
+    prefix: char,
+    key: &dyn Fn(&str) -> (usize, String, String),
+) -> anyhow::Result<Vec<Entry>> {
+    let mut remaining: Vec<(Entry, Vec<String>)> = entries
+        .into_iter()
+        .map(|entry| {
+            let mut deps = vec![];
+            used_names(&entry.value, prefix, &mut deps);
+            let deps = deps
+                .into_iter()
+                .filter(|x| *x != entry.name())
+                .map(str::to_string)
+                .collect();
+            (entry, deps)
+        })
+        .collect();
+
+    let mut result = vec![];
+    while !remaining.is_empty() {
+        let next = remaining
+            .iter()
+            .enumerate()
+            .filter(|(_, (_, deps))| {
+                deps.iter()
+                    .all(|dep| remaining.iter().all(|(entry, _)| entry.name() != dep))
+            })
+            .min_by_key(|(_, (entry, _))| key(entry.name()))
+            .map(|(i, _)| i)
+            .ok_or_else(|| anyhow!("circular reference between definitions"))?;
+        result.push(remaining.remove(next).0);
+    }
+    Ok(result)
+}
+
+impl ExtParseTree {
+    /// Merges adjacent `defalias` blocks into the first of them, and sorts name/value pairs
+    /// in `defalias` and `defvar` blocks. Comments stay attached to the pairs
+    /// they're placed above or next to. Leaves the tree unchanged on error.
+    pub fn sort_definitions(&mut self, order: DefinitionsOrder) -> anyhow::Result<()> {
+        let mut first_use: HashMap<(char, String), usize> = HashMap::new();
+        for i in 0..self.0.len() {
+            let items = match self.toplevel_block(i) {
+                Some(("deflayer" | "deflayermap", items)) => items,
+                _ => continue,
+            };
+            for prefix in ['@', '$'] {
+                let mut names = vec![];
+                for item in items.iter() {
+                    used_names(&item.expr, prefix, &mut names);
+                }
+                for name in names {
+                    let next_index = first_use.len();
+                    first_use
+                        .entry((prefix, name.to_string()))
+                        .or_insert(next_index);
+                }
+            }
+        }
+        let key_for = |prefix: char| {
+            let first_use = &first_use;
+            move |name: &str| {
+                let position = match order {
+                    DefinitionsOrder::Alphabetical => 0,
+                    DefinitionsOrder::FirstUseInLayers => first_use
+                        .get(&(prefix, name.to_string()))
+                        .copied()
+                        .unwrap_or(usize::MAX),
+                };
+                (position, name.to_lowercase(), name.to_string())
+            }
+        };
+
+        let mut nodes: Vec<ParseTreeNode> = self.0.iter().cloned().collect();
+        // Only adjacent blocks are merged, because blocks in between (e.g. `deftemplate`)
+        // can define something that aliases after them depend on.
+        let alias_blocks: Vec<usize> = self.toplevel_blocks("defalias").map(|(i, _)| i).collect();
+        let adjacent_alias_blocks: Vec<&[usize]> =
+            alias_blocks.chunk_by(|a, b| a + 1 == *b).collect();
+        // Merged from the last, so that removing blocks doesn't shift the earlier ones.
+        for blocks in adjacent_alias_blocks.into_iter().rev() {
+            let (&first, others) = blocks.split_first().expect("chunks aren't empty");
+            let mut merged = Block::from_list(self.toplevel_block(first).expect("exists").1)?;
+            let indent = merged.indent();
+            let mut orphan_comments = vec![];
+            for &i in others {
+                let mut block = Block::from_list(self.toplevel_block(i).expect("exists").1)?;
+                // Comments above the block and next to its keyword.
+                let (_, above_block) = split_off_trailing(nodes[i - 1].post_metadata.clone());
+                let mut comments = above_block;
+                comments.extend(block.keyword.pre_metadata.clone());
+                comments.extend(block.keyword_trailing.clone());
+                match block.entries.first_mut() {
+                    Some(entry) => {
+                        let mut leading = comments_on_own_lines(comments, &indent);
+                        leading.append(&mut entry.leading);
+                        entry.leading = leading;
+                    }
+                    None => orphan_comments.extend(comments),
+                }
+                orphan_comments.extend(block.tail.clone());
+                merged.entries.append(&mut block.entries);
+            }
+            let mut tail = comments_on_own_lines(orphan_comments, &indent);
+            tail.append(&mut merged.tail);
+            merged.tail = tail;
+            merged.entries = sort_entries(merged.entries, '@', &key_for('@'))?;
+            nodes[first].expr = Expr::List(merged.into_list());
+
+            for &i in others.iter().rev() {
+                let removed = nodes.remove(i);
+                let is_last = i == nodes.len();
+                let previous = &mut nodes[i - 1];
+                // Comments above the removed block were moved into the merged block.
+                let (mut metadata, above_block) =
+                    split_off_trailing(std::mem::take(&mut previous.post_metadata));
+                let mut after_block = removed.post_metadata.into_iter().peekable();
+                // Keep the separator from before the removed block, unless it was the last one.
+                if let (Some(Metadata::Whitespace(_)), false) = (above_block.first(), is_last) {
+                    metadata.push(above_block[0].clone());
+                    after_block.next_if(|x| matches!(x, Metadata::Whitespace(_)));
+                }
+                metadata.extend(after_block);
+                previous.post_metadata = metadata;
+            }
+        }
+
+        for node in nodes.iter_mut() {
+            let list = match &node.expr {
+                Expr::List(list) => list,
+                Expr::Atom(_) => continue,
+            };
+            match list.get(0).map(|x| &x.expr) {
+                Some(Expr::Atom(x)) if x == "defvar" => {}
+                _ => continue,
+            }
+            let mut block = Block::from_list(list)?;
+            block.entries = sort_entries(block.entries, '$', &key_for('$'))?;
+            node.expr = Expr::List(block.into_list());
+        }
+
+        self.0 = match std::mem::take(&mut self.0) {
+            NodeList::EmptyList(x) => NodeList::EmptyList(x),
+            NodeList::NonEmptyList(_) => NodeList::NonEmptyList(nodes),
+        };
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sort(src: &str, order: DefinitionsOrder) -> anyhow::Result<String> {
+        let mut tree = parse_into_ext_tree(src).map_err(|e| anyhow!(e.msg))?;
+        tree.sort_definitions(order)?;
+        Ok(tree.to_string())
+    }
+
+    #[test]
+    fn test_sort_definitions() {
+        #[rustfmt::skip]
+        let cases = vec![
+            (
+                DefinitionsOrder::Alphabetical,
+                "(defalias\n  c x ;; about c\n  ;; about b\n  b y\n  a z\n)",
+                "(defalias\n  a z\n  ;; about b\n  b y\n  c x ;; about c\n)",
+            ),
+            (
+                DefinitionsOrder::Alphabetical,
+                "(defvar b 1 a 2)",
+                "(defvar a 2 b 1)",
+            ),
+            // Aliases must stay after the aliases they use.
+            (
+                DefinitionsOrder::Alphabetical,
+                "(defalias\n  b x\n  a (multi @b c)\n  c y\n)",
+                "(defalias\n  b x\n  a (multi @b c)\n  c y\n)",
+            ),
+            // Adjacent blocks are merged into the first one.
+            (
+                DefinitionsOrder::Alphabetical,
+                "(defalias\n  b x\n)\n\n;; more\n(defalias\n  a y\n)\n\n(defsrc a)\n",
+                "(defalias\n  ;; more\n  a y\n  b x\n)\n\n(defsrc a)\n",
+            ),
+            // Aliases can't be moved before a template they use.
+            (
+                DefinitionsOrder::Alphabetical,
+                "(defalias b x)\n(deftemplate t () y)\n(defalias a (t! t))\n",
+                "(defalias b x)\n(deftemplate t () y)\n(defalias a (t! t))\n",
+            ),
+            (
+                DefinitionsOrder::Alphabetical,
+                "(defalias b x)\n\n(defalias a y ;; y\n)\n(defsrc a)",
+                "(defalias a y ;; y\n b x)\n\n(defsrc a)",
+            ),
+            (
+                DefinitionsOrder::FirstUseInLayers,
+                "(defalias\n  a x\n  b y\n  c z\n)\n(deflayer base @c _ @a)",
+                "(defalias\n  c z\n  a x\n  b y\n)\n(deflayer base @c _ @a)",
+            ),
+        ];
+
+        for (order, src, expected) in cases {
+            assert_eq!(sort(src, order).unwrap(), expected, "{src}");
+        }
+        assert!(sort("(defalias a @b b @a)", DefinitionsOrder::Alphabetical).is_err());
+        assert!(sort("(defalias a)", DefinitionsOrder::Alphabetical).is_err());
+    }
+}
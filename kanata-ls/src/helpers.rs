@@ -1,13 +1,13 @@
 use std::{
     collections::BTreeMap,
     iter::{repeat, zip},
-    path::Path,
+    path::{Path, PathBuf},
     rc::Rc,
     str::FromStr,
 };
 
 use anyhow::anyhow;
-use itertools::chain;
+use itertools::{chain, Itertools};
 use kanata_parser::cfg::{sexpr::Span, FileContentProvider, ParseError};
 use kanata_parser::lsp_hints::InactiveCode;
 use lsp_types::{PublishDiagnosticsParams, Range, TextDocumentItem, Url};
@@ -17,24 +17,40 @@ pub type HashSet<T> = rustc_hash::FxHashSet<T>;
 pub type Documents = BTreeMap<Url, TextDocumentItem>;
 pub type Diagnostics = BTreeMap<Url, PublishDiagnosticsParams>;
 
-#[cfg(target_os = "unknown")]
+/// Logs a debug message. See [`crate::logger`] for the other levels.
 #[macro_export]
 macro_rules! log {
-    ($string:expr) => {
-        web_sys::console::log_1(&wasm_bindgen::JsValue::from($string))
+    ($($tokens:tt)*) => {
+        $crate::logger::log($crate::logger::LogLevel::Debug, format_args!($($tokens)*))
     };
+}
+
+#[macro_export]
+macro_rules! log_error {
     ($($tokens:tt)*) => {
-        web_sys::console::log_1(&wasm_bindgen::JsValue::from(format!($($tokens)*)))
+        $crate::logger::log($crate::logger::LogLevel::Error, format_args!($($tokens)*))
     };
 }
 
-#[cfg(not(target_os = "unknown"))]
 #[macro_export]
-macro_rules! log {
-    ($($tokens:tt)*) => {{
-        eprint!("kanata-ls: ");
-        eprintln!($($tokens)*);
-    }};
+macro_rules! log_warn {
+    ($($tokens:tt)*) => {
+        $crate::logger::log($crate::logger::LogLevel::Warn, format_args!($($tokens)*))
+    };
+}
+
+#[macro_export]
+macro_rules! log_info {
+    ($($tokens:tt)*) => {
+        $crate::logger::log($crate::logger::LogLevel::Info, format_args!($($tokens)*))
+    };
+}
+
+#[macro_export]
+macro_rules! log_trace {
+    ($($tokens:tt)*) => {
+        $crate::logger::log($crate::logger::LogLevel::Trace, format_args!($($tokens)*))
+    };
 }
 
 #[cfg(target_os = "unknown")]
@@ -61,6 +77,20 @@ pub fn utf16_length(str: impl AsRef<str>) -> usize {
     utf16_encoded.len()
 }
 
+/// Makes text safe to put inside of a markdown table cell, as an inline code span.
+pub fn markdown_table_cell(text: &str) -> String {
+    // Table rows can't span multiple lines, and `|` would end the cell early.
+    let text = text.split_whitespace().join(" ").replace('|', "\\|");
+    // The code span has to be delimited by more backticks than any run of them inside it.
+    let longest_backtick_run = text.split(|c| c != '`').map(str::len).max().unwrap_or(0);
+    let fence = "`".repeat(longest_backtick_run + 1);
+    if text.starts_with('`') || text.ends_with('`') {
+        format!("{fence} {text} {fence}")
+    } else {
+        format!("{fence}{text}{fence}")
+    }
+}
+
 pub fn slice_rc_str(rc_str: &Rc<str>, start: usize, end: usize) -> &str {
     &rc_str[start..end]
 }
@@ -106,6 +136,17 @@ impl ReferenceKind {
             ReferenceKind::Include => false,
         }
     }
+
+    /// Shrinks a reference range so that it doesn't cover the prefix character (if any).
+    pub fn range_without_prefix(&self, range: Range) -> Range {
+        if self.has_prefix() {
+            let mut r = range;
+            r.start.character += 1;
+            r
+        } else {
+            range
+        }
+    }
 }
 
 /// Location info of token, including prefix if any.
@@ -145,6 +186,18 @@ impl DefinitionLocations {
         log!("get_definition_at_position: not found");
         None
     }
+
+    /// Returns location of the definition of a symbol with given kind and name.
+    pub fn get_definition(&self, ref_kind: ReferenceKind, name: &str) -> Option<&Span> {
+        match ref_kind {
+            ReferenceKind::Alias => self.0.alias.get(name),
+            ReferenceKind::Variable => self.0.variable.get(name),
+            ReferenceKind::VirtualKey => self.0.virtual_key.get(name),
+            ReferenceKind::Layer => self.0.layer.get(name),
+            ReferenceKind::Template => self.0.template.get(name),
+            ReferenceKind::Include => None,
+        }
+    }
 }
 
 #[derive(Debug, Default, Clone)]
@@ -179,6 +232,37 @@ impl ReferenceLocations {
         log!("search_definitions_at_position: not found any definitions");
         None
     }
+
+    /// Returns locations of all references to a symbol with given kind and name.
+    pub fn get_references(&self, ref_kind: ReferenceKind, name: &str) -> &[Span] {
+        let location_map = match ref_kind {
+            ReferenceKind::Alias => &self.0.alias,
+            ReferenceKind::Variable => &self.0.variable,
+            ReferenceKind::VirtualKey => &self.0.virtual_key,
+            ReferenceKind::Layer => &self.0.layer,
+            ReferenceKind::Template => &self.0.template,
+            ReferenceKind::Include => &self.0.include,
+        };
+        location_map.0.get(name).map(Vec::as_slice).unwrap_or(&[])
+    }
+}
+
+/// Span of the first occurrence of `needle` after `skip` bytes of `text`,
+/// like the ones in definition and reference locations from kanata-parser.
+#[cfg(test)]
+pub fn span_of(file_name: &str, text: &str, skip: usize, needle: &str) -> Span {
+    let position = |absolute: usize| kanata_parser::cfg::sexpr::Position {
+        absolute,
+        line: text[..absolute].matches('\n').count(),
+        line_beginning: text[..absolute].rfind('\n').map_or(0, |x| x + 1),
+    };
+    let start = skip + text[skip..].find(needle).unwrap();
+    Span {
+        start: position(start),
+        end: position(start + needle.len()),
+        file_name: file_name.into(),
+        file_content: text.into(),
+    }
 }
 
 pub fn lsp_range_from_span(span: &Span) -> lsp_types::Range {
@@ -264,7 +348,24 @@ pub fn parse_wrapper(
     result.expect("no err")
 }
 
+/// Replaces `~` at the start of `path` with the home directory, like kanata does.
+/// Returns `path` unchanged if the home directory is unknown.
+pub fn expand_tilde(path: &Path) -> PathBuf {
+    #[cfg(not(target_arch = "wasm32"))]
+    if let Ok(rest) = path.strip_prefix("~") {
+        if let Some(home) = std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE")) {
+            return PathBuf::from(home).join(rest);
+        }
+    }
+    path.to_path_buf()
+}
+
+pub fn is_in_project_root(url: &Url, project_root: &Url) -> bool {
+    url.as_str().starts_with(project_root.as_str())
+}
+
 pub fn path_to_url(path: &Path, root_folder: &Url) -> anyhow::Result<Url> {
+    let path = expand_tilde(path);
     let file_url = if path.is_absolute() {
         Url::from_str(format!("file://{}", path.to_string_lossy()).as_ref())
             .map_err(|_| anyhow!("invalid path"))?
@@ -345,3 +446,83 @@ pub fn to_js_value<T: serde::ser::Serialize + ?Sized>(
 ) -> std::result::Result<wasm_bindgen::JsValue, serde_wasm_bindgen::Error> {
     value.serialize(&serde_wasm_bindgen::Serializer::new().serialize_maps_as_objects(true))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_markdown_table_cell() {
+        assert_eq!(
+            markdown_table_cell("(tap-hold 200 200\n  a b)"),
+            "`(tap-hold 200 200 a b)`"
+        );
+        assert_eq!(markdown_table_cell("a|b"), "`a\\|b`");
+        assert_eq!(markdown_table_cell("(unicode `)"), "``(unicode `)``");
+        assert_eq!(markdown_table_cell("``"), "``` `` ```");
+    }
+
+    #[test]
+    fn test_range_without_prefix() {
+        let range = Range::new(
+            lsp_types::Position::new(1, 4),
+            lsp_types::Position::new(1, 8),
+        );
+        let without_first_char = Range::new(lsp_types::Position::new(1, 5), range.end);
+        assert_eq!(
+            ReferenceKind::Alias.range_without_prefix(range),
+            without_first_char
+        );
+        assert_eq!(
+            ReferenceKind::Variable.range_without_prefix(range),
+            without_first_char
+        );
+        assert_eq!(ReferenceKind::Layer.range_without_prefix(range), range);
+        assert_eq!(ReferenceKind::Template.range_without_prefix(range), range);
+    }
+
+    #[test]
+    fn test_get_definition_and_references() {
+        let text = "(defvar v 1)\n(defalias a $v b @a)\n(deflayer base @a)";
+        let mut defs = DefinitionLocations::default();
+        defs.0
+            .variable
+            .insert("v".into(), span_of("f", text, 8, "v"));
+        defs.0.alias.insert("a".into(), span_of("f", text, 23, "a"));
+        let mut refs = ReferenceLocations::default();
+        refs.0
+            .variable
+            .0
+            .insert("v".into(), vec![span_of("f", text, 0, "$v")]);
+        refs.0.alias.0.insert(
+            "a".into(),
+            vec![span_of("f", text, 24, "@a"), span_of("f", text, 34, "@a")],
+        );
+
+        assert_eq!(
+            defs.get_definition(ReferenceKind::Alias, "a"),
+            Some(&span_of("f", text, 23, "a"))
+        );
+        assert_eq!(
+            defs.get_definition(ReferenceKind::Variable, "v"),
+            Some(&span_of("f", text, 8, "v"))
+        );
+        // Same name, different kind.
+        assert_eq!(defs.get_definition(ReferenceKind::Layer, "a"), None);
+        assert_eq!(defs.get_definition(ReferenceKind::Include, "a"), None);
+
+        // Reference spans include the prefix.
+        let alias_refs = refs.get_references(ReferenceKind::Alias, "a");
+        assert_eq!(alias_refs.len(), 2);
+        assert_eq!(
+            lsp_range_from_span(&alias_refs[0]),
+            Range::new(
+                lsp_types::Position::new(1, 17),
+                lsp_types::Position::new(1, 19)
+            )
+        );
+        assert_eq!(refs.get_references(ReferenceKind::Variable, "v").len(), 1);
+        assert!(refs.get_references(ReferenceKind::Variable, "a").is_empty());
+        assert!(refs.get_references(ReferenceKind::Alias, "b").is_empty());
+    }
+}
@@ -0,0 +1,125 @@
+use lsp_types::{InlayHint, InlayHintKind, InlayHintLabel, Position, Range};
+
+use crate::{
+    formatter::ext_tree::{Expr, ExtParseTree},
+    navigation::physical_keys::{key_at_path, KeyBlock},
+    signature_help::actions::action_signature,
+};
+
+pub mod variable_values;
+
+/// Returns names of parameters of `tap-hold` family of actions.
+fn tap_hold_parameters(action: &str) -> Option<&'static [&'static str]> {
+    if !action.starts_with("tap-hold") {
+        return None;
+    }
+    action_signature(action).map(|x| x.params)
+}
+
+fn hint(position: Position, label: String, kind: Option<InlayHintKind>) -> InlayHint {
+    InlayHint {
+        position,
+        label: InlayHintLabel::String(label),
+        kind,
+        text_edits: None,
+        tooltip: None,
+        padding_left: None,
+        padding_right: Some(true),
+        data: None,
+    }
+}
+
+/// Returns inlay hints located in `range`:
+/// - `defsrc` key before each `deflayer` slot, if `defsrc_keys` are known,
+/// - parameter names before arguments of `tap-hold` actions.
+pub fn inlay_hints(
+    tree: &ExtParseTree,
+    range: Range,
+    defsrc_keys: Option<&[String]>,
+) -> Vec<InlayHint> {
+    let mut hints = vec![];
+    tree.visit_nodes_with_ranges(&mut |path, expr, node_range| {
+        if node_range.start < range.start || node_range.start > range.end {
+            return;
+        }
+        let slot = key_at_path(tree, path).filter(|x| {
+            // Nodes inside of lists in slots aren't annotated.
+            x.block == KeyBlock::Deflayer && path.len() == 2
+        });
+        match (slot, defsrc_keys) {
+            // Layers with mismatched item count can't be mapped to defsrc keys.
+            (Some(slot), Some(keys)) if slot.key_count == keys.len() => {
+                let key = &keys[slot.key_index];
+                // Hint would only repeat the slot.
+                if !matches!(expr, Expr::Atom(x) if x == key) {
+                    hints.push(hint(node_range.start, format!("{key}:"), None));
+                }
+            }
+            _ => {}
+        };
+        // Argument of an action.
+        if let Some((&arg_index, parent_path)) = path.split_last() {
+            let action = match tree.get_node_by_path(parent_path) {
+                Ok(Expr::List(items)) => items.get(0).map(|x| &x.expr),
+                _ => None,
+            };
+            let param = match action {
+                Some(Expr::Atom(action)) if arg_index > 0 => {
+                    tap_hold_parameters(action).and_then(|x| x.get(arg_index - 1).copied())
+                }
+                _ => None,
+            };
+            if let Some(param) = param {
+                hints.push(hint(
+                    node_range.start,
+                    format!("{param}:"),
+                    Some(InlayHintKind::PARAMETER),
+                ));
+            }
+        }
+    });
+    hints
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formatter::ext_tree::parse_into_ext_tree;
+
+    #[test]
+    fn test_inlay_hints() {
+        let tree = parse_into_ext_tree(
+            "(defsrc a b c)\n(deflayer base x b (tap-hold 200 150 c lctl))\n(deflayer short x)",
+        )
+        .unwrap();
+        let keys = ["a", "b", "c"].map(String::from);
+        let everything = Range::new(Position::new(0, 0), Position::new(3, 0));
+        let labels = |hints: Vec<InlayHint>| -> Vec<(Position, String)> {
+            hints
+                .into_iter()
+                .map(|x| match x.label {
+                    InlayHintLabel::String(label) => (x.position, label),
+                    InlayHintLabel::LabelParts(_) => unreachable!(),
+                })
+                .collect()
+        };
+
+        assert_eq!(
+            labels(inlay_hints(&tree, everything, Some(&keys))),
+            vec![
+                (Position::new(1, 15), "a:".to_string()),
+                (Position::new(1, 19), "c:".to_string()),
+                (Position::new(1, 29), "tap-timeout:".to_string()),
+                (Position::new(1, 33), "hold-timeout:".to_string()),
+                (Position::new(1, 37), "tap-action:".to_string()),
+                (Position::new(1, 39), "hold-action:".to_string()),
+            ]
+        );
+        let second_slot = Range::new(Position::new(1, 17), Position::new(1, 19));
+        assert_eq!(labels(inlay_hints(&tree, second_slot, None)), vec![]);
+        assert_eq!(
+            labels(inlay_hints(&tree, second_slot, Some(&keys))),
+            vec![(Position::new(1, 19), "c:".to_string())]
+        );
+    }
+}
@@ -0,0 +1,144 @@
+use std::collections::HashMap;
+
+use lsp_types::{InlayHint, InlayHintLabel, InlayHintTooltip, Range};
+
+use crate::formatter::ext_tree::{Expr, ExtParseTree};
+
+/// Blocks in which uses of variables get their values shown.
+const BLOCKS_WITH_VALUE_HINTS: [&str; 3] = ["defalias", "defaliasenvcond", "defcfg"];
+
+/// Values longer than this are shortened in hints, and shown in full in tooltips.
+const MAX_HINT_LEN: usize = 30;
+
+/// Limits how deep variables referencing other variables are resolved,
+/// so that circular references don't hang the server.
+const MAX_RESOLVE_DEPTH: usize = 16;
+
+/// Collects values of all variables defined with `defvar` in `trees`.
+pub fn defined_variables(trees: &[&ExtParseTree]) -> HashMap<String, Expr> {
+    let mut result = HashMap::new();
+    for tree in trees {
+        for (_, items) in tree.toplevel_blocks("defvar") {
+            let items: Vec<&Expr> = items.iter().skip(1).map(|x| &x.expr).collect();
+            for pair in items.chunks_exact(2) {
+                if let Expr::Atom(name) = pair[0] {
+                    result.insert(name.clone(), pair[1].clone());
+                }
+            }
+        }
+    }
+    result
+}
+
+/// Returns value of `expr` with variables replaced by their values.
+/// `concat` lists are replaced with the concatenated string.
+fn resolve(expr: &Expr, variables: &HashMap<String, Expr>, depth: usize) -> Option<String> {
+    if depth > MAX_RESOLVE_DEPTH {
+        return None;
+    }
+    match expr {
+        Expr::Atom(x) => match x.strip_prefix('$') {
+            Some(name) => resolve(variables.get(name)?, variables, depth + 1),
+            None => Some(x.clone()),
+        },
+        Expr::List(items) => {
+            let is_concat =
+                matches!(items.get(0).map(|x| &x.expr), Some(Expr::Atom(x)) if x == "concat");
+            if is_concat {
+                let parts = items
+                    .iter()
+                    .skip(1)
+                    .map(|x| resolve(&x.expr, variables, depth + 1))
+                    .collect::<Option<Vec<_>>>()?;
+                let text: String = parts.iter().map(|x| x.trim_matches('"')).collect();
+                return Some(format!("\"{text}\""));
+            }
+            let items = items
+                .iter()
+                .map(|x| resolve(&x.expr, variables, depth + 1))
+                .collect::<Option<Vec<_>>>()?;
+            Some(format!("({})", items.join(" ")))
+        }
+    }
+}
+
+/// Returns hints with resolved values of variables used in `defalias` and `defcfg` blocks.
+pub fn variable_value_hints(
+    tree: &ExtParseTree,
+    range: Range,
+    variables: &HashMap<String, Expr>,
+) -> Vec<InlayHint> {
+    let mut hints = vec![];
+    tree.visit_nodes_with_ranges(&mut |path, expr, node_range| {
+        if node_range.start < range.start || node_range.start > range.end {
+            return;
+        }
+        if !matches!(expr, Expr::Atom(x) if x.starts_with('$')) {
+            return;
+        }
+        match tree.toplevel_block(path[0]) {
+            Some((block, _)) if BLOCKS_WITH_VALUE_HINTS.contains(&block) => {}
+            _ => return,
+        };
+        let value = match resolve(expr, variables, 0) {
+            Some(x) => x,
+            None => return,
+        };
+        let (label, tooltip) = if value.chars().count() > MAX_HINT_LEN {
+            let shortened: String = value.chars().take(MAX_HINT_LEN).collect();
+            (
+                format!("= {shortened}…"),
+                Some(InlayHintTooltip::String(value)),
+            )
+        } else {
+            (format!("= {value}"), None)
+        };
+        hints.push(InlayHint {
+            position: node_range.end,
+            label: InlayHintLabel::String(label),
+            kind: None,
+            text_edits: None,
+            tooltip,
+            padding_left: Some(true),
+            padding_right: None,
+            data: None,
+        });
+    });
+    hints
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formatter::ext_tree::parse_into_ext_tree;
+    use lsp_types::Position;
+
+    #[test]
+    fn test_variable_value_hints() {
+        let tree = parse_into_ext_tree(concat!(
+            "(defvar tt 200 ht $tt keys (a b) dir \"/home\" path (concat $dir \"/x\") loop $loop)\n",
+            "(defalias h (tap-hold $tt $ht a lmet) p (cmd $path) k $keys l $loop)\n",
+            "(deflayer base $tt)",
+        ))
+        .unwrap();
+        let variables = defined_variables(&[&tree]);
+        let everything = Range::new(Position::new(0, 0), Position::new(3, 0));
+        let hints: Vec<(Position, String)> = variable_value_hints(&tree, everything, &variables)
+            .into_iter()
+            .map(|x| match x.label {
+                InlayHintLabel::String(label) => (x.position, label),
+                InlayHintLabel::LabelParts(_) => unreachable!(),
+            })
+            .collect();
+
+        assert_eq!(
+            hints,
+            vec![
+                (Position::new(1, 25), "= 200".to_string()),
+                (Position::new(1, 29), "= 200".to_string()),
+                (Position::new(1, 50), "= \"/home/x\"".to_string()),
+                (Position::new(1, 59), "= (a b)".to_string()),
+            ]
+        );
+    }
+}
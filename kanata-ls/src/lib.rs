@@ -9,13 +9,10 @@ static ALLOC: wee_alloc::WeeAlloc = wee_alloc::WeeAlloc::INIT;
 use crate::helpers::to_js_value;
 use crate::{
     formatter::defsrc_layout::LineEndingSequence,
-    helpers::{lsp_range_from_span, path_to_url, HashSet},
+    helpers::{is_in_project_root, lsp_range_from_span, markdown_table_cell, path_to_url, HashSet},
 };
 use anyhow::{anyhow, bail};
-use formatter::{
-    ext_tree::{Expr, ParseTreeNode},
-    Formatter,
-};
+use formatter::{sort_definitions::DefinitionsOrder, Formatter};
 use itertools::Itertools;
 use kanata_parser::{
     cfg::{sexpr::Span, FileContentProvider, ParseError},
@@ -23,21 +20,29 @@ use kanata_parser::{
 };
 use lsp_types::{
     notification::{
-        DidChangeTextDocument, DidChangeWatchedFiles, DidCloseTextDocument, DidDeleteFiles,
-        DidOpenTextDocument, DidSaveTextDocument, Initialized, Notification,
+        DidChangeConfiguration, DidChangeTextDocument, DidChangeWatchedFiles,
+        DidChangeWorkspaceFolders, DidCloseTextDocument, DidDeleteFiles, DidOpenTextDocument,
+        DidSaveTextDocument, Initialized, Notification, SetTrace,
     },
-    DeleteFilesParams, Diagnostic, DiagnosticSeverity, DiagnosticTag, DidChangeTextDocumentParams,
-    DidChangeWatchedFilesParams, DidOpenTextDocumentParams, DidSaveTextDocumentParams,
-    DocumentFormattingParams, FileChangeType, FileDelete, FileEvent, FileOperationFilter,
+    CallHierarchyIncomingCall, CallHierarchyIncomingCallsParams, CallHierarchyItem,
+    CallHierarchyOutgoingCall, CallHierarchyOutgoingCallsParams, CallHierarchyPrepareParams,
+    CodeActionKind, CodeActionOrCommand, CodeActionParams, CodeActionResponse, CodeLens,
+    CodeLensParams, DeleteFilesParams, Diagnostic, DiagnosticSeverity, DiagnosticTag,
+    DidChangeConfigurationParams, DidChangeTextDocumentParams, DidChangeWatchedFilesParams,
+    DidChangeWorkspaceFoldersParams, DidCloseTextDocumentParams, DidOpenTextDocumentParams,
+    DidSaveTextDocumentParams, DocumentFormattingParams, DocumentHighlight,
+    DocumentHighlightParams, FileChangeType, FileDelete, FileEvent, FileOperationFilter,
     FileOperationPattern, GotoDefinitionParams, GotoDefinitionResponse, Hover, HoverContents,
-    HoverParams, InitializeParams, InitializeResult, LanguageString, LocationLink, MarkedString,
+    HoverParams, InitializeParams, InitializeResult, InlayHint, InlayHintParams,
+    LinkedEditingRangeParams, LinkedEditingRanges, LocationLink, MarkupContent, MarkupKind,
     Position, PositionEncodingKind, PrepareRenameResponse, PublishDiagnosticsParams, RenameParams,
-    SemanticTokenModifier, SemanticTokenType, SemanticTokensLegend, TextDocumentItem,
-    TextDocumentPositionParams, TextDocumentSyncKind, TextEdit, Url,
-    VersionedTextDocumentIdentifier, WorkspaceEdit,
+    SemanticTokenModifier, SemanticTokenType, SemanticTokensLegend, SetTraceParams, SignatureHelp,
+    SignatureHelpParams, TextDocumentItem, TextDocumentPositionParams, TextDocumentSyncKind,
+    TextEdit, Url, VersionedTextDocumentIdentifier, WorkspaceEdit,
 };
-use serde::Deserialize;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::{
+    cell::OnceCell,
     collections::{BTreeMap, HashMap},
     fmt::Display,
     path::{self, Path, PathBuf},
@@ -49,8 +54,16 @@ use helpers::{
     empty_diagnostics_for_doc, parse_wrapper, CustomParseError, DefinitionLocations, Diagnostics,
     Documents, KlsParserOutput, ReferenceLocations,
 };
+#[cfg(not(target_arch = "wasm32"))]
+pub mod cli;
+mod code_actions;
 mod formatter;
+mod inlay_hints;
+pub mod logger;
+use logger::LogLevel;
 mod navigation;
+mod signature_help;
+use navigation::physical_keys::KeyBlock;
 
 #[cfg(target_arch = "wasm32")]
 use wasm_bindgen::prelude::{wasm_bindgen, JsValue};
@@ -71,6 +84,49 @@ const KANATA_PARSER_HELP: &str = r"For more info, see the configuration guide or
 
 const MAIN_CONFIG_FILE_DEFAULT: &str = "kanata.kbd";
 
+/// Error sent to the client in response to a request that couldn't be handled.
+/// Handlers return it instead of panicking, which would end the session.
+#[derive(Debug, Clone, Serialize)]
+pub struct ResponseError {
+    pub code: i32,
+    pub message: String,
+}
+
+impl ResponseError {
+    /// JSON-RPC error code, which isn't in `lsp_types::error_codes`.
+    const INVALID_PARAMS: i32 = -32602;
+
+    pub fn invalid_params(message: impl Display) -> Self {
+        Self {
+            code: Self::INVALID_PARAMS,
+            message: message.to_string(),
+        }
+    }
+
+    pub fn request_failed(message: impl Display) -> Self {
+        Self {
+            code: lsp_types::error_codes::REQUEST_FAILED as i32,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl Display for ResponseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+pub fn params_from_value<T: DeserializeOwned>(
+    params: serde_json::Value,
+) -> Result<T, ResponseError> {
+    serde_json::from_value(params)
+        .map_err(|e| ResponseError::invalid_params(format!("invalid params: {e}")))
+}
+
+/// Section of settings requested from the client, the same as in VS Code.
+pub const CONFIGURATION_SECTION: &str = "vscode-kanata";
+
 impl Kanata {
     fn new(
         def_local_keys_variant_to_apply: DefLocalKeysVariant,
@@ -143,7 +199,7 @@ impl Kanata {
         let mut get_file_content_fn_impl = |filepath: &Path| {
             let file_url = path_to_url(filepath, project_root).map_err(|_| INVALID_PATH_ERROR)?;
 
-            log!("searching URL across opened documents: {}", file_url);
+            log_trace!("searching URL across opened documents: {}", file_url);
             let doc = all_documents.get(&file_url).ok_or_else(|| {
                 kanata_extension_error("Can't open this file for analysis, because it doesn't exist, or is outside of opened workspace.")
             })?;
@@ -183,43 +239,124 @@ impl Kanata {
             &self.env_vars,
         )
     }
+
+    /// Parses with includes read from disk, relative to the directory of `main_cfg_file`,
+    /// like kanata itself does.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn parse_from_disk(&self, main_cfg_file: &Path, main_cfg_text: &str) -> KlsParserOutput {
+        let main_cfg_dir = main_cfg_file.parent().unwrap_or(Path::new(""));
+
+        let mut loaded_files: HashSet<PathBuf> = HashSet::default();
+        if let Ok(path) = main_cfg_file.canonicalize() {
+            loaded_files.insert(path);
+        }
+
+        let mut get_file_content_fn_impl = |filepath: &Path| {
+            let path = main_cfg_dir.join(helpers::expand_tilde(filepath));
+            let canonical_path = path
+                .canonicalize()
+                .map_err(|e| format!("Failed to open {}: {}", path.display(), e))?;
+            if !loaded_files.insert(canonical_path) {
+                return Err("The provided config file was already included before".to_string());
+            }
+            std::fs::read_to_string(&path)
+                .map_err(|e| format!("Failed to read {}: {}", path.display(), e))
+        };
+
+        parse_wrapper(
+            main_cfg_text,
+            main_cfg_file,
+            &mut FileContentProvider::new(&mut get_file_content_fn_impl),
+            &self.def_local_keys_variant_to_apply,
+            &self.env_vars,
+        )
+    }
 }
 
+/// Settings of the VS Code extension. Missing settings have default values.
 #[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
 struct Config {
     #[serde(rename = "includesAndWorkspaces")]
     includes_and_workspaces: IncludesAndWorkspaces,
-    #[serde(rename = "mainConfigFile")]
-    main_config_file: String,
-    #[serde(rename = "localKeysVariant")]
+    /// Either a single file name, or a list of them.
+    /// Empty if main config files should be detected.
+    #[serde(
+        rename = "mainConfigFile",
+        deserialize_with = "deserialize_one_or_many"
+    )]
+    main_config_files: Vec<String>,
+    #[serde(
+        rename = "localKeysVariant",
+        deserialize_with = "deserialize_local_keys_variant"
+    )]
     def_local_keys_variant: DefLocalKeysVariant,
     format: ExtensionFormatterOptions,
-    #[serde(rename = "envVariables")]
+    // `environmentVariables` is the name of the setting in VS Code.
+    #[serde(rename = "envVariables", alias = "environmentVariables")]
     env_variables: HashMap<String, String>,
     #[serde(rename = "dimInactiveConfigItems")]
     dim_inactive_config_items: bool,
+    #[serde(rename = "logLevel")]
+    log_level: LogLevel,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Config {
             includes_and_workspaces: IncludesAndWorkspaces::Single,
-            main_config_file: MAIN_CONFIG_FILE_DEFAULT.to_string(),
-            def_local_keys_variant: match std::env::consts::OS {
-                "linux" => DefLocalKeysVariant::Linux,
-                "macos" => DefLocalKeysVariant::MacOS,
-                _ => DefLocalKeysVariant::Win,
-            },
+            main_config_files: vec![],
+            def_local_keys_variant: DefLocalKeysVariant::for_current_os(),
             format: ExtensionFormatterOptions {
                 enable: false,
                 use_defsrc_layout_on_deflayers: false,
             },
             env_variables: HashMap::new(),
             dim_inactive_config_items: true,
+            log_level: LogLevel::Info,
         }
     }
 }
 
+/// Drops settings that can't be deserialized into [`Config`], so that a single invalid
+/// setting doesn't discard the valid ones. Unknown settings are kept.
+fn valid_settings(
+    settings: serde_json::Map<String, serde_json::Value>,
+) -> serde_json::Map<String, serde_json::Value> {
+    settings
+        .into_iter()
+        .filter(|(key, value)| {
+            let setting = serde_json::json!({ key: value });
+            match serde_json::from_value::<Config>(setting) {
+                Ok(_) => true,
+                Err(e) => {
+                    log_warn!("invalid setting `{}`, ignoring it: {}", key, e);
+                    false
+                }
+            }
+        })
+        .collect()
+}
+
+fn deserialize_one_or_many<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(x) => vec![x],
+        OneOrMany::Many(xs) => xs,
+    }
+    .into_iter()
+    .filter(|x| !x.is_empty())
+    .collect())
+}
+
 #[derive(Debug, Deserialize, Clone, Copy)]
 enum IncludesAndWorkspaces {
     #[serde(rename = "single")]
@@ -242,6 +379,37 @@ enum DefLocalKeysVariant {
     WinIOv2,
 }
 
+impl DefLocalKeysVariant {
+    fn for_current_os() -> Self {
+        match std::env::consts::OS {
+            "linux" => DefLocalKeysVariant::Linux,
+            "macos" => DefLocalKeysVariant::MacOS,
+            _ => DefLocalKeysVariant::Win,
+        }
+    }
+}
+
+/// Accepts `auto` too, as the setting can be set to it. The VS Code extension resolves it
+/// itself, because the OS isn't known to the wasm build.
+fn deserialize_local_keys_variant<'de, D>(deserializer: D) -> Result<DefLocalKeysVariant, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum VariantOrAuto {
+        Variant(DefLocalKeysVariant),
+        Auto(String),
+    }
+    match VariantOrAuto::deserialize(deserializer)? {
+        VariantOrAuto::Variant(x) => Ok(x),
+        VariantOrAuto::Auto(x) if x == "auto" => Ok(DefLocalKeysVariant::for_current_os()),
+        VariantOrAuto::Auto(x) => Err(serde::de::Error::custom(format!(
+            "unknown localKeysVariant: {x}"
+        ))),
+    }
+}
+
 impl Display for DefLocalKeysVariant {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -254,7 +422,7 @@ impl Display for DefLocalKeysVariant {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 enum WorkspaceOptions {
     Single {
         /// `root` is `None` when the document is not opened in a workspace.
@@ -267,49 +435,250 @@ enum WorkspaceOptions {
 }
 
 impl WorkspaceOptions {
-    fn from_config(config: &Config, workspace_root: Option<Url>) -> Self {
-        match config.includes_and_workspaces {
-            IncludesAndWorkspaces::Single => WorkspaceOptions::Single {
-                root: workspace_root,
-            },
-            IncludesAndWorkspaces::Workspace => {
-                let workspace_root =
-                    workspace_root.expect("root folder should be set in workspace mode");
+    /// Returns options for every main config file in every workspace folder.
+    /// Main config files given as absolute paths are shared by all folders.
+    /// If no main config file is set, every tracked file that no other file
+    /// includes is a main config file.
+    fn all_from_config(
+        config: &Config,
+        workspace_folders: &[Url],
+        includes_by_file: &IncludesByFile,
+    ) -> Vec<Self> {
+        if workspace_folders.is_empty() {
+            return vec![WorkspaceOptions::Single { root: None }];
+        }
+        let mut result = vec![];
+        for workspace_root in workspace_folders {
+            let options = match config.includes_and_workspaces {
+                IncludesAndWorkspaces::Single => vec![WorkspaceOptions::Single {
+                    root: Some(workspace_root.clone()),
+                }],
+                IncludesAndWorkspaces::Workspace if config.main_config_files.is_empty() => {
+                    root_config_files(includes_by_file, workspace_root)
+                        .iter()
+                        .map(|url| {
+                            let relative_path = &url.as_str()[workspace_root.as_str().len()..];
+                            Self::workspace(workspace_root.clone(), relative_path)
+                        })
+                        .collect()
+                }
+                IncludesAndWorkspaces::Workspace => config
+                    .main_config_files
+                    .iter()
+                    .map(|main_config_file| {
+                        Self::workspace(workspace_root.clone(), main_config_file)
+                    })
+                    .collect(),
+            };
+            for x in options {
+                if !result.contains(&x) {
+                    result.push(x);
+                }
+            }
+        }
+        result
+    }
 
-                let pb = PathBuf::from(config.main_config_file.clone());
-                let main_cfg_file = pb.as_path();
+    fn workspace(workspace_root: Url, main_config_file: &str) -> Self {
+        let pb = PathBuf::from(main_config_file);
+        let main_cfg_file = pb.as_path();
 
-                let dirname = main_cfg_file.parent().unwrap_or(Path::new(""));
-                let basename = match main_cfg_file.file_name() {
-                    Some(x) => x,
-                    None => {
-                        log!(
-                            "invalid main_cfg_file name, falling back to {}",
-                            MAIN_CONFIG_FILE_DEFAULT
-                        );
-                        Path::new(MAIN_CONFIG_FILE_DEFAULT).as_os_str()
-                    }
-                };
+        let dirname = main_cfg_file.parent().unwrap_or(Path::new(""));
+        let basename = match main_cfg_file.file_name() {
+            Some(x) => x,
+            None => {
+                log!(
+                    "invalid main_cfg_file name, falling back to {}",
+                    MAIN_CONFIG_FILE_DEFAULT
+                );
+                Path::new(MAIN_CONFIG_FILE_DEFAULT).as_os_str()
+            }
+        };
 
-                // Handle cases where the main file in a workspace subfolder, not directly in the workspace root.
-                // Also this handles case where `main_config_file` is an absolute path.
-                let project_root = match workspace_root
-                    .join(&dirname.join("").as_os_str().to_string_lossy())
-                {
-                    Ok(x) => x,
-                    Err(e) => {
-                        log!("failed setting project root from main_cfg_file, falling back to workspace root. Error: {:?}", e);
-                        workspace_root
-                    }
-                };
+        // Handle cases where the main file in a workspace subfolder, not directly in the workspace root.
+        // Also this handles case where `main_config_file` is an absolute path.
+        let project_root = match workspace_root
+            .join(&dirname.join("").as_os_str().to_string_lossy())
+        {
+            Ok(x) => x,
+            Err(e) => {
+                log_warn!("failed setting project root from main_cfg_file, falling back to workspace root. Error: {:?}", e);
+                workspace_root
+            }
+        };
 
-                WorkspaceOptions::Workspace {
-                    main_config_file: basename.into(),
-                    project_root,
-                }
+        WorkspaceOptions::Workspace {
+            main_config_file: basename.into(),
+            project_root,
+        }
+    }
+}
+
+/// Paths that each tracked file includes, as written in the file.
+type IncludesByFile = HashMap<Url, Vec<PathBuf>>;
+
+fn includes_by_file(documents: &Documents) -> IncludesByFile {
+    documents
+        .iter()
+        .map(|(url, doc)| {
+            let includes = formatter::ext_tree::parse_into_ext_tree_and_root_span(&doc.text)
+                .ok()
+                .and_then(|(tree, _)| tree.includes().ok())
+                .unwrap_or_default();
+            (url.clone(), includes)
+        })
+        .collect()
+}
+
+/// Returns tracked files that `main_config_file` (transitively) includes, including itself.
+/// Like in kanata, all includes are relative to `project_root`, the directory of the main
+/// config file, no matter which file they're in.
+fn files_included_by(
+    includes_by_file: &IncludesByFile,
+    main_config_file: Url,
+    project_root: &Url,
+) -> HashSet<Url> {
+    let mut files: HashSet<Url> = HashSet::default();
+    let mut queue = vec![main_config_file];
+    while let Some(url) = queue.pop() {
+        let Some(includes) = includes_by_file.get(&url) else {
+            continue;
+        };
+        if !files.insert(url) {
+            continue;
+        }
+        queue.extend(
+            includes
+                .iter()
+                .filter_map(|include| path_to_url(include, project_root).ok()),
+        );
+    }
+    files
+}
+
+/// Returns tracked `.kbd` files in `folder` that no other tracked file includes.
+/// Every file could be a main config file, so includes are resolved as if it was one.
+fn root_config_files(includes_by_file: &IncludesByFile, folder: &Url) -> Vec<Url> {
+    let files: Vec<&Url> = includes_by_file
+        .keys()
+        .filter(|url| is_in_project_root(url, folder) && url.path().ends_with(".kbd"))
+        .sorted()
+        .collect();
+    let mut included: HashSet<Url> = HashSet::default();
+    for url in &files {
+        let Ok(project_root) = url.join("./") else {
+            continue;
+        };
+        included.extend(
+            files_included_by(includes_by_file, (*url).clone(), &project_root)
+                .into_iter()
+                .filter(|x| x != *url),
+        );
+    }
+    files
+        .into_iter()
+        .filter(|url| !included.contains(url))
+        .cloned()
+        .collect()
+}
+
+/// Main config files of all workspaces, each with tracked files it (transitively) includes.
+struct IncludeGraph {
+    workspaces: Vec<(WorkspaceOptions, HashSet<Url>)>,
+}
+
+impl IncludeGraph {
+    fn new(config: &Config, workspace_folders: &[Url], documents: &Documents) -> Self {
+        let includes_by_file = includes_by_file(documents);
+        let workspaces =
+            WorkspaceOptions::all_from_config(config, workspace_folders, &includes_by_file)
+                .into_iter()
+                .map(|ws| {
+                    let files = match &ws {
+                        // Files aren't included in single file mode.
+                        WorkspaceOptions::Single { .. } => HashSet::default(),
+                        WorkspaceOptions::Workspace {
+                            main_config_file,
+                            project_root,
+                        } => match path_to_url(main_config_file, project_root) {
+                            Ok(url) => files_included_by(&includes_by_file, url, project_root),
+                            Err(_) => HashSet::default(),
+                        },
+                    };
+                    (ws, files)
+                })
+                .collect();
+        Self { workspaces }
+    }
+}
+
+/// Returns the layer that is active on startup, which is the first one kanata parses.
+/// Kanata parses included files in place of their `include`, so positions are
+/// compared as offsets of the `include`s leading to a file, followed by the offset
+/// in that file. `project_root` is `None` in single file mode, where nothing is included.
+fn base_layer(
+    main_config_file: Url,
+    project_root: Option<&Url>,
+    def_locs: &HashMap<Url, DefinitionLocations>,
+    ref_locs: &HashMap<Url, ReferenceLocations>,
+) -> Option<String> {
+    let mut first_layer: Option<(Vec<usize>, &String)> = None;
+    let mut visited: HashSet<Url> = HashSet::default();
+    let mut queue = vec![(main_config_file, vec![])];
+    while let Some((url, include_offsets)) = queue.pop() {
+        if !visited.insert(url.clone()) {
+            continue;
+        }
+        for (name, span) in def_locs
+            .iter()
+            .filter(|(x, _)| **x == url)
+            .flat_map(|(_, defs)| &defs.0.layer)
+        {
+            let mut order = include_offsets.clone();
+            order.push(span.start());
+            if first_layer.as_ref().is_none_or(|(x, _)| order < *x) {
+                first_layer = Some((order, name));
             }
         }
+        let (Some(project_root), Some(refs)) = (project_root, ref_locs.get(&url)) else {
+            continue;
+        };
+        for (path, spans) in &refs.0.include.0 {
+            let Ok(included_url) = path_to_url(Path::new(path), project_root) else {
+                continue;
+            };
+            for span in spans {
+                let mut order = include_offsets.clone();
+                order.push(span.start());
+                queue.push((included_url.clone(), order));
+            }
+        }
+    }
+    first_layer.map(|(_, name)| name.clone())
+}
+
+/// Returns a function that turns file names of spans, relative to the project root
+/// in workspace mode, into URLs. In single file mode, spans are in the source document.
+fn span_file_url_fn(
+    workspace_options: WorkspaceOptions,
+    source_doc_uri: Url,
+) -> impl Fn(&str) -> anyhow::Result<Url> {
+    move |path: &str| match &workspace_options {
+        WorkspaceOptions::Single { .. } => Ok(source_doc_uri.clone()),
+        WorkspaceOptions::Workspace { project_root, .. } => {
+            path_to_url(Path::new(&path), project_root)
+        }
+    }
+}
+
+/// Makes sure that relative paths can be joined to the folder URL.
+fn with_trailing_slash(mut url: Url) -> Url {
+    if !url.path().ends_with('/') {
+        if let Ok(mut segments) = url.path_segments_mut() {
+            segments.pop_if_empty().push("");
+        }
     }
+    url
 }
 
 #[derive(Debug, Deserialize, Clone, Copy)]
@@ -327,11 +696,22 @@ type PublishDiagnosticsClosure = &'static dyn Fn(&PublishDiagnosticsParams) -> a
 #[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
 pub struct KanataLanguageServer {
     documents: Documents,
+    /// Documents that weren't opened by the client, but read from disk instead.
+    documents_read_from_disk: HashSet<Url>,
     kanata: Kanata,
-    workspace_options: WorkspaceOptions,
+    config: Config,
+    /// Settings `config` was deserialized from.
+    settings: serde_json::Map<String, serde_json::Value>,
+    workspace_folders: Vec<Url>,
+    /// Worked out from documents and settings when needed. Has to be reset when any of them,
+    /// or workspace folders, change.
+    include_graph: OnceCell<IncludeGraph>,
     send_diagnostics_callback: PublishDiagnosticsClosure,
     formatter: formatter::Formatter,
     dim_inactive_config_items: bool,
+    /// Whether the client registers commands used in code lenses, like the VS Code extension does.
+    /// Other clients would fail to run them, so they don't get code lenses.
+    supports_client_commands: bool,
 }
 
 #[cfg(target_arch = "wasm32")]
@@ -341,11 +721,15 @@ impl KanataLanguageServer {
     pub fn w_new(
         initialize_params_js: JsValue,
         send_diagnostics_callback_js: js_sys::Function,
-    ) -> Self {
+    ) -> Result<KanataLanguageServer, JsValue> {
         console_error_panic_hook::set_once();
 
         let initialize_params: InitializeParams =
-            serde_wasm_bindgen::from_value(initialize_params_js).unwrap();
+            serde_wasm_bindgen::from_value(initialize_params_js).map_err(|e| {
+                js_error(ResponseError::invalid_params(format!(
+                    "invalid initialize params: {e}"
+                )))
+            })?;
 
         let send_fn: &'static _ = Box::leak(Box::new(send_diagnostics_callback_js));
         let send_diagnostics_callback: &'static _ =
@@ -362,73 +746,168 @@ impl KanataLanguageServer {
                 Ok(())
             }));
 
-        Self::new(initialize_params, send_diagnostics_callback)
+        // Logs go to the console here, so tracing can start right away.
+        if let Some(trace) = initialize_params.trace {
+            logger::set_trace(trace);
+        }
+        Ok(Self::new(initialize_params, send_diagnostics_callback))
     }
 
     #[allow(unused_variables)]
     #[wasm_bindgen(js_class = KanataLanguageServer, js_name = initialize)]
-    pub fn w_initialize(&mut self, params: JsValue) -> JsValue {
-        type Params = <lsp_types::request::Initialize as lsp_types::request::Request>::Params;
-        type Result = <lsp_types::request::Initialize as lsp_types::request::Request>::Result;
-        let params = serde_wasm_bindgen::from_value::<Params>(params).expect("deserializes");
-        to_js_value::<Result>(&self.initialize(&params)).expect("no conversion error")
+    pub fn w_initialize(&mut self, params: JsValue) -> Result<JsValue, JsValue> {
+        w_handle::<lsp_types::request::Initialize>(params, |params| Ok(self.initialize(params)))
     }
 
     #[allow(unused_variables)]
     #[cfg_attr(target_arch = "wasm32", wasm_bindgen(js_class = KanataLanguageServer, js_name = onNotification))]
-    pub fn w_on_notification(&mut self, method: &str, params: JsValue) {
+    pub fn w_on_notification(&mut self, method: &str, params: JsValue) -> Result<(), JsValue> {
         let params: serde_json::Value =
             serde_wasm_bindgen::from_value(params).unwrap_or(serde_json::Value::Null);
-        self.on_notification(method, params);
+        self.on_notification(method, params).map_err(js_error)
     }
 
     #[allow(unused_variables)]
     #[wasm_bindgen(js_class = KanataLanguageServer, js_name = onDocumentFormatting)]
-    pub fn w_on_document_formatting(&mut self, params: JsValue) -> JsValue {
-        type Params = <lsp_types::request::Formatting as lsp_types::request::Request>::Params;
-        type Result = <lsp_types::request::Formatting as lsp_types::request::Request>::Result;
-        let params = serde_wasm_bindgen::from_value::<Params>(params).expect("deserializes");
-        to_js_value::<Result>(&self.on_document_formatting(&params)).expect("no conversion error")
+    pub fn w_on_document_formatting(&mut self, params: JsValue) -> Result<JsValue, JsValue> {
+        w_handle::<lsp_types::request::Formatting>(params, |params| {
+            self.on_document_formatting(params)
+        })
     }
 
     #[allow(unused_variables)]
     #[wasm_bindgen(js_class = KanataLanguageServer, js_name = onDefinition)]
-    pub fn w_on_go_to_definition(&mut self, params: JsValue) -> JsValue {
-        type Params = <lsp_types::request::GotoDefinition as lsp_types::request::Request>::Params;
-        type Result = <lsp_types::request::GotoDefinition as lsp_types::request::Request>::Result;
-        let params = serde_wasm_bindgen::from_value::<Params>(params).expect("deserializes");
-        to_js_value::<Result>(&self.on_go_to_definition(&params)).expect("no conversion error")
+    pub fn w_on_go_to_definition(&mut self, params: JsValue) -> Result<JsValue, JsValue> {
+        w_handle::<lsp_types::request::GotoDefinition>(params, |params| {
+            self.on_go_to_definition(params)
+        })
+    }
+
+    #[allow(unused_variables)]
+    #[wasm_bindgen(js_class = KanataLanguageServer, js_name = onImplementation)]
+    pub fn w_on_go_to_implementation(&mut self, params: JsValue) -> Result<JsValue, JsValue> {
+        w_handle::<lsp_types::request::GotoImplementation>(params, |params| {
+            self.on_go_to_implementation(params)
+        })
     }
 
     #[allow(unused_variables)]
     #[wasm_bindgen(js_class = KanataLanguageServer, js_name = onHover)]
-    pub fn w_on_hover(&mut self, params: JsValue) -> JsValue {
-        type Params = <lsp_types::request::HoverRequest as lsp_types::request::Request>::Params;
-        type Result = <lsp_types::request::HoverRequest as lsp_types::request::Request>::Result;
-        let params = serde_wasm_bindgen::from_value::<Params>(params).expect("deserializes");
-        let result = self.on_hover(&params);
-        to_js_value::<Result>(&result).expect("no conversion error")
+    pub fn w_on_hover(&mut self, params: JsValue) -> Result<JsValue, JsValue> {
+        w_handle::<lsp_types::request::HoverRequest>(params, |params| self.on_hover(params))
     }
 
     #[allow(unused_variables)]
     #[wasm_bindgen(js_class = KanataLanguageServer, js_name = onPrepareRenameRequest)]
-    pub fn w_on_prepare_rename(&mut self, params: JsValue) -> JsValue {
-        type Params =
-            <lsp_types::request::PrepareRenameRequest as lsp_types::request::Request>::Params;
-        type Result =
-            <lsp_types::request::PrepareRenameRequest as lsp_types::request::Request>::Result;
-        let params = serde_wasm_bindgen::from_value::<Params>(params).expect("deserializes");
-        to_js_value::<Result>(&self.on_prepare_rename(&params)).expect("no conversion error")
+    pub fn w_on_prepare_rename(&mut self, params: JsValue) -> Result<JsValue, JsValue> {
+        w_handle::<lsp_types::request::PrepareRenameRequest>(params, |params| {
+            self.on_prepare_rename(params)
+        })
     }
 
     #[allow(unused_variables)]
     #[wasm_bindgen(js_class = KanataLanguageServer, js_name = onRenameRequest)]
-    pub fn w_on_rename(&mut self, params: JsValue) -> JsValue {
-        type Params = <lsp_types::request::Rename as lsp_types::request::Request>::Params;
-        type Result = <lsp_types::request::Rename as lsp_types::request::Request>::Result;
-        let params = serde_wasm_bindgen::from_value::<Params>(params).expect("deserializes");
-        to_js_value::<Result>(&self.on_rename(&params)).expect("no conversion error")
+    pub fn w_on_rename(&mut self, params: JsValue) -> Result<JsValue, JsValue> {
+        w_handle::<lsp_types::request::Rename>(params, |params| self.on_rename(params))
+    }
+
+    #[allow(unused_variables)]
+    #[wasm_bindgen(js_class = KanataLanguageServer, js_name = onLinkedEditingRange)]
+    pub fn w_on_linked_editing_range(&mut self, params: JsValue) -> Result<JsValue, JsValue> {
+        w_handle::<lsp_types::request::LinkedEditingRange>(params, |params| {
+            self.on_linked_editing_range(params)
+        })
+    }
+
+    #[allow(unused_variables)]
+    #[wasm_bindgen(js_class = KanataLanguageServer, js_name = onCodeLens)]
+    pub fn w_on_code_lens(&mut self, params: JsValue) -> Result<JsValue, JsValue> {
+        w_handle::<lsp_types::request::CodeLensRequest>(params, |params| self.on_code_lens(params))
+    }
+
+    #[allow(unused_variables)]
+    #[wasm_bindgen(js_class = KanataLanguageServer, js_name = onSignatureHelp)]
+    pub fn w_on_signature_help(&mut self, params: JsValue) -> Result<JsValue, JsValue> {
+        w_handle::<lsp_types::request::SignatureHelpRequest>(params, |params| {
+            self.on_signature_help(params)
+        })
+    }
+
+    #[allow(unused_variables)]
+    #[wasm_bindgen(js_class = KanataLanguageServer, js_name = onInlayHint)]
+    pub fn w_on_inlay_hint(&mut self, params: JsValue) -> Result<JsValue, JsValue> {
+        w_handle::<lsp_types::request::InlayHintRequest>(params, |params| {
+            self.on_inlay_hint(params)
+        })
+    }
+
+    #[allow(unused_variables)]
+    #[wasm_bindgen(js_class = KanataLanguageServer, js_name = onCodeAction)]
+    pub fn w_on_code_action(&mut self, params: JsValue) -> Result<JsValue, JsValue> {
+        w_handle::<lsp_types::request::CodeActionRequest>(params, |params| {
+            self.on_code_action(params)
+        })
+    }
+
+    #[allow(unused_variables)]
+    #[wasm_bindgen(js_class = KanataLanguageServer, js_name = onDocumentHighlight)]
+    pub fn w_on_document_highlight(&mut self, params: JsValue) -> Result<JsValue, JsValue> {
+        w_handle::<lsp_types::request::DocumentHighlightRequest>(params, |params| {
+            self.on_document_highlight(params)
+        })
+    }
+
+    #[allow(unused_variables)]
+    #[wasm_bindgen(js_class = KanataLanguageServer, js_name = onPrepareCallHierarchy)]
+    pub fn w_on_prepare_call_hierarchy(&mut self, params: JsValue) -> Result<JsValue, JsValue> {
+        w_handle::<lsp_types::request::CallHierarchyPrepare>(params, |params| {
+            self.on_prepare_call_hierarchy(params)
+        })
+    }
+
+    #[allow(unused_variables)]
+    #[wasm_bindgen(js_class = KanataLanguageServer, js_name = onCallHierarchyIncomingCalls)]
+    pub fn w_on_call_hierarchy_incoming_calls(
+        &mut self,
+        params: JsValue,
+    ) -> Result<JsValue, JsValue> {
+        w_handle::<lsp_types::request::CallHierarchyIncomingCalls>(params, |params| {
+            self.on_call_hierarchy_incoming_calls(params)
+        })
     }
+
+    #[allow(unused_variables)]
+    #[wasm_bindgen(js_class = KanataLanguageServer, js_name = onCallHierarchyOutgoingCalls)]
+    pub fn w_on_call_hierarchy_outgoing_calls(
+        &mut self,
+        params: JsValue,
+    ) -> Result<JsValue, JsValue> {
+        w_handle::<lsp_types::request::CallHierarchyOutgoingCalls>(params, |params| {
+            self.on_call_hierarchy_outgoing_calls(params)
+        })
+    }
+}
+
+/// Deserializes request params, calls the handler and serializes its result.
+/// Errors are thrown as `{ code, message }` objects, which the TS server
+/// sends to the client as error responses.
+#[cfg(target_arch = "wasm32")]
+fn w_handle<R: lsp_types::request::Request>(
+    params: JsValue,
+    handler: impl FnOnce(&R::Params) -> Result<R::Result, ResponseError>,
+) -> Result<JsValue, JsValue> {
+    let params = serde_wasm_bindgen::from_value::<R::Params>(params).map_err(|e| {
+        js_error(ResponseError::invalid_params(format!(
+            "invalid params: {e}"
+        )))
+    })?;
+    let result = handler(&params).map_err(js_error)?;
+    to_js_value(&result).map_err(|e| js_error(ResponseError::request_failed(e)))
+}
+
+#[cfg(target_arch = "wasm32")]
+fn js_error(err: ResponseError) -> JsValue {
+    to_js_value(&err).unwrap_or_else(|_| JsValue::from_str(&err.message))
 }
 
 impl KanataLanguageServer {
@@ -437,52 +916,71 @@ impl KanataLanguageServer {
         send_diagnostics_callback: PublishDiagnosticsClosure,
     ) -> Self {
         #[allow(deprecated)]
-        let mut root_uri = initialize_params.root_uri;
+        let root_uri = initialize_params.root_uri;
         let initialization_options = initialize_params.initialization_options;
 
-        let mut config: Config = match initialization_options {
-            Some(opts) => serde_json::from_str(opts.to_string().as_str()).unwrap(),
-            None => {
-                log!("warning: no initializationOptions provided, using defaults");
-                Config::default()
-            }
-        };
-
-        log!("{:?}", &config);
-
-        match &mut root_uri {
-            Some(url) => {
-                log!("workspace root: {}", url.as_ref().to_string());
-                // Ensure the path ends with a slash
-                if !url.path().ends_with('/') {
-                    url.path_segments_mut()
-                        .expect("Invalid path")
-                        .pop_if_empty()
-                        .push("");
-                }
+        let supports_client_commands = initialization_options
+            .as_ref()
+            .and_then(|x| x.get("supportsClientCommands"))
+            .and_then(serde_json::Value::as_bool)
+            .unwrap_or(false);
+        // Settings are kept only if they're valid, since changed settings are merged into them.
+        let settings = match initialization_options {
+            Some(serde_json::Value::Object(opts)) => valid_settings(opts),
+            Some(opts) => {
+                log_error!(
+                    "error: initializationOptions aren't an object, using defaults: {}",
+                    opts
+                );
+                Default::default()
             }
             None => {
-                log!("workspace root is not set, forcing `WorkspaceOptions::Single`.");
-                config.includes_and_workspaces = IncludesAndWorkspaces::Single;
+                log_warn!("no initializationOptions provided, using defaults");
+                Default::default()
             }
         };
+        let config: Config = serde_json::from_value(settings.clone().into()).unwrap_or_else(|e| {
+            log_error!(
+                "error: invalid initializationOptions, using defaults: {}",
+                e
+            );
+            Config::default()
+        });
+        logger::set_level(config.log_level);
+
+        log_info!("{:?}", &config);
 
-        let workspace_options = WorkspaceOptions::from_config(&config, root_uri);
-        log!("workspace_options: {:?}", &workspace_options);
+        let workspace_folders: Vec<Url> = match initialize_params.workspace_folders {
+            Some(folders) if !folders.is_empty() => folders.into_iter().map(|x| x.uri).collect(),
+            _ => root_uri.into_iter().collect::<Vec<_>>(),
+        }
+        .into_iter()
+        .map(with_trailing_slash)
+        .collect();
+        log_info!("workspace folders: {:?}", &workspace_folders);
+
+        if workspace_folders.is_empty() {
+            log!("workspace root is not set, forcing `WorkspaceOptions::Single`.");
+        }
 
-        let env_vars: Vec<_> = config.env_variables.into_iter().collect();
+        let env_vars: Vec<_> = config.env_variables.clone().into_iter().collect();
         log!("env variables: {:?}", &env_vars);
 
         Self {
             documents: BTreeMap::new(),
+            documents_read_from_disk: HashSet::default(),
             kanata: Kanata::new(config.def_local_keys_variant, env_vars),
             formatter: Formatter {
                 options: config.format,
                 remove_extra_empty_lines: false,
             },
-            workspace_options,
+            workspace_folders,
+            include_graph: OnceCell::new(),
             send_diagnostics_callback,
             dim_inactive_config_items: config.dim_inactive_config_items,
+            supports_client_commands,
+            config,
+            settings,
         }
 
         // self_.reload_diagnostics_debouncer =
@@ -546,6 +1044,9 @@ impl KanataLanguageServer {
                     TextDocumentSyncKind::FULL,
                 )),
                 definition_provider: Some(lsp_types::OneOf::Left(true)),
+                implementation_provider: Some(lsp_types::ImplementationProviderCapability::Simple(
+                    true,
+                )),
                 document_formatting_provider: Some(lsp_types::OneOf::Left(true)),
                 hover_provider: Some(lsp_types::HoverProviderCapability::Simple(true)),
                 rename_provider: Some(lsp_types::OneOf::Right(lsp_types::RenameOptions {
@@ -554,10 +1055,42 @@ impl KanataLanguageServer {
                         work_done_progress: Some(false),
                     },
                 })),
+                document_highlight_provider: Some(lsp_types::OneOf::Left(true)),
+                code_action_provider: Some(lsp_types::CodeActionProviderCapability::Options(
+                    lsp_types::CodeActionOptions {
+                        code_action_kinds: Some(vec![
+                            CodeActionKind::QUICKFIX,
+                            CodeActionKind::REFACTOR_EXTRACT,
+                            CodeActionKind::REFACTOR_INLINE,
+                            CodeActionKind::REFACTOR_REWRITE,
+                            code_actions::organize_definitions::SOURCE_SORT_DEFINITIONS_ALPHABETICALLY,
+                            code_actions::organize_definitions::SOURCE_SORT_DEFINITIONS_BY_FIRST_USE,
+                        ]),
+                        work_done_progress_options: Default::default(),
+                        resolve_provider: None,
+                    },
+                )),
+                linked_editing_range_provider: Some(
+                    lsp_types::LinkedEditingRangeServerCapabilities::Simple(true),
+                ),
+                call_hierarchy_provider: Some(lsp_types::CallHierarchyServerCapability::Simple(
+                    true,
+                )),
+                inlay_hint_provider: Some(lsp_types::OneOf::Left(true)),
+                signature_help_provider: Some(lsp_types::SignatureHelpOptions {
+                    trigger_characters: Some(vec!["(".to_string(), " ".to_string()]),
+                    retrigger_characters: None,
+                    work_done_progress_options: Default::default(),
+                }),
+                code_lens_provider: self.supports_client_commands.then_some(
+                    lsp_types::CodeLensOptions {
+                        resolve_provider: Some(false),
+                    },
+                ),
                 workspace: Some(lsp_types::WorkspaceServerCapabilities {
                     workspace_folders: Some(lsp_types::WorkspaceFoldersServerCapabilities {
-                        supported: Some(false),
-                        change_notifications: None,
+                        supported: Some(true),
+                        change_notifications: Some(lsp_types::OneOf::Left(true)),
                     }),
                     file_operations: Some(lsp_types::WorkspaceFileOperationsServerCapabilities {
                         did_delete: Some(lsp_types::FileOperationRegistrationOptions {
@@ -580,32 +1113,50 @@ impl KanataLanguageServer {
     }
 
     /// Catch-all handler for notifications sent by the LSP client.
-    pub fn on_notification(&mut self, method: &str, params: serde_json::Value) {
-        log!("notification: {}", method);
+    /// Returns an error if the params are invalid.
+    pub fn on_notification(
+        &mut self,
+        method: &str,
+        params: serde_json::Value,
+    ) -> Result<(), ResponseError> {
+        log_trace!("notification: {}", method);
 
         match method {
             // Nothing to do when we receive the `Initialized` notification.
             Initialized::METHOD => (),
             DidOpenTextDocument::METHOD => {
-                let DidOpenTextDocumentParams { text_document } =
-                    serde_json::from_value(params).unwrap();
+                let DidOpenTextDocumentParams { text_document } = params_from_value(params)?;
                 log!("opening: {}", text_document.uri);
                 if self.upsert_document(text_document).is_some() {
                     log!("reopened tracked doc");
                 }
+                self.load_untracked_files_from_disk();
                 let KlsParsedWorkspace { diagnostics, .. } = self.parse();
                 self.send_diagnostics(&diagnostics);
             }
             // We don't care when a document is closed -- we care about all Kanata files in a
-            // workspace folder regardless of which ones remain open.
-            DidCloseTextDocument::METHOD => (),
+            // workspace folder regardless of which ones remain open. Its content on disk
+            // is used from now on though, if it can be read.
+            DidCloseTextDocument::METHOD => {
+                let DidCloseTextDocumentParams { text_document } = params_from_value(params)?;
+                // Unsaved changes are discarded, so diagnostics have to reflect the file on disk.
+                if self.reload_document_from_disk(&text_document.uri) {
+                    let KlsParsedWorkspace { diagnostics, .. } = self.parse();
+                    self.send_diagnostics(&diagnostics);
+                }
+            }
             DidChangeTextDocument::METHOD => {
-                let params: DidChangeTextDocumentParams = serde_json::from_value(params).unwrap();
+                let params: DidChangeTextDocumentParams = params_from_value(params)?;
 
                 // Ensure we receive full -- not incremental -- updates.
-                assert_eq!(params.content_changes.len(), 1);
-                let change = params.content_changes.into_iter().next().unwrap();
-                assert!(change.range.is_none());
+                let change = match <[_; 1]>::try_from(params.content_changes) {
+                    Ok([change]) if change.range.is_none() => change,
+                    _ => {
+                        return Err(ResponseError::invalid_params(
+                            "expected a single change with full document content",
+                        ))
+                    }
+                };
 
                 let VersionedTextDocumentIdentifier { uri, version } = params.text_document;
 
@@ -620,19 +1171,12 @@ impl KanataLanguageServer {
             // This is the type of event we'll receive when a Kanata file is deleted, either via the
             // VS Code UI (right-click delete) or otherwise (e.g., `rm file.kbd` in a terminal).
             // The event comes from the `deleteWatcher` file watcher in the extension client.
+            //
+            // The native server also registers a watcher for created and changed files,
+            // to keep documents read from disk up to date.
             DidChangeWatchedFiles::METHOD => {
-                // todo: test this
-                let DidChangeWatchedFilesParams { changes } =
-                    serde_json::from_value(params).unwrap();
-                let uris: Vec<_> = changes
-                    .into_iter()
-                    .map(|FileEvent { uri, typ }| {
-                        assert_eq!(typ, FileChangeType::DELETED); // We only watch for `Deleted` events.
-                        uri
-                    })
-                    .collect();
-
-                self.on_did_change_watched_files(uris);
+                let DidChangeWatchedFilesParams { changes } = params_from_value(params)?;
+                self.on_did_change_watched_files(changes);
             }
 
             // This is the type of event we'll receive when *any* file or folder is deleted via the
@@ -655,8 +1199,7 @@ impl KanataLanguageServer {
             //
             // [0]: https://github.com/microsoft/vscode/issues/60813
             DidDeleteFiles::METHOD => {
-                // todo: test this
-                let DeleteFilesParams { files } = serde_json::from_value(params).unwrap();
+                let DeleteFilesParams { files } = params_from_value(params)?;
                 let mut deleted_uris: Vec<Url> = vec![];
                 for FileDelete { uri } in files {
                     match Url::parse(&uri) {
@@ -675,45 +1218,122 @@ impl KanataLanguageServer {
                 }
             }
 
+            // Clients that support `workspace/configuration` are asked for settings instead,
+            // see `main_native`. The VS Code client sends them in the notification.
+            DidChangeConfiguration::METHOD => {
+                let DidChangeConfigurationParams { settings } = params_from_value(params)?;
+                match settings.get(CONFIGURATION_SECTION) {
+                    Some(settings) => self.on_configuration_change(settings.clone()),
+                    None => log!("settings weren't sent in didChangeConfiguration"),
+                }
+            }
+
+            DidChangeWorkspaceFolders::METHOD => {
+                let DidChangeWorkspaceFoldersParams { event } = params_from_value(params)?;
+                let removed: Vec<Url> = event
+                    .removed
+                    .into_iter()
+                    .map(|folder| with_trailing_slash(folder.uri))
+                    .collect();
+                self.workspace_folders
+                    .retain(|folder| !removed.contains(folder));
+                for folder in event.added {
+                    let folder = with_trailing_slash(folder.uri);
+                    if !self.workspace_folders.contains(&folder) {
+                        self.workspace_folders.push(folder);
+                    }
+                }
+                log!("workspace folders: {:?}", &self.workspace_folders);
+                self.include_graph.take();
+                self.load_untracked_files_from_disk();
+                let KlsParsedWorkspace { diagnostics, .. } = self.parse();
+                self.send_diagnostics(&diagnostics);
+            }
+
             DidSaveTextDocument::METHOD => {
-                let _params: DidSaveTextDocumentParams = serde_json::from_value(params).unwrap();
+                let _params: DidSaveTextDocumentParams = params_from_value(params)?;
+                // Included files outside of the project root aren't watched,
+                // so saving is the cue to read them again.
+                self.load_untracked_files_from_disk();
                 let KlsParsedWorkspace { diagnostics, .. } = self.parse();
                 self.send_diagnostics(&diagnostics);
             }
 
+            SetTrace::METHOD => {
+                let SetTraceParams { value } = params_from_value(params)?;
+                logger::set_trace(value);
+            }
+
             _ => log!("received unsupported notification: {}", method),
         }
+        Ok(())
+    }
+
+    /// Applies new settings and republishes diagnostics. Settings are either sent
+    /// in `workspace/didChangeConfiguration`, or requested with `workspace/configuration`.
+    /// Settings that aren't given keep their previous values. Invalid settings are ignored.
+    pub fn on_configuration_change(&mut self, settings: serde_json::Value) {
+        let serde_json::Value::Object(mut settings) = settings else {
+            log_warn!("settings aren't an object, ignoring: {}", settings);
+            return;
+        };
+        // The setting is named differently in VS Code than in initialization options.
+        if let Some(env_variables) = settings.remove("environmentVariables") {
+            settings.insert("envVariables".to_string(), env_variables);
+        }
+        let mut merged_settings = self.settings.clone();
+        merged_settings.extend(valid_settings(settings));
+        let config: Config = match serde_json::from_value(merged_settings.clone().into()) {
+            Ok(config) => config,
+            Err(e) => {
+                log_warn!("invalid settings, keeping the previous ones: {}", e);
+                return;
+            }
+        };
+        logger::set_level(config.log_level);
+        log_info!("{:?}", &config);
+
+        let env_vars: Vec<_> = config.env_variables.clone().into_iter().collect();
+        self.kanata = Kanata::new(config.def_local_keys_variant, env_vars);
+        self.formatter = Formatter {
+            options: config.format,
+            remove_extra_empty_lines: false,
+        };
+        self.dim_inactive_config_items = config.dim_inactive_config_items;
+        // Workspaces are derived from the config, so they're rebuilt with it.
+        self.config = config;
+        self.include_graph.take();
+        self.settings = merged_settings;
+
+        self.load_untracked_files_from_disk();
+        let KlsParsedWorkspace { diagnostics, .. } = self.parse();
+        self.send_diagnostics(&diagnostics);
     }
 
-    /// Returns None on error.
     pub fn on_document_formatting(
         &mut self,
         params: &DocumentFormattingParams,
-    ) -> Option<Vec<TextEdit>> {
+    ) -> Result<Option<Vec<TextEdit>>, ResponseError> {
+        let workspace_options = self.workspace_options_for(&params.text_document.uri);
         if !self.formatter.options.enable {
             log!("Formatting request received, but formatting is disabled in vscode-kanata settings.");
-            return Some(vec![]);
+            return Ok(Some(vec![]));
         }
 
-        let text = &self
-            .documents
-            .get(&params.text_document.uri)
-            .expect("document should be cached")
-            .text;
-
-        let (mut tree, root_span) =
-            match formatter::ext_tree::parse_into_ext_tree_and_root_span(text) {
-                Ok(x) => x,
-                Err(_) => {
-                    log!("failed to parse current file into tree");
-                    return None;
-                }
-            };
+        let text = &self.tracked_document(&params.text_document.uri)?.text;
+
+        let (mut tree, root_span) = formatter::ext_tree::parse_into_ext_tree_and_root_span(text)
+            .map_err(|e| {
+                ResponseError::request_failed(format!(
+                    "failed to parse current file into tree: {}",
+                    e.msg
+                ))
+            })?;
 
         let range = lsp_range_from_span(&root_span.into());
 
         let defsrc_layout = formatter::defsrc_layout::get_defsrc_layout(
-            &self.workspace_options,
+            &workspace_options,
             &self.documents,
             params.options.tab_size,
             &params.text_document.uri,
@@ -736,30 +1356,32 @@ impl KanataLanguageServer {
             line_endings,
         );
 
-        Some(vec![TextEdit {
+        Ok(Some(vec![TextEdit {
             range,
             new_text: tree.to_string(),
-        }])
+        }]))
     }
 
     // FUTUREWORK: this should be a a proper handler for textDocument/references
     // instead of being a hacky fallback for textDocument/definition.
     //
-    /// Returns None on error.
     pub fn on_go_to_definition(
         &mut self,
         params: &GotoDefinitionParams,
-    ) -> Option<GotoDefinitionResponse> {
-        log!("========= on_go_to_definition ========");
+    ) -> Result<Option<GotoDefinitionResponse>, ResponseError> {
+        self.tracked_document(&params.text_document_position_params.text_document.uri)?;
+        let workspace_options =
+            self.workspace_options_for(&params.text_document_position_params.text_document.uri);
+        log_trace!("========= on_go_to_definition ========");
 
         let KlsParsedWorkspace {
             def_locs: definition_locations_per_doc,
             ref_locs: reference_locations_per_doc,
             ..
-        } = self.parse();
+        } = self.parse_for(&workspace_options);
 
         let source_doc_uri = &params.text_document_position_params.text_document.uri;
-        let match_all_defs = match self.workspace_options {
+        let match_all_defs = match workspace_options {
             WorkspaceOptions::Single { .. } => false,
             WorkspaceOptions::Workspace { .. } => true,
         };
@@ -772,90 +1394,115 @@ impl KanataLanguageServer {
         ) {
             Some(x) => x,
             None => {
-                return Some(GotoDefinitionResponse::Link(self.on_references_impl(
+                if let Some(references) = self.on_references_impl(
+                    &params.text_document_position_params.position,
+                    source_doc_uri,
+                )? {
+                    return Ok(Some(GotoDefinitionResponse::Link(references)));
+                }
+                // Not a symbol, but it could still be a deflayer slot.
+                return Ok(navigation::physical_keys::defsrc_key_for_slot_at_pos(
                     &params.text_document_position_params.position,
                     source_doc_uri,
-                    &definition_locations_per_doc,
-                    &reference_locations_per_doc,
-                )?))
+                    &self.documents,
+                    &workspace_options,
+                )
+                .map(|link| GotoDefinitionResponse::Link(vec![link])));
             }
         };
-        log!("matching definition found: {:#?}", definition_link);
-        let target_uri: Url = match &self.workspace_options {
+        log_trace!("matching definition found: {:#?}", definition_link);
+        let target_uri: Url = match &workspace_options {
             WorkspaceOptions::Single { .. } => source_doc_uri.clone(),
             WorkspaceOptions::Workspace { project_root, .. } => {
-                match path_to_url(Path::new(&definition_link.target_filename), project_root) {
-                    Ok(x) => x,
-                    Err(err) => {
-                        log!("goto definition failed: {}", err);
-                        return None;
-                    }
-                }
+                path_to_url(Path::new(&definition_link.target_filename), project_root).map_err(
+                    |e| ResponseError::request_failed(format!("goto definition failed: {e}")),
+                )?
             }
         };
-        Some(GotoDefinitionResponse::Link(vec![LocationLink {
+        Ok(Some(GotoDefinitionResponse::Link(vec![LocationLink {
             origin_selection_range: Some(definition_link.source_range),
             target_uri,
             target_range: definition_link.target_range,
             target_selection_range: definition_link.target_range,
-        }]))
+        }])))
     }
 
-    /// Returns None on error.
+    /// Returns references of the definition at `position`, from every main config file
+    /// that includes the document.
     pub fn on_references_impl(
         &mut self,
         position: &Position,
         source_doc_uri: &Url,
-        definition_locations_by_doc: &HashMap<Url, DefinitionLocations>,
-        reference_locations_by_doc: &HashMap<Url, ReferenceLocations>,
-    ) -> Option<Vec<LocationLink>> {
-        let match_all_refs = match self.workspace_options {
-            WorkspaceOptions::Single { .. } => false,
-            WorkspaceOptions::Workspace { .. } => true,
-        };
-        let references = navigation::references_for_definition_at_pos(
-            position,
-            source_doc_uri,
-            definition_locations_by_doc,
-            reference_locations_by_doc,
-            match_all_refs,
-        )?;
-        log!("matching reference(s) found: {:#?}", references);
-        references
-            .iter()
-            .try_fold(vec![], |mut acc, reference_link| {
-                let target_uri: Url = match &self.workspace_options {
-                    WorkspaceOptions::Single { .. } => source_doc_uri.clone(),
-                    WorkspaceOptions::Workspace { project_root, .. } => {
-                        match path_to_url(Path::new(&reference_link.target_filename), project_root)
-                        {
-                            Ok(x) => x,
-                            Err(err) => {
-                                log!("reference failed: {}", err);
-                                return None;
-                            }
-                        }
-                    }
-                };
-                acc.push(LocationLink {
+    ) -> Result<Option<Vec<LocationLink>>, ResponseError> {
+        let mut links = vec![];
+        for workspace_options in self.workspaces_including(source_doc_uri) {
+            let KlsParsedWorkspace {
+                def_locs, ref_locs, ..
+            } = self.parse_for(&workspace_options);
+            let match_all_refs = match workspace_options {
+                WorkspaceOptions::Single { .. } => false,
+                WorkspaceOptions::Workspace { .. } => true,
+            };
+            let Some(references) = navigation::references_for_definition_at_pos(
+                position,
+                source_doc_uri,
+                &def_locs,
+                &ref_locs,
+                match_all_refs,
+            ) else {
+                continue;
+            };
+            log_trace!("matching reference(s) found: {:#?}", references);
+            let path_to_url_fn = span_file_url_fn(workspace_options, source_doc_uri.clone());
+            for reference_link in references {
+                let target_uri = path_to_url_fn(&reference_link.target_filename)
+                    .map_err(|e| ResponseError::request_failed(format!("reference failed: {e}")))?;
+                links.push(LocationLink {
                     origin_selection_range: Some(reference_link.source_range),
                     target_uri,
                     target_range: reference_link.target_range,
                     target_selection_range: reference_link.target_range,
                 });
-                Some(acc)
-            })
-    }
+            }
+        }
+        let links = links
+            .into_iter()
+            .unique_by(|x| (x.target_uri.clone(), x.target_range))
+            .collect_vec();
+        if links.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(links))
+    }
+
+    pub fn on_go_to_implementation(
+        &mut self,
+        params: &lsp_types::request::GotoImplementationParams,
+    ) -> Result<Option<lsp_types::request::GotoImplementationResponse>, ResponseError> {
+        self.tracked_document(&params.text_document_position_params.text_document.uri)?;
+        let workspace_options =
+            self.workspace_options_for(&params.text_document_position_params.text_document.uri);
+        log_trace!("========= on_go_to_implementation ========");
+        let links = navigation::physical_keys::deflayer_slots_for_defsrc_key_at_pos(
+            &params.text_document_position_params.position,
+            &params.text_document_position_params.text_document.uri,
+            &self.documents,
+            &workspace_options,
+        );
+        Ok(links.map(lsp_types::request::GotoImplementationResponse::Link))
+    }
 
-    pub fn on_hover(&mut self, params: &HoverParams) -> Option<Hover> {
+    pub fn on_hover(&mut self, params: &HoverParams) -> Result<Option<Hover>, ResponseError> {
         let doc_uri = &params.text_document_position_params.text_document.uri;
-        let pos = params.text_document_position_params.position;
+        let src = &self.tracked_document(doc_uri)?.text;
+        Ok(self.hover(params, src))
+    }
 
-        let src = &self
-            .documents
-            .get(doc_uri)
-            .expect("document should be cached")
-            .text;
+    fn hover(&self, params: &HoverParams, src: &str) -> Option<Hover> {
+        let workspace_options =
+            self.workspace_options_for(&params.text_document_position_params.text_document.uri);
+        let doc_uri = &params.text_document_position_params.text_document.uri;
+        let pos = params.text_document_position_params.position;
 
         // TODO: cache tree?
         let (tree, _) = match formatter::ext_tree::parse_into_ext_tree_and_root_span(src) {
@@ -869,7 +1516,7 @@ impl KanataLanguageServer {
         // Get list of keys in defsrc.
 
         let defsrc_keys = formatter::defsrc_layout::get_defsrc_keys(
-            &self.workspace_options,
+            &workspace_options,
             &self.documents,
             doc_uri,
             &tree,
@@ -889,102 +1536,137 @@ impl KanataLanguageServer {
             }
         };
 
-        let path_to_node = match tree.path_to_node_by_lsp_position(pos) {
-            Ok(x) => x,
-            Err(err) => {
-                log!("hover: tree.path_to_node_by_lsp_position: {:?}", err);
+        // We only want to show hovers for defsrc keys and deflayer slots (for now).
+        let key = navigation::physical_keys::key_at_position(&tree, pos)?;
+        if key.block == KeyBlock::Deflayer && key.key_count != defsrc_keys.len() {
+            // Only give hover hints if deflayer has same number of items as defsrc.
+            log!("hover: skipping key hints: deflayer vs defsrc item count mismatch");
+            return None;
+        }
+        let key_index = key.key_index;
+
+        let defsrc_key: &str = match defsrc_keys.get(key_index) {
+            Some(x) => x,
+            None => {
+                log!("hover: defsrc key with such index not found: {}", key_index);
                 return None;
             }
         };
 
-        // Check if the top-level item is valid for showing hover hints
-        // (we only want to show hovers for deflayer keys (for now)).
-        match path_to_node.first() {
-            Some(toplevel_deflayer_index) => {
-                let node = tree.0.get(*toplevel_deflayer_index as usize);
-                let node = match node {
-                    Some(x) => x,
-                    None => {
-                        log!(
-                            "hover: toplevel item with index {} doesn't exist (bug?)",
-                            toplevel_deflayer_index
-                        );
-                        return None;
-                    }
-                };
-                match &node.expr {
-                    Expr::Atom(_) => return None,
-                    Expr::List(node_list) => {
-                        if let Some(&ParseTreeNode {
-                            expr: Expr::Atom(ref atom),
-                            ..
-                        }) = node_list.get(0)
-                        {
-                            if atom != "deflayer" {
-                                return None;
-                            }
-                        } else {
-                            return None;
-                        }
+        let deflayers = formatter::defsrc_layout::get_deflayers(
+            &workspace_options,
+            &self.documents,
+            doc_uri,
+            &tree,
+        )
+        .unwrap_or_else(|e| {
+            log!("hover: get_deflayers: {}", e);
+            vec![]
+        });
 
-                        // Only give hover hints if deflayer has same number of items as defsrc.
-                        if node_list.len() - 2 != defsrc_keys.len() {
-                            log!(
-                                "hover: skipping key hints: deflayer vs defsrc item count mismatch"
-                            );
-                            return None;
-                        }
-                    }
-                }
-            }
-            None => {
-                log!("hover: path_to_node is empty (this is a bug!)");
-                return None;
-            }
+        let mut text_to_display = format!("```kanata\n{defsrc_key} ;; on defsrc\n```\n");
+        let rows = deflayers
+            .iter()
+            // Layers with mismatched item count can't be mapped to defsrc keys.
+            .filter(|(_, slots)| slots.len() == defsrc_keys.len())
+            .map(|(layer_name, slots)| {
+                format!(
+                    "| {} | {} |\n",
+                    markdown_table_cell(layer_name),
+                    markdown_table_cell(&slots[key_index]),
+                )
+            })
+            .collect::<String>();
+        if !rows.is_empty() {
+            text_to_display.push_str("\n| layer | action |\n|---|---|\n");
+            text_to_display.push_str(&rows);
         }
 
-        let key_index_in_deflayer = match path_to_node.last() {
-            Some(x) => {
-                if *x < 2 {
-                    // hovering over "deflayer" keyword or layer name
-                    return None;
-                }
-                (x - 2) as usize
-            }
-            None => {
-                log!("hover: get_defsrc_keys: last node not found (???)");
-                return None;
+        Some(Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value: text_to_display,
+            }),
+            range: None,
+        })
+    }
+
+    pub fn on_signature_help(
+        &mut self,
+        params: &SignatureHelpParams,
+    ) -> Result<Option<SignatureHelp>, ResponseError> {
+        let doc_uri = &params.text_document_position_params.text_document.uri;
+        let src = &self.tracked_document(doc_uri)?.text;
+        let (tree, _) = match formatter::ext_tree::parse_into_ext_tree_and_root_span(src) {
+            Ok(x) => x,
+            Err(_) => {
+                log!("signature help: failed to parse current file into tree");
+                return Ok(None);
             }
         };
+        Ok(signature_help::signature_help(
+            &tree,
+            params.text_document_position_params.position,
+        ))
+    }
 
-        let text_to_display: String = match defsrc_keys.get(key_index_in_deflayer) {
-            Some(x) => x.clone(),
-            None => {
-                log!(
-                    "hover: defsrc key with such index not found: {}",
-                    key_index_in_deflayer
-                );
-                return None;
+    pub fn on_inlay_hint(
+        &mut self,
+        params: &InlayHintParams,
+    ) -> Result<Option<Vec<InlayHint>>, ResponseError> {
+        let workspace_options = self.workspace_options_for(&params.text_document.uri);
+        let doc_uri = &params.text_document.uri;
+        let src = &self.tracked_document(doc_uri)?.text;
+        let (tree, _) = match formatter::ext_tree::parse_into_ext_tree_and_root_span(src) {
+            Ok(x) => x,
+            Err(_) => {
+                log!("inlay hint: failed to parse current file into tree");
+                return Ok(None);
             }
         };
+        let defsrc_keys = formatter::defsrc_layout::get_defsrc_keys(
+            &workspace_options,
+            &self.documents,
+            doc_uri,
+            &tree,
+        )
+        .unwrap_or_else(|e| {
+            log!("inlay hint: get_defsrc_keys: {}", e);
+            None
+        });
+        let trees = formatter::defsrc_layout::config_trees(
+            &workspace_options,
+            &self.documents,
+            doc_uri,
+            &tree,
+        )
+        .unwrap_or_else(|e| {
+            log!("inlay hint: config_trees: {}", e);
+            vec![(doc_uri.clone(), tree.clone())]
+        });
+        let variables = inlay_hints::variable_values::defined_variables(
+            &trees.iter().map(|(_, tree)| tree).collect_vec(),
+        );
 
-        Some(Hover {
-            contents: HoverContents::Scalar(MarkedString::LanguageString(LanguageString {
-                language: "kanata".to_owned(),
-                value: format!("{text_to_display} ;; on defsrc"),
-            })),
-            range: None,
-        })
+        let mut hints = inlay_hints::inlay_hints(&tree, params.range, defsrc_keys.as_deref());
+        hints.extend(inlay_hints::variable_values::variable_value_hints(
+            &tree,
+            params.range,
+            &variables,
+        ));
+        Ok(Some(hints))
     }
 
     pub fn on_prepare_rename(
         &mut self,
         params: &TextDocumentPositionParams,
-    ) -> Option<PrepareRenameResponse> {
-        log!("========= on_prepare_rename ========");
+    ) -> Result<Option<PrepareRenameResponse>, ResponseError> {
+        self.tracked_document(&params.text_document.uri)?;
+        let workspace_options = self.workspace_options_for(&params.text_document.uri);
+        log_trace!("========= on_prepare_rename ========");
 
         let (reference_locations_per_doc, definition_locations_per_doc) = {
-            let mut parsed_workspace = self.parse();
+            let mut parsed_workspace = self.parse_for(&workspace_options);
             // TODO: support renaming included files
             for (_, ref_loc) in parsed_workspace.ref_locs.iter_mut() {
                 ref_loc.0.include.0.clear();
@@ -993,15 +1675,15 @@ impl KanataLanguageServer {
         };
 
         if let Some(found_definition) = definition_locations_per_doc
-            .get(&params.text_document.uri)?
-            .get_definition_at_position(&params.position)
+            .get(&params.text_document.uri)
+            .and_then(|x| x.get_definition_at_position(&params.position))
         {
-            return Some(PrepareRenameResponse::Range(found_definition.range));
+            return Ok(Some(PrepareRenameResponse::Range(found_definition.range)));
         }
 
         if let Some(found_reference) = reference_locations_per_doc
-            .get(&params.text_document.uri)?
-            .get_reference_at_position(&params.position)
+            .get(&params.text_document.uri)
+            .and_then(|x| x.get_reference_at_position(&params.position))
         {
             let range = if found_reference.ref_kind.has_prefix() {
                 let mut r = found_reference.range;
@@ -1010,52 +1692,32 @@ impl KanataLanguageServer {
             } else {
                 found_reference.range
             };
-            return Some(PrepareRenameResponse::Range(range));
+            return Ok(Some(PrepareRenameResponse::Range(range)));
         };
 
         log!("on_prepare_rename_impl: not found any renameable token at the given position");
-        None
+        Ok(None)
     }
 
-    /// Returns None on error.
-    pub fn on_rename(&mut self, params: &RenameParams) -> Option<WorkspaceEdit> {
-        log!("========= on_rename ========");
-
-        let (reference_locations_per_doc, definition_locations_per_doc) = {
-            let mut parsed_workspace = self.parse();
-            // TODO: support renaming included files
-            for (_, ref_loc) in parsed_workspace.ref_locs.iter_mut() {
-                ref_loc.0.include.0.clear();
-            }
-            (parsed_workspace.ref_locs, parsed_workspace.def_locs)
-        };
-
-        let source_doc_uri = &params.text_document_position.text_document.uri;
-        let match_all_defs = match self.workspace_options {
-            WorkspaceOptions::Single { .. } => false,
-            WorkspaceOptions::Workspace { .. } => true,
-        };
-        let path_to_url_fn = |path: &str| match &self.workspace_options {
-            WorkspaceOptions::Single { .. } => Ok(source_doc_uri.clone()),
-            WorkspaceOptions::Workspace { project_root, .. } => {
-                path_to_url(Path::new(&path), project_root)
-            }
-        };
-        let symbol_locations = navigation::all_locations_of_symbol_at_pos(
-            &params.text_document_position.position,
-            source_doc_uri,
-            &definition_locations_per_doc,
-            &reference_locations_per_doc,
-            match_all_defs,
-            &path_to_url_fn,
-        );
-        log!("symbol locations found: {:#?}", symbol_locations);
+    pub fn on_rename(
+        &mut self,
+        params: &RenameParams,
+    ) -> Result<Option<WorkspaceEdit>, ResponseError> {
+        log_trace!("========= on_rename ========");
+        self.tracked_document(&params.text_document_position.text_document.uri)?;
+        let symbol_locations = self
+            .symbol_locations_at(
+                &params.text_document_position.text_document.uri,
+                &params.text_document_position.position,
+            )
+            .map_err(|e| ResponseError::request_failed(format!("rename failed: {e}")))?;
+        log_trace!("symbol locations found: {:#?}", symbol_locations);
 
         let changes = symbol_locations
-            .iter()
-            .map(|x| {
+            .into_iter()
+            .map(|(url, x)| {
                 (
-                    path_to_url_fn(&x.filename).unwrap(),
+                    url,
                     TextEdit {
                         range: if x.location_info.ref_kind.has_prefix() && !x.is_definition {
                             let mut r = x.location_info.range;
@@ -1070,11 +1732,427 @@ impl KanataLanguageServer {
             })
             .into_group_map();
 
-        Some(WorkspaceEdit {
+        let edit = WorkspaceEdit {
             changes: Some(changes),
             document_changes: None,
             change_annotations: None,
-        })
+        };
+        if let Some(url) = self.read_only_file_in(&edit) {
+            return Err(ResponseError::request_failed(format!(
+                "can't rename, because {url} is read-only"
+            )));
+        }
+        Ok(Some(edit))
+    }
+
+    pub fn on_linked_editing_range(
+        &mut self,
+        params: &LinkedEditingRangeParams,
+    ) -> Result<Option<LinkedEditingRanges>, ResponseError> {
+        log_trace!("========= on_linked_editing_range ========");
+        let source_doc_uri = &params.text_document_position_params.text_document.uri;
+        self.tracked_document(source_doc_uri)?;
+        let symbol_locations = self
+            .symbol_locations_at(
+                source_doc_uri,
+                &params.text_document_position_params.position,
+            )
+            .map_err(|e| ResponseError::request_failed(format!("linked editing failed: {e}")))?;
+        // Linked ranges can't reach into other files, so editing only some of the
+        // occurrences would break the others. Renaming handles this case instead.
+        if symbol_locations
+            .iter()
+            .any(|(url, _)| url != source_doc_uri)
+        {
+            return Ok(None);
+        }
+
+        let ranges = symbol_locations
+            .iter()
+            .map(|(_, x)| {
+                if x.is_definition {
+                    x.location_info.range
+                } else {
+                    x.location_info
+                        .ref_kind
+                        .range_without_prefix(x.location_info.range)
+                }
+            })
+            .unique()
+            .collect_vec();
+        if ranges.is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some(LinkedEditingRanges {
+            ranges,
+            // Prevents the `@` and `$` prefixes from getting into the linked ranges.
+            word_pattern: Some(r#"[^\s()@$"]+"#.to_string()),
+        }))
+    }
+
+    /// Returns locations of the symbol at `position` along with URLs of their files,
+    /// from every main config file that includes the document. Paths of included
+    /// files aren't symbols here.
+    fn symbol_locations_at(
+        &self,
+        uri: &Url,
+        position: &Position,
+    ) -> anyhow::Result<Vec<(Url, navigation::LocationInfoWithFilename)>> {
+        let mut locations = vec![];
+        for workspace_options in self.workspaces_including(uri) {
+            let KlsParsedWorkspace {
+                def_locs,
+                mut ref_locs,
+                ..
+            } = self.parse_for(&workspace_options);
+            // TODO: support renaming included files
+            for ref_loc in ref_locs.values_mut() {
+                ref_loc.0.include.0.clear();
+            }
+            let match_all_defs = match workspace_options {
+                WorkspaceOptions::Single { .. } => false,
+                WorkspaceOptions::Workspace { .. } => true,
+            };
+            let path_to_url_fn = span_file_url_fn(workspace_options, uri.clone());
+            for location in navigation::all_locations_of_symbol_at_pos(
+                position,
+                uri,
+                &def_locs,
+                &ref_locs,
+                match_all_defs,
+                &path_to_url_fn,
+            )? {
+                locations.push((path_to_url_fn(&location.filename)?, location));
+            }
+        }
+        Ok(locations
+            .into_iter()
+            .unique_by(|(url, x)| (url.clone(), x.location_info.range))
+            .collect())
+    }
+
+    pub fn on_document_highlight(
+        &mut self,
+        params: &DocumentHighlightParams,
+    ) -> Result<Option<Vec<DocumentHighlight>>, ResponseError> {
+        self.tracked_document(&params.text_document_position_params.text_document.uri)?;
+        let workspace_options =
+            self.workspace_options_for(&params.text_document_position_params.text_document.uri);
+        let KlsParsedWorkspace {
+            def_locs, ref_locs, ..
+        } = self.parse_for(&workspace_options);
+        Ok(navigation::document_highlights_for_token_at_pos(
+            &params.text_document_position_params.position,
+            &params.text_document_position_params.text_document.uri,
+            &def_locs,
+            &ref_locs,
+        ))
+    }
+
+    pub fn on_code_action(
+        &mut self,
+        params: &CodeActionParams,
+    ) -> Result<Option<CodeActionResponse>, ResponseError> {
+        let workspace_options = self.workspace_options_for(&params.text_document.uri);
+        log_trace!("========= on_code_action ========");
+        let doc_uri = &params.text_document.uri;
+        let src = &self.tracked_document(doc_uri)?.text;
+        let (tree, root_span) = match formatter::ext_tree::parse_into_ext_tree_and_root_span(src) {
+            Ok(x) => x,
+            Err(_) => {
+                log!("code action: failed to parse current file into tree");
+                return Ok(None);
+            }
+        };
+        let mut actions = vec![];
+        // Parsed only if an action needs it, and only once for all of them.
+        // A document shared by several main config files is parsed as a part of each.
+        let parsed_workspaces = OnceCell::new();
+        let parsed = || {
+            parsed_workspaces.get_or_init(|| {
+                self.workspaces_including(doc_uri)
+                    .into_iter()
+                    .map(|ws| {
+                        let parsed = self.parse_for(&ws);
+                        (ws, parsed)
+                    })
+                    .collect_vec()
+            })
+        };
+
+        if code_actions::is_kind_requested(&params.context.only, &CodeActionKind::QUICKFIX) {
+            let defsrc_len = formatter::defsrc_layout::get_defsrc_keys(
+                &workspace_options,
+                &self.documents,
+                doc_uri,
+                &tree,
+            )
+            .unwrap_or_else(|e| {
+                log!("code action: get_defsrc_keys: {}", e);
+                None
+            })
+            .map(|keys| keys.len());
+            actions.extend(params.context.diagnostics.iter().flat_map(|diagnostic| {
+                code_actions::quick_fix::code_actions_for_diagnostic(
+                    diagnostic, doc_uri, &tree, defsrc_len,
+                )
+            }));
+        }
+
+        if code_actions::is_kind_requested(&params.context.only, &CodeActionKind::REFACTOR_EXTRACT)
+        {
+            let all_defs = || parsed().iter().flat_map(|(_, x)| x.def_locs.values());
+            let is_alias_defined =
+                |name: &str| all_defs().any(|defs| defs.0.alias.contains_key(name));
+            let is_template_defined =
+                |name: &str| all_defs().any(|defs| defs.0.template.contains_key(name));
+            let is_variable_defined =
+                |name: &str| all_defs().any(|defs| defs.0.variable.contains_key(name));
+            actions.extend(code_actions::extract_alias::extract_alias(
+                &tree,
+                doc_uri,
+                params.range.start,
+                &is_alias_defined,
+                self.supports_client_commands,
+            ));
+            actions.extend(code_actions::extract_template::extract_template(
+                &tree,
+                doc_uri,
+                params.range.start,
+                &is_template_defined,
+                &is_variable_defined,
+                self.supports_client_commands,
+            ));
+        }
+
+        if code_actions::is_kind_requested(&params.context.only, &CodeActionKind::REFACTOR_INLINE) {
+            let inline_actions = parsed().iter().filter_map(|(ws, parsed_workspace)| {
+                let search_all_docs = match ws {
+                    WorkspaceOptions::Single { .. } => false,
+                    WorkspaceOptions::Workspace { .. } => true,
+                };
+                code_actions::inline_alias::inline_alias(
+                    &params.range.start,
+                    doc_uri,
+                    &self.documents,
+                    &parsed_workspace.def_locs,
+                    &parsed_workspace.ref_locs,
+                    search_all_docs,
+                    &span_file_url_fn(ws.clone(), doc_uri.clone()),
+                )
+            });
+            actions.extend(code_actions::merge_actions(inline_actions));
+        }
+
+        if code_actions::is_kind_requested(&params.context.only, &CodeActionKind::REFACTOR_REWRITE)
+        {
+            let defsrc_keys = formatter::defsrc_layout::get_defsrc_keys(
+                &workspace_options,
+                &self.documents,
+                doc_uri,
+                &tree,
+            );
+            // Tab size only matters for `defsrc` indented with tabs, so assume the usual one,
+            // since code action requests don't come with formatting options.
+            let defsrc_layout = formatter::defsrc_layout::get_defsrc_layout(
+                &workspace_options,
+                &self.documents,
+                4,
+                doc_uri,
+                &tree,
+            )
+            .unwrap_or_else(|e| {
+                log!("code action: get_defsrc_layout: {}", e);
+                None
+            });
+            match defsrc_keys {
+                Ok(Some(defsrc_keys)) => {
+                    actions.extend(code_actions::convert_deflayer::convert_deflayer(
+                        &tree,
+                        doc_uri,
+                        params.range.start,
+                        &defsrc_keys,
+                        defsrc_layout.as_deref(),
+                    ));
+                }
+                Ok(None) => log!("code action: defsrc not found"),
+                Err(e) => log!("code action: get_defsrc_keys: {}", e),
+            }
+        }
+
+        let doc_range = lsp_range_from_span(&root_span.into());
+        for (kind, order) in [
+            (
+                code_actions::organize_definitions::SOURCE_SORT_DEFINITIONS_ALPHABETICALLY,
+                DefinitionsOrder::Alphabetical,
+            ),
+            (
+                code_actions::organize_definitions::SOURCE_SORT_DEFINITIONS_BY_FIRST_USE,
+                DefinitionsOrder::FirstUseInLayers,
+            ),
+        ] {
+            if code_actions::is_kind_requested(&params.context.only, &kind) {
+                actions.extend(code_actions::organize_definitions::organize_definitions(
+                    &tree, doc_uri, doc_range, order,
+                ));
+            }
+        }
+
+        actions.retain(|action| {
+            let read_only_file = action
+                .edit
+                .as_ref()
+                .and_then(|edit| self.read_only_file_in(edit));
+            if let Some(url) = read_only_file {
+                log!(
+                    "on_code_action: skipping '{}', {} is read-only",
+                    action.title,
+                    url
+                );
+            }
+            read_only_file.is_none()
+        });
+
+        Ok(Some(
+            actions
+                .into_iter()
+                .map(CodeActionOrCommand::CodeAction)
+                .collect(),
+        ))
+    }
+
+    pub fn on_code_lens(
+        &mut self,
+        params: &CodeLensParams,
+    ) -> Result<Option<Vec<CodeLens>>, ResponseError> {
+        let source_doc_uri = &params.text_document.uri;
+        self.tracked_document(source_doc_uri)?;
+        let workspaces = self
+            .workspaces_including(source_doc_uri)
+            .into_iter()
+            .map(|workspace_options| self.code_lens_locations(workspace_options, source_doc_uri))
+            .collect_vec();
+        Ok(Some(navigation::code_lens::code_lenses(
+            source_doc_uri,
+            &workspaces,
+        )))
+    }
+
+    fn code_lens_locations(
+        &self,
+        workspace_options: WorkspaceOptions,
+        source_doc_uri: &Url,
+    ) -> navigation::code_lens::WorkspaceLocations {
+        let KlsParsedWorkspace {
+            def_locs, ref_locs, ..
+        } = self.parse_for(&workspace_options);
+        let search_all_docs = match workspace_options {
+            WorkspaceOptions::Single { .. } => false,
+            WorkspaceOptions::Workspace { .. } => true,
+        };
+        let main_doc_uri = match &workspace_options {
+            WorkspaceOptions::Single { .. } => Ok(source_doc_uri.clone()),
+            WorkspaceOptions::Workspace {
+                main_config_file,
+                project_root,
+            } => path_to_url(main_config_file, project_root),
+        };
+
+        let project_root = match &workspace_options {
+            WorkspaceOptions::Single { .. } => None,
+            WorkspaceOptions::Workspace { project_root, .. } => Some(project_root),
+        };
+        let base_layer = main_doc_uri
+            .map_err(|e| log!("code lens: {}", e))
+            .ok()
+            .and_then(|uri| base_layer(uri, project_root, &def_locs, &ref_locs));
+        let layer_chains = match base_layer {
+            Some(base_layer) => navigation::call_hierarchy::layer_chains_from_base(
+                &base_layer,
+                &def_locs,
+                &ref_locs,
+                &self.documents,
+                search_all_docs,
+            ),
+            None => HashMap::new(),
+        };
+
+        navigation::code_lens::WorkspaceLocations {
+            definition_locations_by_doc: def_locs,
+            reference_locations_by_doc: ref_locs,
+            search_all_docs,
+            layer_chains,
+            path_to_url_fn: Box::new(span_file_url_fn(workspace_options, source_doc_uri.clone())),
+        }
+    }
+
+    pub fn on_prepare_call_hierarchy(
+        &mut self,
+        params: &CallHierarchyPrepareParams,
+    ) -> Result<Option<Vec<CallHierarchyItem>>, ResponseError> {
+        self.tracked_document(&params.text_document_position_params.text_document.uri)?;
+        let workspace_options =
+            self.workspace_options_for(&params.text_document_position_params.text_document.uri);
+        let KlsParsedWorkspace {
+            def_locs, ref_locs, ..
+        } = self.parse_for(&workspace_options);
+        let search_all_docs = match workspace_options {
+            WorkspaceOptions::Single { .. } => false,
+            WorkspaceOptions::Workspace { .. } => true,
+        };
+        Ok(navigation::call_hierarchy::prepare_call_hierarchy(
+            &params.text_document_position_params.position,
+            &params.text_document_position_params.text_document.uri,
+            &def_locs,
+            &ref_locs,
+            &self.documents,
+            search_all_docs,
+        ))
+    }
+
+    pub fn on_call_hierarchy_incoming_calls(
+        &mut self,
+        params: &CallHierarchyIncomingCallsParams,
+    ) -> Result<Option<Vec<CallHierarchyIncomingCall>>, ResponseError> {
+        self.tracked_document(&params.item.uri)?;
+        let workspace_options = self.workspace_options_for(&params.item.uri);
+        let KlsParsedWorkspace {
+            def_locs, ref_locs, ..
+        } = self.parse_for(&workspace_options);
+        let search_all_docs = match workspace_options {
+            WorkspaceOptions::Single { .. } => false,
+            WorkspaceOptions::Workspace { .. } => true,
+        };
+        Ok(Some(navigation::call_hierarchy::incoming_calls(
+            &params.item,
+            &def_locs,
+            &ref_locs,
+            &self.documents,
+            search_all_docs,
+        )))
+    }
+
+    pub fn on_call_hierarchy_outgoing_calls(
+        &mut self,
+        params: &CallHierarchyOutgoingCallsParams,
+    ) -> Result<Option<Vec<CallHierarchyOutgoingCall>>, ResponseError> {
+        self.tracked_document(&params.item.uri)?;
+        let workspace_options = self.workspace_options_for(&params.item.uri);
+        let KlsParsedWorkspace {
+            def_locs, ref_locs, ..
+        } = self.parse_for(&workspace_options);
+        let search_all_docs = match workspace_options {
+            WorkspaceOptions::Single { .. } => false,
+            WorkspaceOptions::Workspace { .. } => true,
+        };
+        Ok(Some(navigation::call_hierarchy::outgoing_calls(
+            &params.item,
+            &def_locs,
+            &ref_locs,
+            &self.documents,
+            search_all_docs,
+        )))
     }
 }
 
@@ -1087,20 +2165,40 @@ impl KanataLanguageServer {
     // and `DidChangeWatchedFiles` in `KanataLanguageServer::on_notification`.
     //
     // [0]: https://github.com/microsoft/vscode/issues/60813
-    fn on_did_change_watched_files(&mut self, uris: Vec<Url>) {
-        for uri in uris {
-            log!("deleting: {}", uri);
-
-            // If this returns `None`, `uri` was already removed from the local set of tracked
-            // documents. An easy way to encounter this is to right-click delete a Kanata file via
-            // the VS Code UI, which races the `DidDeleteFiles` and `DidChangeWatchedFiles` events.
-            if let Some(doc) = self.remove_document(&uri) {
-                let diagnostics = self.empty_diagnostics_for_a_single_document(&doc);
-                self.send_diagnostics(&diagnostics);
-            } else {
-                log!("cannot delete untracked doc");
+    fn on_did_change_watched_files(&mut self, changes: Vec<FileEvent>) {
+        let mut changed_on_disk = false;
+        for FileEvent { uri, typ } in changes {
+            match typ {
+                FileChangeType::DELETED => {
+                    log!("deleting: {}", uri);
+
+                    // If this returns `None`, `uri` was already removed from the local set of tracked
+                    // documents. An easy way to encounter this is to right-click delete a Kanata file via
+                    // the VS Code UI, which races the `DidDeleteFiles` and `DidChangeWatchedFiles` events.
+                    if let Some(doc) = self.remove_document(&uri) {
+                        let diagnostics = self.empty_diagnostics_for_a_single_document(&doc);
+                        self.send_diagnostics(&diagnostics);
+                        // Files including it are affected too.
+                        changed_on_disk = true;
+                    } else {
+                        log!("cannot delete untracked doc");
+                    }
+                }
+                // Changes of documents opened by the client are received via `DidChangeTextDocument`.
+                _ if self.documents.contains_key(&uri)
+                    && !self.documents_read_from_disk.contains(&uri) => {}
+                _ => {
+                    log!("file changed on disk: {}", uri);
+                    self.reload_document_from_disk(&uri);
+                    changed_on_disk = true;
+                }
             }
         }
+        if changed_on_disk {
+            self.load_untracked_files_from_disk();
+            let KlsParsedWorkspace { diagnostics, .. } = self.parse();
+            self.send_diagnostics(&diagnostics);
+        }
     }
 }
 
@@ -1110,18 +2208,219 @@ impl KanataLanguageServer {
         log!("sending diagnostics for {} files", diagnostics.len());
         for params in diagnostics.values() {
             if let Err(e) = (self.send_diagnostics_callback)(params) {
-                log!("send_diagnostics_callback error: {:?}", e);
+                log_error!("send_diagnostics_callback error: {:?}", e);
             }
         }
     }
 
     fn upsert_document(&mut self, doc: TextDocumentItem) -> Option<TextDocumentItem> {
+        self.documents_read_from_disk.remove(&doc.uri);
+        self.include_graph.take();
         self.documents.insert(doc.uri.clone(), doc)
     }
 
+    /// Each main config file in each workspace folder has its own options.
+    /// Main config files are detected from tracked documents if not set.
+    fn workspaces(&self) -> Vec<WorkspaceOptions> {
+        self.include_graph()
+            .workspaces
+            .iter()
+            .map(|(ws, _)| ws.clone())
+            .collect()
+    }
+
+    fn include_graph(&self) -> &IncludeGraph {
+        self.include_graph.get_or_init(|| {
+            IncludeGraph::new(&self.config, &self.workspace_folders, &self.documents)
+        })
+    }
+
+    fn is_detecting_main_config_files(&self) -> bool {
+        matches!(
+            self.config.includes_and_workspaces,
+            IncludesAndWorkspaces::Workspace
+        ) && self.config.main_config_files.is_empty()
+    }
+
+    /// Requests are expected only for documents the client has opened.
+    fn tracked_document(&self, uri: &Url) -> Result<&TextDocumentItem, ResponseError> {
+        self.documents
+            .get(uri)
+            .ok_or_else(|| ResponseError::request_failed(format!("document isn't tracked: {uri}")))
+    }
+
+    /// Returns every workspace whose main config file (transitively) includes the document,
+    /// or the one from `workspace_options_for` if there's none like that.
+    fn workspaces_including(&self, uri: &Url) -> Vec<WorkspaceOptions> {
+        let workspaces = self
+            .include_graph()
+            .workspaces
+            .iter()
+            .filter(|(_, files)| files.contains(uri))
+            .map(|(ws, _)| ws.clone())
+            .collect_vec();
+        if workspaces.is_empty() {
+            return vec![self.workspace_options_for(uri)];
+        }
+        workspaces
+    }
+
+    /// Returns the workspace a document belongs to: the first one whose main config
+    /// file (transitively) includes it, or else the first one whose project root
+    /// contains it.
+    fn workspace_options_for(&self, uri: &Url) -> WorkspaceOptions {
+        let workspaces = self.workspaces();
+        let in_project_root = |ws: &&WorkspaceOptions| match ws {
+            WorkspaceOptions::Single { root } => root
+                .as_ref()
+                .is_none_or(|root| is_in_project_root(uri, root)),
+            WorkspaceOptions::Workspace { project_root, .. } => {
+                is_in_project_root(uri, project_root)
+            }
+        };
+        let including_workspace = self
+            .include_graph()
+            .workspaces
+            .iter()
+            .find(|(_, files)| files.contains(uri))
+            .map(|(ws, _)| ws);
+        including_workspace
+            .or_else(|| workspaces.iter().find(in_project_root))
+            .or_else(|| workspaces.first())
+            .cloned()
+            .unwrap_or(WorkspaceOptions::Single { root: None })
+    }
+
+    /// Files outside of the workspace, included with an absolute path, are never edited.
+    fn is_read_only(&self, url: &Url) -> bool {
+        self.documents_read_from_disk.contains(url)
+            && !self.workspaces().iter().any(|ws| match ws {
+                WorkspaceOptions::Single { .. } => true,
+                WorkspaceOptions::Workspace { project_root, .. } => {
+                    is_in_project_root(url, project_root)
+                }
+            })
+    }
+
+    /// Returns a read-only file that `edit` changes, if any.
+    fn read_only_file_in<'a>(&self, edit: &'a WorkspaceEdit) -> Option<&'a Url> {
+        edit.changes
+            .iter()
+            .flat_map(|changes| changes.keys())
+            .find(|url| self.is_read_only(url))
+    }
+
     fn remove_document(&mut self, uri: &Url) -> Option<TextDocumentItem> {
+        self.documents_read_from_disk.remove(uri);
+        self.include_graph.take();
         self.documents.remove(uri)
     }
+
+    /// In workspace mode, reads the main config files and files they (transitively) include
+    /// from disk, if they aren't tracked yet. Unlike VS Code, other editors don't open all
+    /// kanata files in the workspace for us. Only files under the project root are read.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn load_untracked_files_from_disk(&mut self) {
+        for workspace_options in self.workspaces() {
+            self.load_untracked_files_of_workspace(&workspace_options);
+        }
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn load_untracked_files_of_workspace(&mut self, workspace_options: &WorkspaceOptions) {
+        let WorkspaceOptions::Workspace {
+            main_config_file,
+            project_root,
+        } = workspace_options
+        else {
+            return;
+        };
+        let Ok(main_config_file_url) = path_to_url(main_config_file, project_root) else {
+            return;
+        };
+
+        let mut visited: HashSet<Url> = HashSet::default();
+        // Files outside of the project root are read only if they're included with
+        // an absolute or `~` path. Those may change without us being notified,
+        // so they're read again every time.
+        let mut queue = vec![(main_config_file_url, false)];
+        while let Some((url, is_included_by_absolute_path)) = queue.pop() {
+            if !visited.insert(url.clone()) {
+                continue;
+            }
+            let is_outside_project_root = !is_in_project_root(&url, project_root);
+            if is_outside_project_root && !is_included_by_absolute_path {
+                continue;
+            }
+            if !self.documents.contains_key(&url)
+                || (is_outside_project_root && self.documents_read_from_disk.contains(&url))
+            {
+                match read_document_from_disk(&url) {
+                    Ok(doc) => {
+                        log!("read untracked file from disk: {}", url);
+                        self.documents.insert(url.clone(), doc);
+                        self.documents_read_from_disk.insert(url.clone());
+                        self.include_graph.take();
+                    }
+                    Err(e) => {
+                        log_warn!("failed to read untracked file {}: {}", url, e);
+                        continue;
+                    }
+                }
+            }
+            let Ok((tree, _)) =
+                formatter::ext_tree::parse_into_ext_tree_and_root_span(&self.documents[&url].text)
+            else {
+                continue;
+            };
+            for include in tree.includes().unwrap_or_default() {
+                if let Ok(include_url) = path_to_url(&include, project_root) {
+                    queue.push((include_url, helpers::expand_tilde(&include).is_absolute()));
+                }
+            }
+        }
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    fn load_untracked_files_from_disk(&mut self) {}
+
+    /// Replaces a document with its content on disk, if it's in the project root
+    /// or has been read from disk before, in workspace mode.
+    /// Documents that no longer exist are removed. Returns whether the document was changed.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn reload_document_from_disk(&mut self, url: &Url) -> bool {
+        let is_in_any_project_root = self.workspaces().iter().any(|ws| match ws {
+            WorkspaceOptions::Single { .. } => false,
+            WorkspaceOptions::Workspace { project_root, .. } => {
+                is_in_project_root(url, project_root)
+            }
+        });
+        // Any file in a workspace folder can turn out to be a main config file.
+        let is_in_any_project_root = is_in_any_project_root
+            || (self.is_detecting_main_config_files()
+                && (self.workspace_folders.iter()).any(|folder| is_in_project_root(url, folder)));
+        if !is_in_any_project_root && !self.documents_read_from_disk.contains(url) {
+            return false;
+        }
+        match read_document_from_disk(url) {
+            Ok(doc) => {
+                self.documents_read_from_disk.insert(url.clone());
+                let previous = self.documents.insert(url.clone(), doc.clone());
+                self.include_graph.take();
+                previous.is_none_or(|x| x.text != doc.text)
+            }
+            Err(e) => {
+                log_warn!("failed to reload {} from disk: {}", url, e);
+                self.documents_read_from_disk.contains(url) && self.remove_document(url).is_some()
+            }
+        }
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    fn reload_document_from_disk(&mut self, _url: &Url) -> bool {
+        false
+    }
+
     /// Remove tracked docs inside `dir`. Returns documents that were removed.
     fn remove_tracked_documents_in_dir(&mut self, dir: &Url) -> Vec<TextDocumentItem> {
         let (in_removed_dir, _not_in_removed_dir): (Documents, Documents) =
@@ -1143,12 +2442,16 @@ impl KanataLanguageServer {
             .collect()
     }
 
-    fn document_from_span(&self, span: &Span) -> anyhow::Result<Option<TextDocumentItem>> {
-        let url: Url = match &self.workspace_options {
+    fn document_from_span(
+        &self,
+        workspace_options: &WorkspaceOptions,
+        span: &Span,
+    ) -> anyhow::Result<Option<TextDocumentItem>> {
+        let url: Url = match workspace_options {
             WorkspaceOptions::Workspace {
                 project_root: root, ..
-            }
-            | WorkspaceOptions::Single { root: Some(root) } => {
+            } => path_to_url(Path::new(&span.file_name()), root)?,
+            WorkspaceOptions::Single { root: Some(root) } => {
                 let filename = span.file_name();
                 Url::join(root, &filename).map_err(|e| anyhow!(e.to_string()))?
             }
@@ -1177,76 +2480,36 @@ impl KanataLanguageServer {
 
     fn diagnostics_from_kanata_parse_error(
         &self,
+        workspace_options: &WorkspaceOptions,
         err: &CustomParseError,
     ) -> (Option<TextDocumentItem>, Vec<Diagnostic>) {
-        let (message, severity) = (err.msg.clone(), DiagnosticSeverity::ERROR);
-
-        let doc: Option<TextDocumentItem> =
-            self.document_from_span(&err.span).unwrap_or_else(|e| {
+        let doc: Option<TextDocumentItem> = self
+            .document_from_span(workspace_options, &err.span)
+            .unwrap_or_else(|e| {
                 log!(
                     "Error in `document_from_kanata_diagnostic_context`: {:?}",
                     e
                 );
                 None
             });
-
-        let is_extension_the_error_source = message.starts_with(EXTENSION_ERROR_PREFIX);
-
-        let mut diagnostics = vec![];
-
-        let range = lsp_range_from_span(&err.span);
-
-        diagnostics.push(Diagnostic {
-            range,
-            severity: Some(severity),
-            source: if is_extension_the_error_source {
-                Some("kanata-ls".to_string())
-            } else {
-                Some("kanata-parser".to_string())
-            },
-            message,
-            ..Default::default()
-        });
-
-        if !is_extension_the_error_source {
-            diagnostics.push(Diagnostic {
-                range,
-                severity: Some(DiagnosticSeverity::INFORMATION),
-                message: KANATA_PARSER_HELP.to_string(),
-                ..Default::default()
-            });
-        }
-
-        (doc, diagnostics)
+        (doc, diagnostics_from_parse_error(err))
     }
 
     fn diagnostics_from_inactive_code(
         &self,
+        workspace_options: &WorkspaceOptions,
         inactive: &InactiveCode,
     ) -> (Option<TextDocumentItem>, Vec<Diagnostic>) {
-        let doc: Option<TextDocumentItem> =
-            self.document_from_span(&inactive.span).unwrap_or_else(|e| {
+        let doc: Option<TextDocumentItem> = self
+            .document_from_span(workspace_options, &inactive.span)
+            .unwrap_or_else(|e| {
                 log!(
                     "`diagnostics_from_inactive_code`: document not found '{:?}'",
                     e
                 );
                 None
             });
-
-        let mut diagnostics = vec![];
-
-        let range = lsp_range_from_span(&inactive.span);
-
-        diagnostics.push(Diagnostic {
-            range,
-            severity: Some(DiagnosticSeverity::HINT),
-            source: Some("kanata-parser".to_string()),
-            message: inactive.reason.clone(),
-            tags: Some(vec![DiagnosticTag::UNNECESSARY]),
-            ..Default::default()
-        });
-
-        (doc, diagnostics)
+        (doc, vec![diagnostic_from_inactive_code(inactive)])
     }
 
     fn parse_workspace(&self, root: &Url, main_config_file: &Path) -> KlsParserOutput {
@@ -1259,7 +2522,7 @@ impl KanataLanguageServer {
         let main_cfg_filename: PathBuf = path::PathBuf::from_str(url_path_str)
             .expect("shoudn't error because it comes from Url");
         let main_cfg_text: &str = &doc.text;
-        let is_opened_in_workspace: bool = match &self.workspace_options {
+        let is_opened_in_workspace: bool = match self.workspace_options_for(&doc.uri) {
             WorkspaceOptions::Workspace { .. } => true,
             WorkspaceOptions::Single { root } => root.is_some(),
         };
@@ -1284,7 +2547,108 @@ impl KanataLanguageServer {
             .collect()
     }
 
+    /// Parses all workspaces (every main config file) and merges the results.
     fn parse(&self) -> KlsParsedWorkspace {
+        self.parse_workspaces(&self.workspaces())
+    }
+
+    /// Parses only the workspace a request is about.
+    fn parse_for(&self, workspace_options: &WorkspaceOptions) -> KlsParsedWorkspace {
+        self.parse_workspaces(std::slice::from_ref(workspace_options))
+    }
+
+    /// Definitions and references found first take precedence. Diagnostics in files
+    /// included by more than one main config file are reported once, with names
+    /// of the main config files they come from, if there's more than one.
+    fn parse_workspaces(&self, workspaces: &[WorkspaceOptions]) -> KlsParsedWorkspace {
+        let main_config_files_count = workspaces
+            .iter()
+            .filter(|ws| matches!(ws, WorkspaceOptions::Workspace { .. }))
+            .count();
+
+        let mut def_locs: HashMap<Url, DefinitionLocations> = Default::default();
+        let mut ref_locs: HashMap<Url, ReferenceLocations> = Default::default();
+        #[allow(clippy::type_complexity)]
+        let mut diagnostics_with_mains: BTreeMap<
+            Url,
+            (Option<i32>, Vec<(Diagnostic, Vec<String>)>),
+        > = Default::default();
+        let mut is_single_file_mode_parsed = false;
+
+        for workspace_options in workspaces {
+            let main_config_file = match workspace_options {
+                WorkspaceOptions::Single { .. } => {
+                    // All documents are parsed at once, regardless of the workspace folder.
+                    if is_single_file_mode_parsed {
+                        continue;
+                    }
+                    is_single_file_mode_parsed = true;
+                    None
+                }
+                WorkspaceOptions::Workspace {
+                    main_config_file,
+                    project_root,
+                } => path_to_url(main_config_file, project_root)
+                    .ok()
+                    .map(|url| (url, main_config_file.display().to_string())),
+            };
+
+            let parsed = self.parse_one(workspace_options);
+            for (url, locs) in parsed.def_locs {
+                def_locs.entry(url).or_insert(locs);
+            }
+            for (url, locs) in parsed.ref_locs {
+                ref_locs.entry(url).or_insert(locs);
+            }
+            for (url, params) in parsed.diagnostics {
+                let (_, diags) = diagnostics_with_mains
+                    .entry(url.clone())
+                    .or_insert_with(|| (params.version, vec![]));
+                let main_config_file_name = match &main_config_file {
+                    Some((main_url, name)) if main_url != &url => Some(name.clone()),
+                    _ => None,
+                };
+                for diag in params.diagnostics {
+                    // Hints where to ask for help have no source and aren't attributed.
+                    let main_config_file_name = main_config_file_name
+                        .clone()
+                        .filter(|_| diag.source.is_some());
+                    match diags.iter_mut().find(|(d, _)| d == &diag) {
+                        Some((_, mains)) => mains.extend(main_config_file_name),
+                        None => diags.push((diag, main_config_file_name.into_iter().collect())),
+                    }
+                }
+            }
+        }
+
+        let mut diagnostics = self.empty_diagnostics_for_all_documents();
+        for (url, (version, diags)) in diagnostics_with_mains {
+            let diags = diags
+                .into_iter()
+                .map(|(mut diag, mains)| {
+                    if main_config_files_count > 1 && !mains.is_empty() {
+                        diag.message
+                            .push_str(&format!("\n(in config: {})", mains.join(", ")));
+                    }
+                    diag
+                })
+                .collect();
+            diagnostics.insert(
+                url.clone(),
+                PublishDiagnosticsParams::new(url, diags, version),
+            );
+        }
+
+        KlsParsedWorkspace {
+            diagnostics,
+            def_locs,
+            ref_locs,
+        }
+    }
+
+    /// Parses a single workspace. Returned diagnostics don't include
+    /// empty diagnostics of documents without problems.
+    fn parse_one(&self, workspace_options: &WorkspaceOptions) -> KlsParsedWorkspace {
         let docs = self
             .documents
             .values()
@@ -1298,7 +2662,7 @@ impl KanataLanguageServer {
             Vec<InactiveCode>,
             HashMap<Url, DefinitionLocations>,
             HashMap<Url, ReferenceLocations>,
-        ) = match &self.workspace_options {
+        ) = match workspace_options {
             WorkspaceOptions::Single { .. } => {
                 let mut errs = vec![];
                 let mut inactives = vec![];
@@ -1356,11 +2720,11 @@ impl KanataLanguageServer {
 
         let new_error_diags = parse_errors
             .iter()
-            .map(|e| self.diagnostics_from_kanata_parse_error(e))
+            .map(|e| self.diagnostics_from_kanata_parse_error(workspace_options, e))
             .fold(Diagnostics::new(), |mut acc, (doc_or_not, diag)| {
                 match doc_or_not {
                     Some(doc) => {
-                        log!("added diagnostic for document: {}", doc.uri.as_str());
+                        log_trace!("added diagnostic for document: {}", doc.uri.as_str());
                         let url: &Url = &doc.uri;
 
                         let mut diags = acc.get(url).map(|x| x.to_owned()).unwrap_or(
@@ -1386,11 +2750,11 @@ impl KanataLanguageServer {
         // TODO: merge with code above
         let new_inactive_codes_diags = inactive_codes
             .iter()
-            .map(|span| self.diagnostics_from_inactive_code(span))
+            .map(|span| self.diagnostics_from_inactive_code(workspace_options, span))
             .fold(Diagnostics::new(), |mut acc, (doc_or_not, diag)| {
                 match doc_or_not {
                     Some(doc) => {
-                        log!("added diagnostic for document: {}", doc.uri.as_str());
+                        log_trace!("added diagnostic for document: {}", doc.uri.as_str());
                         let url: &Url = &doc.uri;
 
                         let mut diags = acc.get(url).map(|x| x.to_owned()).unwrap_or(
@@ -1413,8 +2777,7 @@ impl KanataLanguageServer {
                 acc
             });
 
-        let mut diagnostics = self.empty_diagnostics_for_all_documents();
-        diagnostics.extend(new_error_diags);
+        let mut diagnostics = new_error_diags;
         if self.dim_inactive_config_items {
             diagnostics.extend(new_inactive_codes_diags);
         }
@@ -1427,8 +2790,665 @@ impl KanataLanguageServer {
     }
 }
 
+#[cfg(not(target_arch = "wasm32"))]
+fn read_document_from_disk(url: &Url) -> anyhow::Result<TextDocumentItem> {
+    let path = url
+        .to_file_path()
+        .map_err(|_| anyhow!("not a file path: {}", url))?;
+    let text = std::fs::read_to_string(path)?;
+    Ok(TextDocumentItem::new(url.clone(), "kanata".into(), 0, text))
+}
+
+/// Returns the error diagnostic, followed by a hint where to look for help
+/// if the error comes from kanata-parser.
+fn diagnostics_from_parse_error(err: &CustomParseError) -> Vec<Diagnostic> {
+    let (message, severity) = (err.msg.clone(), DiagnosticSeverity::ERROR);
+
+    let is_extension_the_error_source = message.starts_with(EXTENSION_ERROR_PREFIX);
+
+    let mut diagnostics = vec![];
+
+    let range = lsp_range_from_span(&err.span);
+
+    let span_text = err
+        .span
+        .file_content
+        .get(err.span.start()..err.span.end())
+        .unwrap_or_default();
+    let quick_fix = if is_extension_the_error_source {
+        None
+    } else {
+        code_actions::quick_fix::quick_fix_for_parse_error(&message, span_text)
+    };
+
+    diagnostics.push(Diagnostic {
+        range,
+        severity: Some(severity),
+        source: if is_extension_the_error_source {
+            Some("kanata-ls".to_string())
+        } else {
+            Some("kanata-parser".to_string())
+        },
+        message,
+        data: quick_fix.map(|x| serde_json::to_value(x).expect("serializable")),
+        ..Default::default()
+    });
+
+    if !is_extension_the_error_source {
+        diagnostics.push(Diagnostic {
+            range,
+            severity: Some(DiagnosticSeverity::INFORMATION),
+            message: KANATA_PARSER_HELP.to_string(),
+            ..Default::default()
+        });
+    }
+
+    diagnostics
+}
+
+fn diagnostic_from_inactive_code(inactive: &InactiveCode) -> Diagnostic {
+    Diagnostic {
+        range: lsp_range_from_span(&inactive.span),
+        severity: Some(DiagnosticSeverity::HINT),
+        source: Some("kanata-parser".to_string()),
+        message: inactive.reason.clone(),
+        tags: Some(vec![DiagnosticTag::UNNECESSARY]),
+        ..Default::default()
+    }
+}
+
 struct KlsParsedWorkspace {
     diagnostics: Diagnostics,
     def_locs: HashMap<Url, DefinitionLocations>,
     ref_locs: HashMap<Url, ReferenceLocations>,
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use lsp_types::{CodeLensParams, Range, TextDocumentIdentifier};
+
+    pub(crate) fn url_of(name: &str) -> Url {
+        Url::parse("file:///cfg/").unwrap().join(name).unwrap()
+    }
+
+    fn server_in_folder(
+        folder: Url,
+        settings: serde_json::Value,
+        send_diagnostics_callback: PublishDiagnosticsClosure,
+    ) -> KanataLanguageServer {
+        let params = InitializeParams {
+            workspace_folders: Some(vec![lsp_types::WorkspaceFolder {
+                uri: folder,
+                name: "cfg".into(),
+            }]),
+            initialization_options: Some(settings),
+            ..Default::default()
+        };
+        KanataLanguageServer::new(params, send_diagnostics_callback)
+    }
+
+    pub(crate) fn server_with_documents(
+        settings: serde_json::Value,
+        files: &[(&str, &str)],
+    ) -> KanataLanguageServer {
+        let mut server =
+            server_in_folder(Url::parse("file:///cfg/").unwrap(), settings, &|_| Ok(()));
+        for (name, text) in files {
+            server.upsert_document(TextDocumentItem::new(
+                url_of(name),
+                "kanata".into(),
+                0,
+                text.to_string(),
+            ));
+        }
+        server
+    }
+
+    /// Parses `files` in workspace mode, with every file not included by another one
+    /// being a main config file.
+    pub(crate) fn parsed_workspace(
+        files: &[(&str, &str)],
+    ) -> (
+        HashMap<Url, DefinitionLocations>,
+        HashMap<Url, ReferenceLocations>,
+        Documents,
+    ) {
+        let server = server_with_documents(
+            serde_json::json!({ "includesAndWorkspaces": "workspace" }),
+            files,
+        );
+        let KlsParsedWorkspace {
+            def_locs, ref_locs, ..
+        } = server.parse();
+        (def_locs, ref_locs, server.documents)
+    }
+
+    #[test]
+    fn test_workspaces_including_file_shared_by_two_main_config_files() {
+        let server = server_with_documents(
+            serde_json::json!({ "includesAndWorkspaces": "workspace" }),
+            &[
+                ("main_a.kbd", "(include shared.kbd)\n(deflayer a @x)\n"),
+                ("main_b.kbd", "(include shared.kbd)\n(deflayer b @x)\n"),
+                ("shared.kbd", "(defalias x y)\n"),
+            ],
+        );
+        let main_config_files_of = |name: &str| {
+            server
+                .workspaces_including(&url_of(name))
+                .into_iter()
+                .map(|ws| match ws {
+                    WorkspaceOptions::Workspace {
+                        main_config_file, ..
+                    } => main_config_file.display().to_string(),
+                    WorkspaceOptions::Single { .. } => panic!("unexpected single file mode"),
+                })
+                .sorted()
+                .collect_vec()
+        };
+
+        assert_eq!(
+            main_config_files_of("shared.kbd"),
+            vec!["main_a.kbd", "main_b.kbd"]
+        );
+        assert_eq!(main_config_files_of("main_a.kbd"), vec!["main_a.kbd"]);
+        assert_eq!(main_config_files_of("main_b.kbd"), vec!["main_b.kbd"]);
+    }
+
+    fn root_config_file_names(files: &[(&str, &str)]) -> Vec<String> {
+        let folder = Url::parse("file:///cfg/").unwrap();
+        let documents: Documents = files
+            .iter()
+            .map(|(name, text)| {
+                let uri = folder.join(name).unwrap();
+                let doc = TextDocumentItem::new(uri.clone(), "kanata".into(), 0, text.to_string());
+                (uri, doc)
+            })
+            .collect();
+        root_config_files(&includes_by_file(&documents), &folder)
+            .into_iter()
+            .map(|url| url.as_str()[folder.as_str().len()..].to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_root_config_files() {
+        assert_eq!(
+            root_config_file_names(&[
+                ("main_a.kbd", "(include shared.kbd)"),
+                ("main_b.kbd", "(include shared.kbd)"),
+                ("shared.kbd", "(defalias x y)"),
+                ("notes.txt", ""),
+            ]),
+            vec!["main_a.kbd", "main_b.kbd"]
+        );
+        // A file including itself is still a main config file.
+        assert_eq!(
+            root_config_file_names(&[("main.kbd", "(include main.kbd)")]),
+            vec!["main.kbd"]
+        );
+    }
+
+    #[test]
+    fn test_root_config_files_resolves_includes_relative_to_main_config_file() {
+        // Kanata resolves `sub/b.kbd` relative to the directory of `main.kbd`,
+        // even though it's included by `sub/a.kbd`.
+        assert_eq!(
+            root_config_file_names(&[
+                ("main.kbd", "(include sub/a.kbd)"),
+                ("sub/a.kbd", "(include sub/b.kbd)"),
+                ("sub/b.kbd", "(defalias x y)"),
+            ]),
+            vec!["main.kbd"]
+        );
+    }
+
+    #[test]
+    fn test_include_graph_is_reset_when_documents_change() {
+        let mut server = server_with_documents(
+            serde_json::json!({ "includesAndWorkspaces": "workspace" }),
+            &[("main.kbd", ""), ("other.kbd", "")],
+        );
+        assert_eq!(server.workspaces().len(), 2);
+        server.upsert_document(TextDocumentItem::new(
+            Url::parse("file:///cfg/main.kbd").unwrap(),
+            "kanata".into(),
+            1,
+            "(include other.kbd)".into(),
+        ));
+        assert_eq!(server.workspaces().len(), 1);
+    }
+
+    fn linked_editing_ranges_at(
+        server: &mut KanataLanguageServer,
+        file_name: &str,
+        position: Position,
+    ) -> Option<Vec<Range>> {
+        let params = LinkedEditingRangeParams {
+            text_document_position_params: TextDocumentPositionParams {
+                text_document: TextDocumentIdentifier::new(url_of(file_name)),
+                position,
+            },
+            work_done_progress_params: Default::default(),
+        };
+        server.on_linked_editing_range(&params).unwrap().map(|x| {
+            x.ranges
+                .into_iter()
+                .sorted_by_key(|r| (r.start.line, r.start.character))
+                .collect()
+        })
+    }
+
+    #[test]
+    fn test_linked_editing_range() {
+        let mut server = server_with_documents(
+            serde_json::json!({ "includesAndWorkspaces": "workspace" }),
+            &[
+                (
+                    "main.kbd",
+                    "(include shared.kbd)\n(defalias x a y b)\n(deflayer base @x @y)\n",
+                ),
+                ("shared.kbd", "(defsrc a b)\n(defalias z @y)\n"),
+            ],
+        );
+        let line_range =
+            |line, start, end| Range::new(Position::new(line, start), Position::new(line, end));
+
+        // `x` is used only in `main.kbd`; the `@` prefix isn't linked.
+        assert_eq!(
+            linked_editing_ranges_at(&mut server, "main.kbd", Position::new(1, 10)),
+            Some(vec![line_range(1, 10, 11), line_range(2, 16, 17)])
+        );
+        // `y` is also used in `shared.kbd`.
+        assert_eq!(
+            linked_editing_ranges_at(&mut server, "main.kbd", Position::new(1, 14)),
+            None
+        );
+        assert_eq!(
+            linked_editing_ranges_at(&mut server, "shared.kbd", Position::new(1, 12)),
+            None
+        );
+    }
+
+    /// Titles of code lenses in `file_name`, with lines they're on.
+    fn code_lens_titles(server: &mut KanataLanguageServer, file_name: &str) -> Vec<(u32, String)> {
+        let params = CodeLensParams {
+            text_document: TextDocumentIdentifier::new(url_of(file_name)),
+            work_done_progress_params: Default::default(),
+            partial_result_params: Default::default(),
+        };
+        server
+            .on_code_lens(&params)
+            .unwrap()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|x| (x.range.start.line, x.command.unwrap().title))
+            .collect()
+    }
+
+    #[test]
+    fn test_code_lenses_count_references_from_included_files() {
+        let mut server = server_with_documents(
+            serde_json::json!({
+                "includesAndWorkspaces": "workspace",
+                "supportsClientCommands": true,
+            }),
+            &[
+                (
+                    "main.kbd",
+                    "(include other.kbd)\n(defalias\n  a b\n)\n(deflayer base @a)\n",
+                ),
+                ("other.kbd", "(deflayer other @a)\n"),
+            ],
+        );
+        assert_eq!(
+            code_lens_titles(&mut server, "main.kbd"),
+            vec![
+                (2, "2 references".to_string()),
+                (4, "0 references".to_string()),
+                // `other` is parsed first, in place of the `include`.
+                (4, "not reachable from base layer".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_code_lenses_of_file_shared_by_two_main_config_files() {
+        let mut server = server_with_documents(
+            serde_json::json!({
+                "includesAndWorkspaces": "workspace",
+                "supportsClientCommands": true,
+            }),
+            &[
+                ("main_a.kbd", "(include shared.kbd)\n(deflayer base @a)\n"),
+                ("main_b.kbd", "(include shared.kbd)\n(deflayer base @a)\n"),
+                ("shared.kbd", "(defalias\n  a b\n)\n"),
+            ],
+        );
+        // Each main config file is parsed separately, so each sees only its own reference.
+        assert_eq!(
+            code_lens_titles(&mut server, "shared.kbd"),
+            vec![(1, "2 references".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_base_layer_follows_include_order() {
+        let mut server = server_with_documents(
+            serde_json::json!({
+                "includesAndWorkspaces": "workspace",
+                "supportsClientCommands": true,
+            }),
+            &[
+                (
+                    "main.kbd",
+                    "(defsrc a)\n(include layers.kbd)\n(deflayer late (layer-switch nav))\n",
+                ),
+                (
+                    "layers.kbd",
+                    "(deflayer first (layer-switch nav))\n(deflayer nav a)\n",
+                ),
+            ],
+        );
+        let layer_lenses = |server: &mut KanataLanguageServer, file_name| {
+            code_lens_titles(server, file_name)
+                .into_iter()
+                .filter(|(_, title)| {
+                    !title.ends_with("references") && !title.ends_with("reference")
+                })
+                .collect_vec()
+        };
+        // `late` is at a lower offset than `first`, but in a different file,
+        // which is parsed in place of the `include` before it.
+        assert_eq!(
+            layer_lenses(&mut server, "layers.kbd"),
+            vec![
+                (0, "base layer".to_string()),
+                (1, "reachable from: first → nav".to_string()),
+            ]
+        );
+        assert_eq!(
+            layer_lenses(&mut server, "main.kbd"),
+            vec![(2, "not reachable from base layer".to_string())]
+        );
+    }
+
+    /// Applies the action inlining all uses of the alias at `position`,
+    /// returning texts of changed documents by their file names.
+    fn inline_alias_at(
+        server: &mut KanataLanguageServer,
+        file_name: &str,
+        position: Position,
+    ) -> Vec<(String, String)> {
+        let params = CodeActionParams {
+            text_document: TextDocumentIdentifier::new(url_of(file_name)),
+            range: Range::new(position, position),
+            context: lsp_types::CodeActionContext {
+                diagnostics: vec![],
+                only: Some(vec![CodeActionKind::REFACTOR_INLINE]),
+                trigger_kind: None,
+            },
+            work_done_progress_params: Default::default(),
+            partial_result_params: Default::default(),
+        };
+        let actions = server.on_code_action(&params).unwrap().unwrap_or_default();
+        let action = actions
+            .into_iter()
+            .find_map(|x| match x {
+                CodeActionOrCommand::CodeAction(x) if x.title.starts_with("Inline all") => Some(x),
+                _ => None,
+            })
+            .expect("inline action");
+        action
+            .edit
+            .unwrap()
+            .changes
+            .unwrap()
+            .into_iter()
+            .map(|(url, edits)| {
+                let text = &server.documents[&url].text;
+                let file_name = url.as_str().trim_start_matches("file:///cfg/").to_string();
+                (file_name, code_actions::apply_edits(text, &edits))
+            })
+            .sorted()
+            .collect()
+    }
+
+    #[test]
+    fn test_inline_alias_used_in_included_file() {
+        let mut server = server_with_documents(
+            serde_json::json!({ "includesAndWorkspaces": "workspace" }),
+            &[
+                (
+                    "main.kbd",
+                    "(include other.kbd)\n(defalias\n  a b\n)\n(deflayer base @a)\n",
+                ),
+                ("other.kbd", "(deflayer other @a)\n"),
+            ],
+        );
+        assert_eq!(
+            inline_alias_at(&mut server, "main.kbd", Position::new(2, 2)),
+            vec![
+                (
+                    "main.kbd".to_string(),
+                    "(include other.kbd)\n(defalias\n)\n(deflayer base b)\n".to_string()
+                ),
+                ("other.kbd".to_string(), "(deflayer other b)\n".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_inline_alias_of_file_shared_by_two_main_config_files() {
+        let main_text = "(include shared.kbd)\n(deflayer base @a)\n";
+        let mut server = server_with_documents(
+            serde_json::json!({ "includesAndWorkspaces": "workspace" }),
+            &[
+                ("main_a.kbd", main_text),
+                ("main_b.kbd", main_text),
+                ("shared.kbd", "(defalias\n  a b\n)\n"),
+            ],
+        );
+        // Actions from both main config files are merged into one.
+        let inlined = "(include shared.kbd)\n(deflayer base b)\n".to_string();
+        assert_eq!(
+            inline_alias_at(&mut server, "shared.kbd", Position::new(1, 2)),
+            vec![
+                ("main_a.kbd".to_string(), inlined.clone()),
+                ("main_b.kbd".to_string(), inlined),
+                ("shared.kbd".to_string(), "(defalias\n)\n".to_string()),
+            ]
+        );
+    }
+
+    /// Creates an empty directory for files of a test.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("kanata-ls-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Returns a callback that records sent diagnostics, and messages of the ones
+    /// last sent for each document.
+    fn recorded_diagnostics() -> (
+        PublishDiagnosticsClosure,
+        impl Fn() -> BTreeMap<Url, Vec<String>>,
+    ) {
+        let sent: &'static std::sync::Mutex<BTreeMap<Url, Vec<String>>> = Box::leak(Box::default());
+        let callback: PublishDiagnosticsClosure =
+            Box::leak(Box::new(|params: &PublishDiagnosticsParams| {
+                let messages = params.diagnostics.iter().map(|x| x.message.clone());
+                sent.lock()
+                    .unwrap()
+                    .insert(params.uri.clone(), messages.collect());
+                Ok(())
+            }));
+        (callback, || sent.lock().unwrap().clone())
+    }
+
+    #[test]
+    fn test_included_files_are_read_from_disk_and_follow_changes() {
+        let dir = temp_dir("watched-files");
+        let main_text = "(include inc.kbd)\n(defsrc a)\n(deflayer base @x)\n";
+        std::fs::write(dir.join("main.kbd"), main_text).unwrap();
+        std::fs::write(dir.join("inc.kbd"), "(defalias x b)\n").unwrap();
+        let main_url = Url::from_file_path(dir.join("main.kbd")).unwrap();
+        let inc_url = Url::from_file_path(dir.join("inc.kbd")).unwrap();
+
+        let (callback, diagnostics) = recorded_diagnostics();
+        let mut server = server_in_folder(
+            Url::from_directory_path(&dir).unwrap(),
+            serde_json::json!({ "includesAndWorkspaces": "workspace" }),
+            callback,
+        );
+        // Only the main config file is opened by the client.
+        let open = DidOpenTextDocumentParams {
+            text_document: TextDocumentItem::new(
+                main_url.clone(),
+                "kanata".into(),
+                1,
+                main_text.into(),
+            ),
+        };
+        server
+            .on_notification(
+                DidOpenTextDocument::METHOD,
+                serde_json::to_value(open).unwrap(),
+            )
+            .unwrap();
+        assert!(server.documents_read_from_disk.contains(&inc_url));
+        assert!(diagnostics()[&main_url].is_empty());
+        assert!(diagnostics()[&inc_url].is_empty());
+
+        let notify_watched_file = |server: &mut KanataLanguageServer, typ: FileChangeType| {
+            let params = DidChangeWatchedFilesParams {
+                changes: vec![FileEvent::new(inc_url.clone(), typ)],
+            };
+            server
+                .on_notification(
+                    DidChangeWatchedFiles::METHOD,
+                    serde_json::to_value(params).unwrap(),
+                )
+                .unwrap();
+        };
+
+        std::fs::write(dir.join("inc.kbd"), "(defalias x b))\n").unwrap();
+        notify_watched_file(&mut server, FileChangeType::CHANGED);
+        assert_eq!(server.documents[&inc_url].text, "(defalias x b))\n");
+        assert!(!diagnostics()[&inc_url].is_empty());
+
+        std::fs::remove_file(dir.join("inc.kbd")).unwrap();
+        notify_watched_file(&mut server, FileChangeType::DELETED);
+        assert!(!server.documents.contains_key(&inc_url));
+        assert!(!server.documents_read_from_disk.contains(&inc_url));
+        assert!(diagnostics()[&inc_url].is_empty());
+        // The main config file can't include it anymore.
+        assert!(!diagnostics()[&main_url].is_empty());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_deleted_directory_removes_documents_in_it() {
+        let mut server = server_with_documents(
+            serde_json::json!({ "includesAndWorkspaces": "workspace" }),
+            &[
+                ("main.kbd", "(defsrc a)\n"),
+                ("sub/a.kbd", "(defsrc a)\n"),
+                ("sub/b.kbd", "(defsrc a)\n"),
+            ],
+        );
+        let params = DeleteFilesParams {
+            files: vec![FileDelete {
+                uri: "file:///cfg/sub".to_string(),
+            }],
+        };
+        server
+            .on_notification(
+                DidDeleteFiles::METHOD,
+                serde_json::to_value(params).unwrap(),
+            )
+            .unwrap();
+        assert_eq!(
+            server.documents.keys().collect_vec(),
+            vec![&url_of("main.kbd")]
+        );
+    }
+
+    #[test]
+    fn test_invalid_settings_are_ignored() {
+        let mut server = server_with_documents(
+            serde_json::json!({ "includesAndWorkspaces": "workspace", "logLevel": "loud" }),
+            &[],
+        );
+        // Valid settings are applied, regardless of the invalid `logLevel`.
+        assert!(matches!(
+            server.config.includes_and_workspaces,
+            IncludesAndWorkspaces::Workspace
+        ));
+        // Otherwise the invalid `logLevel` would make every later change invalid too.
+        assert!(!server.settings.contains_key("logLevel"));
+        server.on_configuration_change(
+            serde_json::json!({ "dimInactiveConfigItems": false, "mainConfigFile": 1 }),
+        );
+        assert!(!server.dim_inactive_config_items);
+        assert!(matches!(
+            server.config.includes_and_workspaces,
+            IncludesAndWorkspaces::Workspace
+        ));
+        assert!(server.config.main_config_files.is_empty());
+    }
+
+    #[test]
+    fn test_configuration_change_rebuilds_state() {
+        let (callback, diagnostics) = recorded_diagnostics();
+        let mut server = server_in_folder(
+            Url::parse("file:///cfg/").unwrap(),
+            serde_json::json!({
+                "includesAndWorkspaces": "workspace",
+                "mainConfigFile": "main.kbd",
+                "localKeysVariant": "deflocalkeys-win",
+            }),
+            callback,
+        );
+        for (name, text) in [
+            ("main.kbd", "(defsrc a)\n(deflayer base a)\n"),
+            ("other.kbd", "(defsrc a))\n"),
+        ] {
+            server.upsert_document(TextDocumentItem::new(
+                url_of(name),
+                "kanata".into(),
+                0,
+                text.to_string(),
+            ));
+        }
+        assert_eq!(server.workspaces().len(), 1);
+        assert!(!server.formatter.options.enable);
+
+        server.on_configuration_change(serde_json::json!({
+            "mainConfigFile": "",
+            "localKeysVariant": "deflocalkeys-linux",
+            "format": { "enable": true, "useDefsrcLayoutOnDeflayers": true },
+            "environmentVariables": { "VAR": "value" },
+        }));
+        assert_eq!(
+            server.kanata.def_local_keys_variant_to_apply,
+            "deflocalkeys-linux"
+        );
+        assert!(server.formatter.options.enable);
+        assert!(server.formatter.options.use_defsrc_layout_on_deflayers);
+        // Renamed to the name used in initialization options.
+        assert!(!server.settings.contains_key("environmentVariables"));
+        assert_eq!(server.settings["envVariables"]["VAR"], "value");
+        assert_eq!(server.kanata.env_vars, vec![("VAR".into(), "value".into())]);
+        // Main config files are detected again, so `other.kbd` is checked now too.
+        assert_eq!(server.workspaces().len(), 2);
+        let diagnostics = diagnostics();
+        assert_eq!(diagnostics[&url_of("main.kbd")], Vec::<String>::new());
+        assert!(diagnostics[&url_of("other.kbd")]
+            .iter()
+            .any(|x| x.contains("unexpected )")));
+    }
+}
@@ -0,0 +1,113 @@
+//! Levelled logging of the server, used through the `log!` family of macros.
+//!
+//! Messages are written to stderr (`console.log` in wasm), unless a sink is set,
+//! like the native server does to forward them to the client.
+
+use std::sync::{
+    atomic::{AtomicU8, Ordering},
+    OnceLock,
+};
+
+use lsp_types::TraceValue;
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl LogLevel {
+    fn from_u8(level: u8) -> Option<Self> {
+        Some(match level {
+            1 => LogLevel::Error,
+            2 => LogLevel::Warn,
+            3 => LogLevel::Info,
+            4 => LogLevel::Debug,
+            5 => LogLevel::Trace,
+            _ => return None,
+        })
+    }
+}
+
+pub type Sink = Box<dyn Fn(LogLevel, &str) + Send + Sync>;
+
+static LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+/// Level implied by `$/setTrace`, 0 if tracing is off.
+static TRACE_LEVEL: AtomicU8 = AtomicU8::new(0);
+static SINK: OnceLock<Sink> = OnceLock::new();
+
+pub fn set_level(level: LogLevel) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// `messages` enables debug messages and `verbose` all of them,
+/// regardless of the configured level. `off` restores it.
+pub fn set_trace(value: TraceValue) {
+    let level = match value {
+        TraceValue::Off => 0,
+        TraceValue::Messages => LogLevel::Debug as u8,
+        TraceValue::Verbose => LogLevel::Trace as u8,
+    };
+    TRACE_LEVEL.store(level, Ordering::Relaxed);
+}
+
+pub fn is_tracing() -> bool {
+    TRACE_LEVEL.load(Ordering::Relaxed) != 0
+}
+
+/// Can be set only once. Returns false if a sink is already set.
+pub fn set_sink(sink: Sink) -> bool {
+    SINK.set(sink).is_ok()
+}
+
+pub fn enabled(level: LogLevel) -> bool {
+    let max_level = LEVEL
+        .load(Ordering::Relaxed)
+        .max(TRACE_LEVEL.load(Ordering::Relaxed));
+    LogLevel::from_u8(max_level).is_some_and(|max_level| level <= max_level)
+}
+
+/// Used by the `log!` macros. Formatting is skipped for disabled levels.
+pub fn log(level: LogLevel, args: std::fmt::Arguments) {
+    if !enabled(level) {
+        return;
+    }
+    let message = args.to_string();
+    match SINK.get() {
+        Some(sink) => sink(level, &message),
+        None => write_default(&message),
+    }
+}
+
+#[cfg(target_os = "unknown")]
+fn write_default(message: &str) {
+    web_sys::console::log_1(&wasm_bindgen::JsValue::from(message));
+}
+
+#[cfg(not(target_os = "unknown"))]
+fn write_default(message: &str) {
+    eprintln!("kanata-ls: {message}");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trace_overrides_configured_level() {
+        set_level(LogLevel::Warn);
+        assert!(enabled(LogLevel::Error));
+        assert!(!enabled(LogLevel::Info));
+        set_trace(TraceValue::Messages);
+        assert!(enabled(LogLevel::Debug));
+        assert!(!enabled(LogLevel::Trace));
+        set_trace(TraceValue::Off);
+        assert!(!enabled(LogLevel::Info));
+        set_level(LogLevel::Info);
+    }
+}
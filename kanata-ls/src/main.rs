@@ -2,11 +2,15 @@
 mod main_native;
 
 #[cfg(not(target_arch = "wasm32"))]
-fn main() -> anyhow::Result<()> {
+fn main() -> std::process::ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Some(exit_code) = kanata_ls::cli::run(&args) {
+        return exit_code;
+    }
     if let Err(e) = main_native::main() {
         eprintln!("kanata-ls: error: {e}")
     };
-    Ok(())
+    std::process::ExitCode::SUCCESS
 }
 
 #[cfg(target_arch = "wasm32")]
@@ -1,11 +1,29 @@
-use lsp_server::{Connection, ErrorCode, Message, Response};
+use lsp_server::{Connection, ErrorCode, Message, RequestId, Response};
 use lsp_types::{
-    notification::PublishDiagnostics,
-    request::{Formatting, GotoDefinition, HoverRequest, PrepareRenameRequest, Rename, Request},
-    InitializeParams, PublishDiagnosticsParams,
+    notification::{
+        DidChangeConfiguration, DidChangeWatchedFiles, LogMessage, LogTrace, Notification,
+        PublishDiagnostics,
+    },
+    request::{
+        CallHierarchyIncomingCalls, CallHierarchyOutgoingCalls, CallHierarchyPrepare,
+        CodeActionRequest, CodeLensRequest, DocumentHighlightRequest, Formatting, GotoDefinition,
+        GotoImplementation, HoverRequest, InlayHintRequest, LinkedEditingRange,
+        PrepareRenameRequest, RegisterCapability, Rename, Request, SignatureHelpRequest,
+        WorkspaceConfiguration,
+    },
+    ConfigurationItem, ConfigurationParams, DidChangeWatchedFilesRegistrationOptions,
+    FileSystemWatcher, GlobPattern, InitializeParams, LogMessageParams, LogTraceParams,
+    MessageType, PublishDiagnosticsParams, Registration, RegistrationParams, WatchKind,
 };
+use serde::{de::DeserializeOwned, Serialize};
 
-use kanata_ls::{log, KanataLanguageServer};
+use kanata_ls::{
+    log, log_error, log_info, log_warn,
+    logger::{self, LogLevel},
+    params_from_value, KanataLanguageServer, ResponseError, CONFIGURATION_SECTION,
+};
+
+const CONFIGURATION_REQUEST_ID_PREFIX: &str = "kanata-ls/configuration/";
 
 pub fn main() -> anyhow::Result<()> {
     eprintln!("kanata-ls starting");
@@ -16,6 +34,13 @@ pub fn main() -> anyhow::Result<()> {
     log!("waiting for client initialize");
     let (id, params) = connection.initialize_start()?;
 
+    // From now on, logs are shown in the LSP log of the editor instead of stderr.
+    logger::set_sink(Box::new(|level, message| {
+        let _ = connection
+            .sender
+            .send(Message::Notification(log_notification(level, message)));
+    }));
+
     log!("parsing the received message as InitializeParams struct");
     let params: InitializeParams = serde_json::from_value(params)?;
 
@@ -33,6 +58,46 @@ pub fn main() -> anyhow::Result<()> {
     let init_result = kls.initialize(&params);
 
     connection.initialize_finish(id, serde_json::to_value(init_result)?)?;
+    // `$/logTrace` can't be sent before the client gets the result of `initialize`.
+    if let Some(trace) = params.trace {
+        logger::set_trace(trace);
+    }
+
+    let supports_configuration_requests = params
+        .capabilities
+        .workspace
+        .as_ref()
+        .and_then(|x| x.configuration)
+        .unwrap_or(false);
+    let supports_watching_files = params
+        .capabilities
+        .workspace
+        .and_then(|x| x.did_change_watched_files)
+        .and_then(|x| x.dynamic_registration)
+        .unwrap_or(false);
+    if supports_watching_files {
+        log!("registering file watcher for kanata files");
+        connection
+            .sender
+            .send(Message::Request(watch_kanata_files_request()?))?;
+    } else {
+        log_info!("client doesn't support file watching, changes on disk won't be picked up");
+    }
+
+    // Settings may be given only this way, instead of `initializationOptions`.
+    let mut configuration_requests_count = 0;
+    let mut request_configuration = || -> anyhow::Result<()> {
+        if supports_configuration_requests {
+            configuration_requests_count += 1;
+            connection
+                .sender
+                .send(Message::Request(configuration_request(
+                    configuration_requests_count,
+                )))?;
+        }
+        Ok(())
+    };
+    request_configuration()?;
 
     for msg in &connection.receiver {
         match msg {
@@ -40,13 +105,44 @@ pub fn main() -> anyhow::Result<()> {
                 if connection.handle_shutdown(&req)? {
                     break;
                 }
+                let method = req.method.clone();
                 let resp = dispatch_request(&mut kls, req);
+                // Requests may have failed without the user noticing.
+                if let Some(err) = &resp.error {
+                    log_error!("request {} failed: {}", method, err.message);
+                }
                 connection.sender.send(Message::Response(resp))?;
             }
+            // Clients may send only changed settings or none at all,
+            // so all of them are requested if possible.
+            Message::Notification(not)
+                if not.method == DidChangeConfiguration::METHOD
+                    && supports_configuration_requests =>
+            {
+                request_configuration()?;
+            }
             Message::Notification(not) => {
-                kls.on_notification(&not.method, not.params);
+                if let Err(err) = kls.on_notification(&not.method, not.params) {
+                    log_error!("notification {} failed: {}", not.method, err);
+                }
+            }
+            Message::Response(resp) => {
+                let is_configuration_response = resp
+                    .id
+                    .to_string()
+                    .trim_matches('"')
+                    .starts_with(CONFIGURATION_REQUEST_ID_PREFIX);
+                if !is_configuration_response {
+                    continue;
+                }
+                match (resp.result, resp.error) {
+                    (Some(serde_json::Value::Array(mut sections)), _) if !sections.is_empty() => {
+                        kls.on_configuration_change(sections.swap_remove(0));
+                    }
+                    (_, Some(err)) => log_warn!("failed to get settings: {}", err.message),
+                    _ => log_warn!("client returned no settings"),
+                }
             }
-            Message::Response(_) => {}
         }
     }
 
@@ -59,38 +155,112 @@ pub fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Makes the client notify us about changes of kanata files on disk, so that
+/// the files that aren't opened in the editor, but are included, stay up to date.
+fn watch_kanata_files_request() -> anyhow::Result<lsp_server::Request> {
+    let options = DidChangeWatchedFilesRegistrationOptions {
+        watchers: vec![FileSystemWatcher {
+            glob_pattern: GlobPattern::String("**/*.kbd".to_string()),
+            kind: Some(WatchKind::all()),
+        }],
+    };
+    let params = RegistrationParams {
+        registrations: vec![Registration {
+            id: "kanata-ls/watch-kanata-files".to_string(),
+            method: DidChangeWatchedFiles::METHOD.to_string(),
+            register_options: Some(serde_json::to_value(options)?),
+        }],
+    };
+    Ok(lsp_server::Request::new(
+        RequestId::from("kanata-ls/register-file-watcher".to_string()),
+        RegisterCapability::METHOD.to_string(),
+        params,
+    ))
+}
+
+fn configuration_request(n: usize) -> lsp_server::Request {
+    let params = ConfigurationParams {
+        items: vec![ConfigurationItem {
+            scope_uri: None,
+            section: Some(CONFIGURATION_SECTION.to_string()),
+        }],
+    };
+    lsp_server::Request::new(
+        RequestId::from(format!("{CONFIGURATION_REQUEST_ID_PREFIX}{n}")),
+        WorkspaceConfiguration::METHOD.to_string(),
+        params,
+    )
+}
+
 fn dispatch_request(kls: &mut KanataLanguageServer, req: lsp_server::Request) -> Response {
-    let id = req.id.clone();
     match req.method.as_str() {
-        Formatting::METHOD => {
-            let params = serde_json::from_value(req.params).unwrap();
-            let result = kls.on_document_formatting(&params);
-            Response::new_ok(id, serde_json::to_value(result).unwrap())
-        }
-        GotoDefinition::METHOD => {
-            let params = serde_json::from_value(req.params).unwrap();
-            let result = kls.on_go_to_definition(&params);
-            Response::new_ok(id, serde_json::to_value(result).unwrap())
-        }
-        HoverRequest::METHOD => {
-            let params = serde_json::from_value(req.params).unwrap();
-            let result = kls.on_hover(&params);
-            Response::new_ok(id, serde_json::to_value(result).unwrap())
-        }
-        PrepareRenameRequest::METHOD => {
-            let params = serde_json::from_value(req.params).unwrap();
-            let result = kls.on_prepare_rename(&params);
-            Response::new_ok(id, serde_json::to_value(result).unwrap())
+        Formatting::METHOD => handle(req, |params| kls.on_document_formatting(params)),
+        GotoDefinition::METHOD => handle(req, |params| kls.on_go_to_definition(params)),
+        HoverRequest::METHOD => handle(req, |params| kls.on_hover(params)),
+        PrepareRenameRequest::METHOD => handle(req, |params| kls.on_prepare_rename(params)),
+        Rename::METHOD => handle(req, |params| kls.on_rename(params)),
+        GotoImplementation::METHOD => handle(req, |params| kls.on_go_to_implementation(params)),
+        LinkedEditingRange::METHOD => handle(req, |params| kls.on_linked_editing_range(params)),
+        CodeLensRequest::METHOD => handle(req, |params| kls.on_code_lens(params)),
+        SignatureHelpRequest::METHOD => handle(req, |params| kls.on_signature_help(params)),
+        InlayHintRequest::METHOD => handle(req, |params| kls.on_inlay_hint(params)),
+        CodeActionRequest::METHOD => handle(req, |params| kls.on_code_action(params)),
+        DocumentHighlightRequest::METHOD => handle(req, |params| kls.on_document_highlight(params)),
+        CallHierarchyPrepare::METHOD => handle(req, |params| kls.on_prepare_call_hierarchy(params)),
+        CallHierarchyIncomingCalls::METHOD => {
+            handle(req, |params| kls.on_call_hierarchy_incoming_calls(params))
         }
-        Rename::METHOD => {
-            let params = serde_json::from_value(req.params).unwrap();
-            let result = kls.on_rename(&params);
-            Response::new_ok(id, serde_json::to_value(result).unwrap())
+        CallHierarchyOutgoingCalls::METHOD => {
+            handle(req, |params| kls.on_call_hierarchy_outgoing_calls(params))
         }
         method => Response::new_err(
-            id,
+            req.id,
             ErrorCode::MethodNotFound as i32,
             format!("unknown request method: {method}"),
         ),
     }
 }
+
+/// Deserializes request params, calls the handler and serializes its result.
+/// Invalid params and handler errors are turned into error responses.
+fn handle<P: DeserializeOwned, R: Serialize>(
+    req: lsp_server::Request,
+    handler: impl FnOnce(&P) -> Result<R, ResponseError>,
+) -> Response {
+    let result = params_from_value(req.params)
+        .and_then(|params| handler(&params))
+        .and_then(|result| {
+            serde_json::to_value(result).map_err(|e| ResponseError::request_failed(e.to_string()))
+        });
+    match result {
+        Ok(result) => Response::new_ok(req.id, result),
+        Err(err) => Response::new_err(req.id, err.code, err.message),
+    }
+}
+
+/// Debug and trace messages are sent as `$/logTrace` if the client enabled tracing,
+/// so that they end up in its trace log, as the spec suggests.
+fn log_notification(level: LogLevel, message: &str) -> lsp_server::Notification {
+    if level >= LogLevel::Debug && logger::is_tracing() {
+        return lsp_server::Notification::new(
+            LogTrace::METHOD.to_string(),
+            LogTraceParams {
+                message: message.to_string(),
+                verbose: None,
+            },
+        );
+    }
+    let typ = match level {
+        LogLevel::Error => MessageType::ERROR,
+        LogLevel::Warn => MessageType::WARNING,
+        LogLevel::Info => MessageType::INFO,
+        LogLevel::Debug | LogLevel::Trace => MessageType::LOG,
+    };
+    lsp_server::Notification::new(
+        LogMessage::METHOD.to_string(),
+        LogMessageParams {
+            typ,
+            message: message.to_string(),
+        },
+    )
+}
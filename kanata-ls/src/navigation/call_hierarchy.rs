@@ -0,0 +1,659 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+use itertools::Itertools;
+use kanata_parser::cfg::sexpr::{self, SExpr, Span, Spanned};
+use lsp_types::{
+    CallHierarchyIncomingCall, CallHierarchyItem, CallHierarchyOutgoingCall, Position, Range,
+    SymbolKind, Url,
+};
+
+use crate::{
+    helpers::{
+        lsp_range_from_span, DefinitionLocations, Documents, HashSet, ReferenceKind,
+        ReferenceLocations,
+    },
+    log,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CallableKind {
+    Layer,
+    Alias,
+    /// Any other top-level block, e.g. `deffakekeys`. These can't be referenced,
+    /// but can still reference layers and aliases.
+    Block,
+}
+
+/// A node in the layer graph.
+#[derive(Debug)]
+struct Callable {
+    kind: CallableKind,
+    name: String,
+    uri: Url,
+    range: Range,
+    selection_range: Range,
+    /// Absolute byte offsets of the part of the document that belongs to this node.
+    body: (usize, usize),
+}
+
+impl Callable {
+    fn contains(&self, span: &Span) -> bool {
+        self.body.0 <= span.start() && span.end() <= self.body.1
+    }
+
+    fn to_item(&self) -> CallHierarchyItem {
+        let (kind, detail) = match self.kind {
+            CallableKind::Layer => (SymbolKind::NAMESPACE, "layer"),
+            CallableKind::Alias => (SymbolKind::FUNCTION, "alias"),
+            CallableKind::Block => (SymbolKind::OBJECT, "block"),
+        };
+        CallHierarchyItem {
+            name: self.name.clone(),
+            kind,
+            tags: None,
+            detail: Some(detail.to_string()),
+            uri: self.uri.clone(),
+            range: self.range,
+            selection_range: self.selection_range,
+            data: None,
+        }
+    }
+}
+
+fn callable_kind(ref_kind: ReferenceKind) -> Option<CallableKind> {
+    match ref_kind {
+        ReferenceKind::Layer => Some(CallableKind::Layer),
+        ReferenceKind::Alias => Some(CallableKind::Alias),
+        _ => None,
+    }
+}
+
+/// Actions that activate the layer given as their first argument. Other references
+/// to layers, e.g. `(layer base)` in `switch`, only test for the layer.
+const LAYER_SWITCHING_ACTIONS: [&str; 3] = ["layer-switch", "layer-toggle", "layer-while-held"];
+
+fn reference_kind(kind: CallableKind) -> Option<ReferenceKind> {
+    match kind {
+        CallableKind::Layer => Some(ReferenceKind::Layer),
+        CallableKind::Alias => Some(ReferenceKind::Alias),
+        CallableKind::Block => None,
+    }
+}
+
+/// Documents with a definition of given layer or alias.
+fn documents_defining<'a>(
+    definition_locations_by_doc: &'a HashMap<Url, DefinitionLocations>,
+    kind: CallableKind,
+    name: &str,
+) -> Vec<&'a Url> {
+    definition_locations_by_doc
+        .iter()
+        .filter(|(_, defs)| {
+            reference_kind(kind).is_some_and(|x| defs.get_definition(x, name).is_some())
+        })
+        .map(|(uri, _)| uri)
+        .collect()
+}
+
+/// Layers, aliases and remaining top-level blocks of the documents parsed so far.
+/// Documents are parsed only when a request needs something located in them.
+#[derive(Default)]
+struct Callables {
+    items: Vec<Callable>,
+    /// Start offsets of layer names that are arguments of layer-switching actions.
+    layer_switches: HashMap<Url, HashSet<usize>>,
+}
+
+impl Callables {
+    /// Adds layers, aliases and remaining top-level blocks from the given documents,
+    /// along with the parts of documents that belong to them. Documents that were
+    /// already added are skipped.
+    fn add_documents<'a>(
+        &mut self,
+        uris: impl IntoIterator<Item = &'a Url>,
+        definition_locations_by_doc: &HashMap<Url, DefinitionLocations>,
+        documents: &Documents,
+    ) {
+        for uri in uris.into_iter().sorted().dedup() {
+            if self.layer_switches.contains_key(uri) {
+                continue;
+            }
+            let doc = match documents.get(uri) {
+                Some(x) => x,
+                None => continue,
+            };
+            let toplevel_lists = match sexpr::parse_(&doc.text, "", false) {
+                Ok((exprs, _)) => exprs,
+                Err(e) => {
+                    log!("call hierarchy: failed to parse {}: {}", uri, e.msg);
+                    continue;
+                }
+            };
+            let mut layer_switches = HashSet::default();
+            for list in &toplevel_lists {
+                collect_layer_switches(&list.t, &mut layer_switches);
+            }
+            self.layer_switches.insert(uri.clone(), layer_switches);
+            self.add_document(uri, &toplevel_lists, definition_locations_by_doc.get(uri));
+        }
+    }
+
+    fn add_document(
+        &mut self,
+        uri: &Url,
+        toplevel_lists: &[Spanned<Vec<SExpr>>],
+        defs: Option<&DefinitionLocations>,
+    ) {
+        let containing_list = |span: &Span| {
+            toplevel_lists
+                .iter()
+                .find(|list| list.span.start() <= span.start() && span.end() <= list.span.end())
+        };
+
+        for (name, span) in defs.iter().flat_map(|defs| defs.0.layer.iter()) {
+            let list = match containing_list(span) {
+                Some(x) => x,
+                None => continue,
+            };
+            self.items.push(Callable {
+                kind: CallableKind::Layer,
+                name: name.clone(),
+                uri: uri.clone(),
+                range: lsp_range_from_span(&list.span),
+                selection_range: lsp_range_from_span(span),
+                body: (list.span.start(), list.span.end()),
+            });
+        }
+
+        for (name, span) in defs.iter().flat_map(|defs| defs.0.alias.iter()) {
+            // Alias body is the expression right after the alias name.
+            let body = containing_list(span).and_then(|list| {
+                list.t
+                    .iter()
+                    .skip_while(|x| x.span().start() != span.start())
+                    .nth(1)
+                    .map(SExpr::span)
+            });
+            let body = match body {
+                Some(x) => x,
+                None => continue,
+            };
+            let range = lsp_range_from_span(&Span {
+                start: span.start,
+                end: body.end,
+                ..body.clone()
+            });
+            self.items.push(Callable {
+                kind: CallableKind::Alias,
+                name: name.clone(),
+                uri: uri.clone(),
+                range,
+                selection_range: lsp_range_from_span(span),
+                body: (span.start(), body.end()),
+            });
+        }
+
+        for list in toplevel_lists {
+            let first_atom = match list.t.first() {
+                Some(SExpr::Atom(x)) => x,
+                _ => continue,
+            };
+            if first_atom.t.starts_with("deflayer") || first_atom.t.starts_with("defalias") {
+                continue;
+            }
+            self.items.push(Callable {
+                kind: CallableKind::Block,
+                name: first_atom.t.clone(),
+                uri: uri.clone(),
+                range: lsp_range_from_span(&list.span),
+                selection_range: lsp_range_from_span(&first_atom.span),
+                body: (list.span.start(), list.span.end()),
+            });
+        }
+    }
+
+    /// Whether a reference located in `uri` calls what it references. Aliases are always
+    /// called, layers only when switched to.
+    fn is_call(&self, uri: &Url, kind: CallableKind, span: &Span) -> bool {
+        match kind {
+            CallableKind::Alias => true,
+            CallableKind::Layer => self
+                .layer_switches
+                .get(uri)
+                .is_some_and(|x| x.contains(&span.start())),
+            CallableKind::Block => false,
+        }
+    }
+
+    fn find_definition(
+        &self,
+        kind: CallableKind,
+        name: &str,
+        source_doc: &Url,
+        search_all_docs: bool,
+    ) -> Option<usize> {
+        self.items.iter().position(|x| {
+            x.kind == kind && x.name == name && (search_all_docs || x.uri == *source_doc)
+        })
+    }
+
+    fn find_item(&self, item: &CallHierarchyItem) -> Option<usize> {
+        self.items
+            .iter()
+            .position(|x| x.uri == item.uri && x.selection_range == item.selection_range)
+    }
+}
+
+fn collect_layer_switches(exprs: &[SExpr], result: &mut HashSet<usize>) {
+    if let [SExpr::Atom(action), SExpr::Atom(layer), ..] = exprs {
+        if LAYER_SWITCHING_ACTIONS.contains(&action.t.as_str()) {
+            result.insert(layer.span.start());
+        }
+    }
+    for expr in exprs {
+        if let SExpr::List(list) = expr {
+            collect_layer_switches(&list.t, result);
+        }
+    }
+}
+
+/// Returns the layer or alias at given position, either by its definition or a reference to it.
+pub fn prepare_call_hierarchy(
+    pos: &Position,
+    source_doc: &Url,
+    definition_locations_by_doc: &HashMap<Url, DefinitionLocations>,
+    reference_locations_by_doc: &HashMap<Url, ReferenceLocations>,
+    documents: &Documents,
+    search_all_docs: bool, // Need to be set `true` for workspace mode and `false` otherwise.
+) -> Option<Vec<CallHierarchyItem>> {
+    let mut callables = Callables::default();
+
+    if let Some(def) = definition_locations_by_doc
+        .get(source_doc)
+        .and_then(|defs| defs.get_definition_at_position(pos))
+    {
+        let kind = callable_kind(def.ref_kind)?;
+        callables.add_documents([source_doc], definition_locations_by_doc, documents);
+        return callables
+            .find_definition(kind, &def.ref_name, source_doc, false)
+            .map(|i| vec![callables.items[i].to_item()]);
+    }
+
+    let reference = reference_locations_by_doc
+        .get(source_doc)?
+        .get_reference_at_position(pos)?;
+    let kind = callable_kind(reference.ref_kind)?;
+    callables.add_documents(
+        documents_defining(definition_locations_by_doc, kind, &reference.ref_name),
+        definition_locations_by_doc,
+        documents,
+    );
+    callables
+        .find_definition(kind, &reference.ref_name, source_doc, search_all_docs)
+        .map(|i| vec![callables.items[i].to_item()])
+}
+
+/// Returns places that call the layer or alias represented by `item`,
+/// grouped by the layer, alias or block they're located in.
+pub fn incoming_calls(
+    item: &CallHierarchyItem,
+    definition_locations_by_doc: &HashMap<Url, DefinitionLocations>,
+    reference_locations_by_doc: &HashMap<Url, ReferenceLocations>,
+    documents: &Documents,
+    search_all_docs: bool, // Need to be set `true` for workspace mode and `false` otherwise.
+) -> Vec<CallHierarchyIncomingCall> {
+    let mut callables = Callables::default();
+    callables.add_documents([&item.uri], definition_locations_by_doc, documents);
+    let target = match callables.find_item(item) {
+        Some(i) => &callables.items[i],
+        None => {
+            log!("call hierarchy: item not found: {:?}", item);
+            return vec![];
+        }
+    };
+    let (kind, name, target_uri) = (target.kind, target.name.clone(), target.uri.clone());
+    let ref_kind = match reference_kind(kind) {
+        Some(x) => x,
+        None => return vec![],
+    };
+
+    let references: Vec<(&Url, &[Span])> = reference_locations_by_doc
+        .iter()
+        .filter(|(uri, _)| search_all_docs || **uri == target_uri)
+        .map(|(uri, refs)| (uri, refs.get_references(ref_kind, &name)))
+        .filter(|(_, spans)| !spans.is_empty())
+        .collect();
+    callables.add_documents(
+        references.iter().map(|(uri, _)| *uri),
+        definition_locations_by_doc,
+        documents,
+    );
+
+    // Keyed by index in `callables`, to keep the output order stable.
+    let mut calls: BTreeMap<usize, Vec<Range>> = BTreeMap::new();
+    for (uri, spans) in references {
+        for span in spans.iter().filter(|x| callables.is_call(uri, kind, x)) {
+            // Prefer layers and aliases over blocks that may contain them.
+            let caller = callables
+                .items
+                .iter()
+                .enumerate()
+                .filter(|(_, x)| x.uri == *uri && x.contains(span))
+                .min_by_key(|(_, x)| x.kind == CallableKind::Block);
+            if let Some((i, _)) = caller {
+                calls.entry(i).or_default().push(lsp_range_from_span(span));
+            }
+        }
+    }
+
+    calls
+        .into_iter()
+        .map(|(i, from_ranges)| CallHierarchyIncomingCall {
+            from: callables.items[i].to_item(),
+            from_ranges,
+        })
+        .collect()
+}
+
+/// Returns layers switched to and aliases used from inside of the layer, alias or block
+/// represented by `item`.
+pub fn outgoing_calls(
+    item: &CallHierarchyItem,
+    definition_locations_by_doc: &HashMap<Url, DefinitionLocations>,
+    reference_locations_by_doc: &HashMap<Url, ReferenceLocations>,
+    documents: &Documents,
+    search_all_docs: bool, // Need to be set `true` for workspace mode and `false` otherwise.
+) -> Vec<CallHierarchyOutgoingCall> {
+    let mut callables = Callables::default();
+    callables.add_documents([&item.uri], definition_locations_by_doc, documents);
+    let caller = match callables.find_item(item) {
+        Some(i) => &callables.items[i],
+        None => {
+            log!("call hierarchy: item not found: {:?}", item);
+            return vec![];
+        }
+    };
+    let reference_locations = match reference_locations_by_doc.get(&caller.uri) {
+        Some(x) => x,
+        None => return vec![],
+    };
+
+    let mut references: Vec<(CallableKind, &String, Vec<&Span>)> = vec![];
+    for (kind, location_map) in [
+        (CallableKind::Layer, &reference_locations.0.layer),
+        (CallableKind::Alias, &reference_locations.0.alias),
+    ] {
+        for (name, spans) in location_map.0.iter() {
+            let spans: Vec<&Span> = spans
+                .iter()
+                .filter(|x| caller.contains(x) && callables.is_call(&caller.uri, kind, x))
+                .collect();
+            if !spans.is_empty() {
+                references.push((kind, name, spans));
+            }
+        }
+    }
+    let caller_uri = caller.uri.clone();
+
+    callables.add_documents(
+        references.iter().flat_map(|(kind, name, _)| {
+            documents_defining(definition_locations_by_doc, *kind, name)
+        }),
+        definition_locations_by_doc,
+        documents,
+    );
+    let mut calls: Vec<(&Callable, Vec<&Span>)> = references
+        .into_iter()
+        .filter_map(|(kind, name, spans)| {
+            // Skip undefined ones, kanata-parser will complain about them.
+            let target = callables.find_definition(kind, name, &caller_uri, search_all_docs)?;
+            Some((&callables.items[target], spans))
+        })
+        .collect();
+
+    // Order by first occurrence in the caller.
+    calls.sort_by_key(|(_, spans)| spans.iter().map(|x| x.start()).min());
+
+    calls
+        .into_iter()
+        .map(|(target, spans)| CallHierarchyOutgoingCall {
+            to: target.to_item(),
+            from_ranges: spans.into_iter().map(lsp_range_from_span).collect(),
+        })
+        .collect()
+}
+
+/// Returns the shortest chain of layers through which each layer can be reached
+/// from `base_layer`, by layer-switching actions used in layers directly or through aliases.
+/// Chains start with `base_layer` and end with the reached layer.
+/// Layers that can't be reached are missing.
+pub fn layer_chains_from_base(
+    base_layer: &str,
+    definition_locations_by_doc: &HashMap<Url, DefinitionLocations>,
+    reference_locations_by_doc: &HashMap<Url, ReferenceLocations>,
+    documents: &Documents,
+    search_all_docs: bool, // Need to be set `true` for workspace mode and `false` otherwise.
+) -> HashMap<String, Vec<String>> {
+    let mut all_callables = Callables::default();
+    all_callables.add_documents(
+        definition_locations_by_doc
+            .iter()
+            .filter(|(_, defs)| !defs.0.layer.is_empty() || !defs.0.alias.is_empty())
+            .map(|(uri, _)| uri),
+        definition_locations_by_doc,
+        documents,
+    );
+    let callables = &all_callables.items;
+
+    // Layers switched to and aliases used directly from each layer and alias.
+    let mut callees: Vec<Vec<usize>> = vec![vec![]; callables.len()];
+    for (caller_index, caller) in callables.iter().enumerate() {
+        let reference_locations = match reference_locations_by_doc.get(&caller.uri) {
+            Some(x) if caller.kind != CallableKind::Block => x,
+            _ => continue,
+        };
+        for (kind, location_map) in [
+            (CallableKind::Layer, &reference_locations.0.layer),
+            (CallableKind::Alias, &reference_locations.0.alias),
+        ] {
+            for (name, spans) in location_map.0.iter() {
+                if !spans
+                    .iter()
+                    .any(|x| caller.contains(x) && all_callables.is_call(&caller.uri, kind, x))
+                {
+                    continue;
+                }
+                let target =
+                    all_callables.find_definition(kind, name, &caller.uri, search_all_docs);
+                callees[caller_index].extend(target);
+            }
+        }
+    }
+
+    // Layers reachable from a layer, following aliases (which can use other aliases).
+    let reachable_layers = |layer: usize| -> Vec<usize> {
+        let mut result = vec![];
+        let mut visited = vec![layer];
+        let mut stack = callees[layer].clone();
+        while let Some(i) = stack.pop() {
+            if visited.contains(&i) {
+                continue;
+            }
+            visited.push(i);
+            match callables[i].kind {
+                CallableKind::Layer => result.push(i),
+                _ => stack.extend(&callees[i]),
+            }
+        }
+        result.sort_by_key(|&i| (&callables[i].uri, callables[i].body.0));
+        result
+    };
+
+    let base = match callables
+        .iter()
+        .position(|x| x.kind == CallableKind::Layer && x.name == base_layer)
+    {
+        Some(x) => x,
+        None => return HashMap::new(),
+    };
+    let mut chains: HashMap<String, Vec<String>> =
+        HashMap::from([(base_layer.to_string(), vec![base_layer.to_string()])]);
+    let mut queue = VecDeque::from([base]);
+    while let Some(layer) = queue.pop_front() {
+        let chain = chains[&callables[layer].name].clone();
+        for next in reachable_layers(layer) {
+            let name = &callables[next].name;
+            if chains.contains_key(name) {
+                continue;
+            }
+            let mut next_chain = chain.clone();
+            next_chain.push(name.clone());
+            chains.insert(name.clone(), next_chain);
+            queue.push_back(next);
+        }
+    }
+    chains
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        helpers::span_of,
+        tests::{parsed_workspace, url_of},
+    };
+
+    const MAIN: &str = "(include shared.kbd)
+(defsrc a b c)
+(deflayer base @nav (layer-toggle sym) c)
+(deflayer nav a b c)
+(deflayer sym a b (switch ((layer base)) x break))
+";
+    const SHARED: &str = "(defalias nav (layer-while-held nav))\n";
+
+    struct Workspace {
+        defs: HashMap<Url, DefinitionLocations>,
+        refs: HashMap<Url, ReferenceLocations>,
+        documents: Documents,
+    }
+
+    impl Workspace {
+        fn new() -> Self {
+            let (defs, refs, documents) =
+                parsed_workspace(&[("main.kbd", MAIN), ("shared.kbd", SHARED)]);
+            Self {
+                defs,
+                refs,
+                documents,
+            }
+        }
+
+        /// Prepares the call hierarchy at the first occurrence of `needle` after `skip` bytes.
+        fn item_at(&self, file_name: &str, skip: usize, needle: &str) -> CallHierarchyItem {
+            let text = &self.documents[&url_of(file_name)].text;
+            let pos = lsp_range_from_span(&span_of(file_name, text, skip, needle)).start;
+            let items = prepare_call_hierarchy(
+                &pos,
+                &url_of(file_name),
+                &self.defs,
+                &self.refs,
+                &self.documents,
+                true,
+            )
+            .expect("a layer or alias");
+            assert_eq!(items.len(), 1);
+            items.into_iter().next().unwrap()
+        }
+
+        fn incoming(&self, item: &CallHierarchyItem) -> Vec<(String, String, usize)> {
+            incoming_calls(item, &self.defs, &self.refs, &self.documents, true)
+                .into_iter()
+                .map(|call| {
+                    (
+                        call.from.name,
+                        call.from.uri.to_string(),
+                        call.from_ranges.len(),
+                    )
+                })
+                .collect()
+        }
+
+        fn outgoing(&self, item: &CallHierarchyItem) -> Vec<(String, String, usize)> {
+            outgoing_calls(item, &self.defs, &self.refs, &self.documents, true)
+                .into_iter()
+                .map(|call| {
+                    (
+                        call.to.name,
+                        call.to.uri.to_string(),
+                        call.from_ranges.len(),
+                    )
+                })
+                .collect()
+        }
+    }
+
+    fn call(name: &str, file_name: &str) -> (String, String, usize) {
+        (name.to_string(), url_of(file_name).to_string(), 1)
+    }
+
+    #[test]
+    fn test_prepare_call_hierarchy() {
+        let ws = Workspace::new();
+
+        let layer = ws.item_at("main.kbd", 0, "nav a b c");
+        assert_eq!(layer.name, "nav");
+        assert_eq!(layer.kind, SymbolKind::NAMESPACE);
+        assert_eq!(layer.uri, url_of("main.kbd"));
+
+        // From a reference in another file.
+        let alias = ws.item_at("main.kbd", 0, "@nav");
+        assert_eq!(alias.name, "nav");
+        assert_eq!(alias.kind, SymbolKind::FUNCTION);
+        assert_eq!(alias.uri, url_of("shared.kbd"));
+    }
+
+    #[test]
+    fn test_incoming_calls() {
+        let ws = Workspace::new();
+
+        let nav_layer = ws.item_at("main.kbd", 0, "nav a b c");
+        assert_eq!(ws.incoming(&nav_layer), vec![call("nav", "shared.kbd")]);
+
+        let nav_alias = ws.item_at("shared.kbd", 0, "nav");
+        assert_eq!(ws.incoming(&nav_alias), vec![call("base", "main.kbd")]);
+
+        let sym_layer = ws.item_at("main.kbd", 0, "sym a b");
+        assert_eq!(ws.incoming(&sym_layer), vec![call("base", "main.kbd")]);
+
+        // `(layer base)` in `switch` only tests for the layer.
+        let base_layer = ws.item_at("main.kbd", 0, "base");
+        assert_eq!(ws.incoming(&base_layer), vec![]);
+    }
+
+    #[test]
+    fn test_outgoing_calls() {
+        let ws = Workspace::new();
+
+        let base_layer = ws.item_at("main.kbd", 0, "base");
+        assert_eq!(
+            ws.outgoing(&base_layer),
+            vec![call("nav", "shared.kbd"), call("sym", "main.kbd")]
+        );
+
+        let nav_alias = ws.item_at("shared.kbd", 0, "nav");
+        assert_eq!(ws.outgoing(&nav_alias), vec![call("nav", "main.kbd")]);
+
+        // `(layer base)` in `switch` only tests for the layer.
+        let sym_layer = ws.item_at("main.kbd", 0, "sym a b");
+        assert_eq!(ws.outgoing(&sym_layer), vec![]);
+    }
+
+    #[test]
+    fn test_layer_chains_from_base() {
+        let ws = Workspace::new();
+        let chains = layer_chains_from_base("base", &ws.defs, &ws.refs, &ws.documents, true);
+        assert_eq!(chains["nav"], ["base", "nav"]);
+        assert_eq!(chains["sym"], ["base", "sym"]);
+        assert_eq!(chains.len(), 3);
+    }
+}
@@ -0,0 +1,119 @@
+use std::collections::{HashMap, HashSet};
+
+use itertools::Itertools;
+use lsp_types::{CodeLens, Command, Location, Range, Url};
+
+use crate::{
+    helpers::{lsp_range_from_span, DefinitionLocations, ReferenceLocations},
+    log,
+};
+
+/// Client-side command that shows a list of locations in a peek view.
+/// Arguments: document URI, position and locations.
+const SHOW_REFERENCES_COMMAND: &str = "vscode-kanata.showReferences";
+
+fn lens(range: Range, title: String, command: &str, arguments: Vec<serde_json::Value>) -> CodeLens {
+    CodeLens {
+        range,
+        command: Some(Command {
+            title,
+            command: command.to_string(),
+            arguments: Some(arguments),
+        }),
+        data: None,
+    }
+}
+
+pub type PathToUrlFn = Box<dyn Fn(&str) -> anyhow::Result<Url>>;
+
+/// Definitions and references found by parsing one main config file
+/// that includes the source document.
+pub struct WorkspaceLocations {
+    pub definition_locations_by_doc: HashMap<Url, DefinitionLocations>,
+    pub reference_locations_by_doc: HashMap<Url, ReferenceLocations>,
+    pub search_all_docs: bool, // Need to be set `true` for workspace mode and `false` otherwise.
+    pub layer_chains: HashMap<String, Vec<String>>,
+    pub path_to_url_fn: PathToUrlFn,
+}
+
+/// Returns code lenses with reference counts for layers, aliases, virtual keys
+/// and templates defined in `source_doc`. Layers additionally get a lens with
+/// the chain of layers through which they can be reached from the base layer.
+///
+/// A document included by several main config files is a part of each of `workspaces`,
+/// so references from all of them are counted.
+pub fn code_lenses(source_doc: &Url, workspaces: &[WorkspaceLocations]) -> Vec<CodeLens> {
+    let mut lenses = vec![];
+    let mut seen_definitions = HashSet::new();
+    for workspace in workspaces {
+        let defs = match workspace.definition_locations_by_doc.get(source_doc) {
+            Some(x) => x,
+            None => continue,
+        };
+        for (is_layer, location_map) in [
+            (true, &defs.0.layer),
+            (false, &defs.0.alias),
+            (false, &defs.0.virtual_key),
+            (false, &defs.0.template),
+        ] {
+            for (name, span) in location_map {
+                let range = lsp_range_from_span(span);
+                if !seen_definitions.insert(range) {
+                    continue;
+                }
+                let locations: Vec<Location> = workspaces
+                    .iter()
+                    .flat_map(|ws| references_to(source_doc, range, ws))
+                    .unique()
+                    .collect();
+
+                let title = match locations.len() {
+                    1 => "1 reference".to_string(),
+                    n => format!("{n} references"),
+                };
+                lenses.push(lens(
+                    range,
+                    title,
+                    SHOW_REFERENCES_COMMAND,
+                    vec![
+                        serde_json::json!(source_doc),
+                        serde_json::json!(range.start),
+                        serde_json::json!(locations),
+                    ],
+                ));
+
+                if is_layer {
+                    let chain = workspaces.iter().find_map(|ws| ws.layer_chains.get(name));
+                    let title = match chain {
+                        Some(chain) if chain.len() == 1 => "base layer".to_string(),
+                        Some(chain) => format!("reachable from: {}", chain.join(" → ")),
+                        None => "not reachable from base layer".to_string(),
+                    };
+                    // Empty command makes the lens not clickable.
+                    lenses.push(lens(range, title, "", vec![]));
+                }
+            }
+        }
+    }
+    lenses.sort_by_key(|x| x.range.start);
+    lenses
+}
+
+fn references_to(source_doc: &Url, range: Range, workspace: &WorkspaceLocations) -> Vec<Location> {
+    super::references_for_definition_at_pos(
+        &range.start,
+        source_doc,
+        &workspace.definition_locations_by_doc,
+        &workspace.reference_locations_by_doc,
+        workspace.search_all_docs,
+    )
+    .unwrap_or_default()
+    .into_iter()
+    .filter_map(|link| {
+        let uri = (workspace.path_to_url_fn)(&link.target_filename)
+            .map_err(|e| log!("code lens: {}", e))
+            .ok()?;
+        Some(Location::new(uri, link.target_range))
+    })
+    .collect()
+}
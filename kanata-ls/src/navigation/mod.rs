@@ -1,13 +1,17 @@
 use std::{collections::HashMap, iter::repeat};
 
 use itertools::Itertools;
-use lsp_types::{Position, Range, Url};
+use lsp_types::{DocumentHighlight, DocumentHighlightKind, Position, Range, Url};
 
 use crate::{
     helpers::{lsp_range_from_span, DefinitionLocations, ReferenceKind, ReferenceLocations},
     log,
 };
 
+pub mod call_hierarchy;
+pub mod code_lens;
+pub mod physical_keys;
+
 #[derive(Debug)]
 pub struct GotoDefinitionLink {
     pub source_range: Range,
@@ -36,8 +40,7 @@ pub fn goto_definition_for_token_at_pos(
         if search_all_docs {
             definition_locations_by_doc.iter()
         } else {
-            let item: DefinitionLocations =
-                definition_locations_by_doc.get(source_doc).unwrap().clone();
+            let item: DefinitionLocations = definition_locations_by_doc.get(source_doc)?.clone();
             map.insert(source_doc.clone(), item);
             map.iter()
         };
@@ -107,7 +110,7 @@ pub fn references_for_definition_at_pos(
     let refs_iter: std::collections::hash_map::Iter<Url, ReferenceLocations> = if match_all_refs {
         reference_locations_by_doc.iter()
     } else {
-        let item: ReferenceLocations = reference_locations_by_doc.get(source_doc).unwrap().clone();
+        let item: ReferenceLocations = reference_locations_by_doc.get(source_doc)?.clone();
         map.insert(source_doc.clone(), item);
         map.iter()
     };
@@ -138,6 +141,12 @@ pub fn references_for_definition_at_pos(
             reference_links.extend(locations)
         }
     }
+    // A file shared by several main config files has its references
+    // in the parse result of each of them.
+    let reference_links: Vec<_> = reference_links
+        .into_iter()
+        .unique_by(|x| (x.target_filename.clone(), x.target_range))
+        .collect();
     if reference_links.is_empty() {
         return None;
     }
@@ -152,6 +161,7 @@ pub struct LocationInfoWithFilename {
 }
 
 // Return all locations of symbols (without prefix character if present (like @ or $))
+// Fails if a file name can't be turned into an URL.
 pub fn all_locations_of_symbol_at_pos(
     source_pos: &Position,
     source_doc: &Url,
@@ -159,7 +169,7 @@ pub fn all_locations_of_symbol_at_pos(
     reference_locations_by_doc: &HashMap<Url, ReferenceLocations>,
     match_all_refs: bool, // Need to set `true` for workspace mode and `false` otherwise.
     path_to_url_fn: &dyn Fn(&str) -> anyhow::Result<Url>,
-) -> Vec<LocationInfoWithFilename> {
+) -> anyhow::Result<Vec<LocationInfoWithFilename>> {
     let i1 = goto_definition_for_token_at_pos(
         source_pos,
         source_doc,
@@ -181,34 +191,30 @@ pub fn all_locations_of_symbol_at_pos(
 
     // Query for backreferences. Kinda hacky thing to do.
 
-    let i1_rev = i1
-        .first()
-        .map(|x| {
-            references_for_definition_at_pos(
-                &x.target_range.start,
-                &path_to_url_fn(&x.target_filename).unwrap(),
-                definition_locations_by_doc,
-                reference_locations_by_doc,
-                match_all_refs,
-            )
-        })
-        .unwrap_or_else(|| None)
-        .unwrap_or_default();
-
-    let i2_rev = i2
-        .first()
-        .map(|x| {
-            goto_definition_for_token_at_pos(
-                &x.target_range.start,
-                &path_to_url_fn(&x.target_filename).unwrap(),
-                definition_locations_by_doc,
-                reference_locations_by_doc,
-                match_all_refs,
-            )
-        })
-        .unwrap_or_else(|| None)
-        .map(|x| vec![x])
-        .unwrap_or_default();
+    let i1_rev = match i1.first() {
+        Some(x) => references_for_definition_at_pos(
+            &x.target_range.start,
+            &path_to_url_fn(&x.target_filename)?,
+            definition_locations_by_doc,
+            reference_locations_by_doc,
+            match_all_refs,
+        )
+        .unwrap_or_default(),
+        None => vec![],
+    };
+
+    let i2_rev = match i2.first() {
+        Some(x) => goto_definition_for_token_at_pos(
+            &x.target_range.start,
+            &path_to_url_fn(&x.target_filename)?,
+            definition_locations_by_doc,
+            reference_locations_by_doc,
+            match_all_refs,
+        )
+        .into_iter()
+        .collect(),
+        None => vec![],
+    };
 
     let all = itertools::chain!(
         std::iter::zip(i1, repeat(true)),
@@ -226,7 +232,111 @@ pub fn all_locations_of_symbol_at_pos(
         is_definition,
     });
 
-    all
-    .dedup(). // FIXME: references_for_definition_at_pos gives duplicates, it needs fixing.
-    collect::<Vec<_>>()
+    // The definition is found both from a reference and from the definition itself.
+    Ok(all
+        .unique_by(|x| (x.filename.clone(), x.location_info.range))
+        .collect::<Vec<_>>())
+}
+
+/// Returns all occurrences of the symbol at given position in the source document.
+/// Definitions are marked as writes and references as reads. Prefix characters
+/// (like @ or $) are not included in ranges of references.
+pub fn document_highlights_for_token_at_pos(
+    pos: &Position,
+    source_doc: &Url,
+    definition_locations_by_doc: &HashMap<Url, DefinitionLocations>,
+    reference_locations_by_doc: &HashMap<Url, ReferenceLocations>,
+) -> Option<Vec<DocumentHighlight>> {
+    let definition_locations = definition_locations_by_doc.get(source_doc);
+    let reference_locations = reference_locations_by_doc.get(source_doc);
+
+    let symbol = definition_locations
+        .and_then(|x| x.get_definition_at_position(pos))
+        .or_else(|| reference_locations.and_then(|x| x.get_reference_at_position(pos)))?;
+
+    let definition = definition_locations
+        .and_then(|x| x.get_definition(symbol.ref_kind, &symbol.ref_name))
+        .map(|span| DocumentHighlight {
+            range: lsp_range_from_span(span),
+            kind: Some(DocumentHighlightKind::WRITE),
+        });
+
+    let references = reference_locations
+        .map(|x| x.get_references(symbol.ref_kind, &symbol.ref_name))
+        .unwrap_or_default()
+        .iter()
+        .map(|span| DocumentHighlight {
+            range: symbol
+                .ref_kind
+                .range_without_prefix(lsp_range_from_span(span)),
+            kind: Some(DocumentHighlightKind::READ),
+        });
+
+    Some(definition.into_iter().chain(references).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        helpers::span_of,
+        tests::{parsed_workspace, url_of},
+    };
+
+    const MAIN: &str = "(include shared.kbd)
+(defvar tt 200)
+(defalias x (tap-hold $tt $tt a b))
+(defsrc a b)
+(deflayer base @x @y)
+";
+    const SHARED: &str = "(defalias y @x)\n";
+
+    /// Highlights for the first occurrence of `needle` after `skip` bytes of `file_name`,
+    /// as the highlighted text and whether it's the definition.
+    fn highlights(file_name: &str, skip: usize, needle: &str) -> Option<Vec<(String, bool)>> {
+        let (defs, refs, documents) =
+            parsed_workspace(&[("main.kbd", MAIN), ("shared.kbd", SHARED)]);
+        let text = &documents[&url_of(file_name)].text;
+        let pos = lsp_range_from_span(&span_of(file_name, text, skip, needle)).start;
+        let highlights =
+            document_highlights_for_token_at_pos(&pos, &url_of(file_name), &defs, &refs)?;
+        let lines: Vec<&str> = text.lines().collect();
+        Some(
+            highlights
+                .into_iter()
+                .map(|x| {
+                    let r = x.range;
+                    assert_eq!(r.start.line, r.end.line);
+                    let line = lines[r.start.line as usize];
+                    let highlighted =
+                        line[r.start.character as usize..r.end.character as usize].to_string();
+                    (highlighted, x.kind == Some(DocumentHighlightKind::WRITE))
+                })
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_document_highlights_for_token_at_pos() {
+        let x = || ("x".to_string(), false);
+        let tt = || ("tt".to_string(), false);
+
+        // The `@` and `$` prefixes aren't highlighted.
+        let expected = vec![("x".to_string(), true), x()];
+        assert_eq!(highlights("main.kbd", 0, "x"), Some(expected.clone()));
+        assert_eq!(highlights("main.kbd", 0, "@x"), Some(expected));
+        assert_eq!(
+            highlights("main.kbd", 0, "$tt"),
+            Some(vec![("tt".to_string(), true), tt(), tt()])
+        );
+
+        // Only the current document is highlighted.
+        assert_eq!(highlights("shared.kbd", 0, "@x"), Some(vec![x()]));
+        assert_eq!(
+            highlights("main.kbd", 0, "@y"),
+            Some(vec![("y".to_string(), false)])
+        );
+
+        assert_eq!(highlights("main.kbd", 0, "tap-hold"), None);
+    }
 }
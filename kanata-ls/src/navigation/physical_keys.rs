@@ -0,0 +1,216 @@
+use lsp_types::{LocationLink, Position, Url};
+
+use crate::{
+    formatter::{
+        defsrc_layout::config_trees,
+        ext_tree::{parse_into_ext_tree_and_root_span, ExtParseTree},
+    },
+    helpers::Documents,
+    log, WorkspaceOptions,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyBlock {
+    Defsrc,
+    Deflayer,
+}
+
+/// A `defsrc` key or a `deflayer` slot.
+#[derive(Debug)]
+pub struct KeyAtPosition {
+    pub block: KeyBlock,
+    /// Index of the `defsrc`/`deflayer` block among top-level items of the tree.
+    pub block_index: usize,
+    /// Number of keys (or slots) in the block.
+    pub key_count: usize,
+    /// Index of the key (or slot) in the block, which is the same as
+    /// the index of the corresponding key in `defsrc`.
+    pub key_index: usize,
+    /// Whether the position is on an atom directly in the block,
+    /// and not somewhere inside of a list in a `deflayer` slot.
+    pub is_toplevel_atom: bool,
+}
+
+impl KeyAtPosition {
+    /// Path to the key (or slot) node in the tree.
+    pub fn path(&self) -> [usize; 2] {
+        match self.block {
+            KeyBlock::Defsrc => [self.block_index, self.key_index + 1],
+            KeyBlock::Deflayer => [self.block_index, self.key_index + 2],
+        }
+    }
+}
+
+/// Checks if there's a `defsrc` key or a `deflayer` slot at given position.
+pub fn key_at_position(tree: &ExtParseTree, pos: Position) -> Option<KeyAtPosition> {
+    let path_to_node = match tree.path_to_node_by_lsp_position(pos) {
+        Ok(x) => x,
+        Err(err) => {
+            log!("tree.path_to_node_by_lsp_position: {:?}", err);
+            return None;
+        }
+    };
+
+    let path: Vec<usize> = path_to_node.into_iter().map(|x| x as usize).collect();
+    key_at_path(tree, &path)
+}
+
+/// Checks if the node at `path` is a `defsrc` key or a `deflayer` slot, or is inside of one.
+pub fn key_at_path(tree: &ExtParseTree, path: &[usize]) -> Option<KeyAtPosition> {
+    let block_index = *path.first()?;
+    // When the node is inside of a list in a slot, it's the index of the whole slot.
+    let index_in_block = *path.get(1)?;
+    let is_toplevel_atom = path.len() == 2;
+
+    let (block_name, node_list) = tree.toplevel_block(block_index)?;
+    let (block, key_index, key_count) = match block_name {
+        // Skip "defsrc" keyword.
+        "defsrc" => (
+            KeyBlock::Defsrc,
+            index_in_block.checked_sub(1)?,
+            node_list.len() - 1,
+        ),
+        // Skip "deflayer" keyword and layer name.
+        "deflayer" => (
+            KeyBlock::Deflayer,
+            index_in_block.checked_sub(2)?,
+            node_list.len().checked_sub(2)?,
+        ),
+        _ => return None,
+    };
+
+    Some(KeyAtPosition {
+        block,
+        block_index,
+        key_count,
+        key_index,
+        is_toplevel_atom,
+    })
+}
+
+fn parse_doc(documents: &Documents, doc_uri: &Url) -> Option<ExtParseTree> {
+    let text = &documents.get(doc_uri)?.text;
+    match parse_into_ext_tree_and_root_span(text) {
+        Ok((tree, _)) => Some(tree),
+        Err(_) => {
+            log!("failed to parse current file into tree");
+            None
+        }
+    }
+}
+
+/// For a `deflayer` slot at given position, returns a link to the corresponding key in `defsrc`.
+pub fn defsrc_key_for_slot_at_pos(
+    pos: &Position,
+    source_doc: &Url,
+    documents: &Documents,
+    workspace_options: &WorkspaceOptions,
+) -> Option<LocationLink> {
+    let tree = parse_doc(documents, source_doc)?;
+    let key = key_at_position(&tree, *pos)?;
+    if key.block != KeyBlock::Deflayer || !key.is_toplevel_atom {
+        return None;
+    }
+
+    let trees = config_trees(workspace_options, documents, source_doc, &tree)
+        .map_err(|e| log!("defsrc_key_for_slot_at_pos: {}", e))
+        .ok()?;
+
+    for (target_uri, target_tree) in trees {
+        let (defsrc_index, defsrc) = match target_tree.toplevel_blocks("defsrc").next() {
+            Some(x) => x,
+            None => continue,
+        };
+        if defsrc.len() - 1 != key.key_count {
+            log!("deflayer vs defsrc item count mismatch");
+            return None;
+        }
+        let origin_range = tree.lsp_range_of_node(&key.path()).ok()?;
+        let target_range = target_tree
+            .lsp_range_of_node(&[defsrc_index, key.key_index + 1])
+            .ok()?;
+        return Some(LocationLink {
+            origin_selection_range: Some(origin_range),
+            target_uri,
+            target_range,
+            target_selection_range: target_range,
+        });
+    }
+
+    log!("defsrc not found");
+    None
+}
+
+/// For a `defsrc` key at given position, returns links to the corresponding slot
+/// in every `deflayer` that has the same number of items as `defsrc`.
+pub fn deflayer_slots_for_defsrc_key_at_pos(
+    pos: &Position,
+    source_doc: &Url,
+    documents: &Documents,
+    workspace_options: &WorkspaceOptions,
+) -> Option<Vec<LocationLink>> {
+    let tree = parse_doc(documents, source_doc)?;
+    let key = key_at_position(&tree, *pos)?;
+    if key.block != KeyBlock::Defsrc {
+        return None;
+    }
+    let origin_range = tree.lsp_range_of_node(&key.path()).ok()?;
+
+    let trees = config_trees(workspace_options, documents, source_doc, &tree)
+        .map_err(|e| log!("deflayer_slots_for_defsrc_key_at_pos: {}", e))
+        .ok()?;
+
+    let mut links = vec![];
+    for (target_uri, target_tree) in trees {
+        for (deflayer_index, deflayer) in target_tree.toplevel_blocks("deflayer") {
+            if deflayer.len() < 2 || deflayer.len() - 2 != key.key_count {
+                continue;
+            }
+            let target_range =
+                match target_tree.lsp_range_of_node(&[deflayer_index, key.key_index + 2]) {
+                    Ok(x) => x,
+                    Err(e) => {
+                        log!("lsp_range_of_node: {}", e);
+                        continue;
+                    }
+                };
+            links.push(LocationLink {
+                origin_selection_range: Some(origin_range),
+                target_uri: target_uri.clone(),
+                target_range,
+                target_selection_range: target_range,
+            });
+        }
+    }
+    Some(links)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formatter::ext_tree::parse_into_ext_tree;
+
+    #[test]
+    fn test_key_at_position() {
+        let tree =
+            parse_into_ext_tree("(defsrc a b)\n(deflayer base 1 (tap-hold 1 1 x y))").unwrap();
+
+        let key = key_at_position(&tree, Position::new(0, 10)).expect("on `b` in defsrc");
+        assert_eq!(key.block, KeyBlock::Defsrc);
+        assert_eq!((key.key_index, key.key_count), (1, 2));
+        assert_eq!(key.path(), [0, 2]);
+
+        let key = key_at_position(&tree, Position::new(1, 15)).expect("on `1` in deflayer");
+        assert_eq!(key.block, KeyBlock::Deflayer);
+        assert_eq!((key.key_index, key.key_count), (0, 2));
+        assert!(key.is_toplevel_atom);
+
+        let key = key_at_position(&tree, Position::new(1, 31)).expect("on `x` in deflayer");
+        assert_eq!(key.key_index, 1);
+        assert!(!key.is_toplevel_atom);
+        assert_eq!(key.path(), [1, 3]);
+
+        assert!(key_at_position(&tree, Position::new(0, 1)).is_none()); // "defsrc"
+        assert!(key_at_position(&tree, Position::new(1, 11)).is_none()); // "base"
+    }
+}
@@ -0,0 +1,172 @@
+/// Parameters of a list action, e.g. `(tap-hold tap-timeout hold-timeout tap-action hold-action)`.
+pub struct ActionSignature {
+    pub name: &'static str,
+    pub params: &'static [&'static str],
+    /// Index of the first parameter of a group that can be repeated
+    /// until the end of the list, e.g. items of `macro`.
+    pub repeat_from: Option<usize>,
+    pub doc: &'static str,
+}
+
+impl ActionSignature {
+    /// Returns index of the parameter that the list item at `arg_index` is for
+    /// (0 being the first item after the action name).
+    pub fn param_index(&self, arg_index: usize) -> Option<usize> {
+        match self.repeat_from {
+            Some(start) if arg_index >= start => {
+                Some(start + (arg_index - start) % (self.params.len() - start))
+            }
+            _ if arg_index < self.params.len() => Some(arg_index),
+            _ => None,
+        }
+    }
+}
+
+const TAP_HOLD: [&str; 4] = ["tap-timeout", "hold-timeout", "tap-action", "hold-action"];
+const TAP_HOLD_DOC: &str =
+    "Does `tap-action` when the key is released within `tap-timeout`, `hold-action` when it's held for `hold-timeout`.";
+
+macro_rules! action {
+    ($name:expr, [$($param:expr),*], $doc:expr) => {
+        ActionSignature { name: $name, params: &[$($param),*], repeat_from: None, doc: $doc }
+    };
+    ($name:expr, [$($param:expr),*], repeat_from = $repeat_from:expr, $doc:expr) => {
+        ActionSignature {
+            name: $name,
+            params: &[$($param),*],
+            repeat_from: Some($repeat_from),
+            doc: $doc,
+        }
+    };
+}
+
+#[rustfmt::skip]
+const ACTIONS: &[ActionSignature] = &[
+    action!("tap-hold", [TAP_HOLD[0], TAP_HOLD[1], TAP_HOLD[2], TAP_HOLD[3]], TAP_HOLD_DOC),
+    action!("tap-hold-press", [TAP_HOLD[0], TAP_HOLD[1], TAP_HOLD[2], TAP_HOLD[3]],
+        "Like `tap-hold`, but also does `hold-action` when another key is pressed."),
+    action!("tap-hold-release", [TAP_HOLD[0], TAP_HOLD[1], TAP_HOLD[2], TAP_HOLD[3]],
+        "Like `tap-hold`, but also does `hold-action` when another key is pressed and released."),
+    action!("tap-hold-press-timeout", [TAP_HOLD[0], TAP_HOLD[1], TAP_HOLD[2], TAP_HOLD[3], "timeout-action"],
+        "Like `tap-hold-press`, but does `timeout-action` instead of `hold-action` after `hold-timeout`."),
+    action!("tap-hold-release-timeout", [TAP_HOLD[0], TAP_HOLD[1], TAP_HOLD[2], TAP_HOLD[3], "timeout-action"],
+        "Like `tap-hold-release`, but does `timeout-action` instead of `hold-action` after `hold-timeout`."),
+    action!("tap-hold-release-keys", [TAP_HOLD[0], TAP_HOLD[1], TAP_HOLD[2], TAP_HOLD[3], "tap-trigger-keys"],
+        "Like `tap-hold-release`, but does `tap-action` early when one of `tap-trigger-keys` is pressed."),
+    action!("tap-hold-except-keys", [TAP_HOLD[0], TAP_HOLD[1], TAP_HOLD[2], TAP_HOLD[3], "tap-keys"],
+        "Like `tap-hold`, but always does `tap-action` when one of `tap-keys` is pressed."),
+    action!("tap-hold-release-tap-keys-release",
+        [TAP_HOLD[0], TAP_HOLD[1], TAP_HOLD[2], TAP_HOLD[3], "tap-trigger-keys-on-press", "tap-trigger-keys-on-release"],
+        "Like `tap-hold-release-keys`, with separate keys triggering `tap-action` on press and on release."),
+    action!("tap-dance", ["timeout", "actions"],
+        "Does the n-th action of `actions` when tapped n times, with taps at most `timeout` apart."),
+    action!("tap-dance-eager", ["timeout", "actions"],
+        "Like `tap-dance`, but does every action on the way to the n-th one."),
+    action!("one-shot", ["timeout", "action"],
+        "Keeps `action` active until the next key press, or until `timeout`."),
+    action!("one-shot-press", ["timeout", "action"],
+        "Keeps `action` active until the next key press, or until `timeout`."),
+    action!("one-shot-release", ["timeout", "action"],
+        "Keeps `action` active until the next key release, or until `timeout`."),
+    action!("one-shot-press-pcancel", ["timeout", "action"],
+        "Like `one-shot-press`, but pressing it again cancels it."),
+    action!("one-shot-release-pcancel", ["timeout", "action"],
+        "Like `one-shot-release`, but pressing it again cancels it."),
+    action!("multi", ["action"], repeat_from = 0,
+        "Does all actions at the same time."),
+    action!("macro", ["item"], repeat_from = 0,
+        "Types keys and runs actions one after another. Numbers are delays in milliseconds."),
+    action!("macro-release-cancel", ["item"], repeat_from = 0,
+        "Like `macro`, but releasing the key cancels it."),
+    action!("macro-cancel-on-press", ["item"], repeat_from = 0,
+        "Like `macro`, but pressing another key cancels it."),
+    action!("macro-repeat", ["item"], repeat_from = 0,
+        "Like `macro`, but repeats while the key is held."),
+    action!("macro-repeat-release-cancel", ["item"], repeat_from = 0,
+        "Like `macro-repeat`, but releasing the key cancels it."),
+    action!("macro-repeat-cancel-on-press", ["item"], repeat_from = 0,
+        "Like `macro-repeat`, but pressing another key cancels it."),
+    action!("switch", ["conditions", "action", "break|fallthrough"], repeat_from = 0,
+        "Does `action` of the first case whose `conditions` are met. With `fallthrough`, the next case is checked as well."),
+    action!("layer-switch", ["layer"],
+        "Changes the base layer."),
+    action!("layer-while-held", ["layer"],
+        "Activates `layer` while the key is held."),
+    action!("layer-toggle", ["layer"],
+        "Activates `layer` while the key is held."),
+    action!("release-layer", ["layer"],
+        "Deactivates `layer` activated with `layer-while-held`."),
+    action!("release-key", ["key"],
+        "Releases `key` if it's held."),
+    action!("caps-word", ["timeout"],
+        "Shifts letters and `-` until a different key is pressed, or until `timeout`."),
+    action!("caps-word-custom", ["timeout", "shifted-keys", "extra-keys"],
+        "Like `caps-word`, with `shifted-keys` being shifted and `extra-keys` not ending it."),
+    action!("caps-word-toggle", ["timeout"],
+        "Like `caps-word`, but pressing it again ends it."),
+    action!("caps-word-custom-toggle", ["timeout", "shifted-keys", "extra-keys"],
+        "Like `caps-word-custom`, but pressing it again ends it."),
+    action!("fork", ["left-action", "right-action", "right-trigger-keys"],
+        "Does `right-action` if one of `right-trigger-keys` is held, `left-action` otherwise."),
+    action!("unicode", ["character"],
+        "Types a unicode character."),
+    action!("cmd", ["program", "arg"], repeat_from = 1,
+        "Runs a program. Requires `danger-enable-cmd`."),
+    action!("cmd-output-keys", ["program", "arg"], repeat_from = 1,
+        "Runs a program and types its output. Requires `danger-enable-cmd`."),
+    action!("on-press", ["virtual-key-action", "virtual-key"],
+        "Does `virtual-key-action` (e.g. `tap-vkey`) on `virtual-key` when pressed."),
+    action!("on-release", ["virtual-key-action", "virtual-key"],
+        "Does `virtual-key-action` (e.g. `tap-vkey`) on `virtual-key` when released."),
+    action!("on-idle", ["idle-time", "virtual-key-action", "virtual-key"],
+        "Does `virtual-key-action` on `virtual-key` after no keys were pressed for `idle-time`."),
+    action!("hold-for-duration", ["duration", "virtual-key"],
+        "Presses `virtual-key` and releases it after `duration`."),
+    action!("sequence", ["timeout", "input-mode"],
+        "Starts typing a sequence defined in `defseq`, which ends after `timeout`."),
+    action!("chord", ["group", "key"],
+        "Part of the `defchords` `group` chord."),
+    action!("dynamic-macro-record", ["id"],
+        "Starts recording a dynamic macro."),
+    action!("dynamic-macro-play", ["id"],
+        "Plays a recorded dynamic macro."),
+    action!("arbitrary-code", ["code"],
+        "Sends a raw key code."),
+    action!("mwheel-up", ["interval", "distance"],
+        "Scrolls up by `distance` every `interval` milliseconds while held."),
+    action!("mwheel-down", ["interval", "distance"],
+        "Scrolls down by `distance` every `interval` milliseconds while held."),
+    action!("mwheel-left", ["interval", "distance"],
+        "Scrolls left by `distance` every `interval` milliseconds while held."),
+    action!("mwheel-right", ["interval", "distance"],
+        "Scrolls right by `distance` every `interval` milliseconds while held."),
+    action!("movemouse-up", ["interval", "distance"],
+        "Moves the mouse up by `distance` every `interval` milliseconds while held."),
+    action!("movemouse-down", ["interval", "distance"],
+        "Moves the mouse down by `distance` every `interval` milliseconds while held."),
+    action!("movemouse-left", ["interval", "distance"],
+        "Moves the mouse left by `distance` every `interval` milliseconds while held."),
+    action!("movemouse-right", ["interval", "distance"],
+        "Moves the mouse right by `distance` every `interval` milliseconds while held."),
+    action!("movemouse-accel-up", ["interval", "acceleration-time", "min-distance", "max-distance"],
+        "Moves the mouse up, speeding up from `min-distance` to `max-distance` over `acceleration-time`."),
+    action!("movemouse-accel-down", ["interval", "acceleration-time", "min-distance", "max-distance"],
+        "Moves the mouse down, speeding up from `min-distance` to `max-distance` over `acceleration-time`."),
+    action!("movemouse-accel-left", ["interval", "acceleration-time", "min-distance", "max-distance"],
+        "Moves the mouse left, speeding up from `min-distance` to `max-distance` over `acceleration-time`."),
+    action!("movemouse-accel-right", ["interval", "acceleration-time", "min-distance", "max-distance"],
+        "Moves the mouse right, speeding up from `min-distance` to `max-distance` over `acceleration-time`."),
+    action!("setmouse", ["x", "y"],
+        "Moves the mouse to the absolute position."),
+    action!("unmod", ["key"], repeat_from = 0,
+        "Presses keys with all modifiers released."),
+    action!("unshift", ["key"], repeat_from = 0,
+        "Presses keys with shift released."),
+    action!("t!", ["template", "arg"], repeat_from = 1,
+        "Expands the `deftemplate` `template` with given arguments."),
+];
+
+/// Returns the signature of the action with given name.
+pub fn action_signature(name: &str) -> Option<&'static ActionSignature> {
+    ACTIONS.iter().find(|x| x.name == name)
+}
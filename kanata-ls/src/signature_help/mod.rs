@@ -0,0 +1,128 @@
+use lsp_types::{
+    Documentation, MarkupContent, MarkupKind, ParameterInformation, ParameterLabel, Position,
+    SignatureHelp, SignatureInformation,
+};
+
+use crate::formatter::ext_tree::{Expr, ExtParseTree};
+
+pub mod actions;
+
+/// Returns path to the innermost list enclosing `pos`, along with the index
+/// of the list item at `pos`. If `pos` is between items, the index is the one
+/// of the item that would be typed there.
+fn enclosing_list_at(tree: &ExtParseTree, pos: Position) -> Option<(Vec<usize>, usize)> {
+    // Cursor on an item.
+    if let Ok(path) = tree.path_to_node_by_lsp_position(pos) {
+        let path: Vec<usize> = path.into_iter().map(|x| x as usize).collect();
+        if let Some((&index, list_path)) = path.split_last() {
+            if !list_path.is_empty() {
+                return Some((list_path.to_vec(), index));
+            }
+        }
+    }
+
+    // Cursor between items, e.g. right after a space or `(`.
+    let mut list_path: Option<Vec<usize>> = None;
+    let mut items_before: Vec<(Vec<usize>, lsp_types::Range)> = vec![];
+    tree.visit_nodes_with_ranges(&mut |path, expr, range| {
+        if range.start < pos && pos < range.end && matches!(expr, Expr::List(_)) {
+            // Parents are visited first, so the innermost list wins.
+            list_path = Some(path.to_vec());
+        }
+        items_before.push((path.to_vec(), range));
+    });
+    let list_path = list_path?;
+    let index = items_before
+        .iter()
+        .filter(|(path, range)| {
+            path.len() == list_path.len() + 1 && path.starts_with(&list_path) && range.end < pos
+        })
+        .count();
+    Some((list_path, index))
+}
+
+/// Returns parameters of the action at the head of the list enclosing `pos`,
+/// with the parameter at `pos` being active.
+pub fn signature_help(tree: &ExtParseTree, pos: Position) -> Option<SignatureHelp> {
+    let (list_path, index) = enclosing_list_at(tree, pos)?;
+    let action = match tree.get_node_by_path(&list_path).ok()? {
+        Expr::List(items) => match &items.get(0)?.expr {
+            Expr::Atom(x) => x,
+            Expr::List(_) => return None,
+        },
+        Expr::Atom(_) => return None,
+    };
+    let signature = actions::action_signature(action)?;
+
+    let mut label = format!("({}", signature.name);
+    let mut parameters = vec![];
+    for (i, param) in signature.params.iter().enumerate() {
+        label.push(' ');
+        let start = label.encode_utf16().count() as u32;
+        label.push_str(param);
+        let end = label.encode_utf16().count() as u32;
+        if signature.repeat_from.is_some_and(|x| x == i) {
+            label.push_str("...");
+        }
+        parameters.push(ParameterInformation {
+            label: ParameterLabel::LabelOffsets([start, end]),
+            documentation: None,
+        });
+    }
+    label.push(')');
+
+    // Index 0 is the action name.
+    let active_parameter = index
+        .checked_sub(1)
+        .and_then(|x| signature.param_index(x))
+        .map(|x| x as u32);
+    Some(SignatureHelp {
+        signatures: vec![SignatureInformation {
+            label,
+            documentation: Some(Documentation::MarkupContent(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value: signature.doc.to_string(),
+            })),
+            parameters: Some(parameters),
+            active_parameter,
+        }],
+        active_signature: Some(0),
+        active_parameter,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formatter::ext_tree::parse_into_ext_tree;
+
+    #[test]
+    fn test_signature_help() {
+        let tree = parse_into_ext_tree(
+            "(defalias\n  a (tap-hold 200 )\n  m (macro a b 10 c)\n  s (switch ((or a)) b break ())\n)",
+        )
+        .unwrap();
+        let active = |line, character| -> Option<(String, Option<u32>)> {
+            signature_help(&tree, Position::new(line, character))
+                .map(|x| (x.signatures[0].label.clone(), x.active_parameter))
+        };
+        let tap_hold = "(tap-hold tap-timeout hold-timeout tap-action hold-action)".to_string();
+
+        // On the action name.
+        assert_eq!(active(1, 6), Some((tap_hold.clone(), None)));
+        // On an argument.
+        assert_eq!(active(1, 15), Some((tap_hold.clone(), Some(0))));
+        // Right after an argument.
+        assert_eq!(active(1, 17), Some((tap_hold.clone(), Some(0))));
+        // After a space.
+        assert_eq!(active(1, 18), Some((tap_hold, Some(1))));
+        // Repeated parameters.
+        assert_eq!(active(2, 18).map(|x| x.1), Some(Some(0)));
+        assert_eq!(active(3, 26).map(|x| x.1), Some(Some(2)));
+        // Inside a list that isn't an action.
+        assert_eq!(active(3, 16), None);
+        // Inner empty list of `switch` isn't an action either.
+        assert_eq!(active(3, 30), None);
+        assert_eq!(active(0, 3), None);
+    }
+}
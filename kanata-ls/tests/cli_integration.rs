@@ -1,12 +1,14 @@
 use std::io::{Read, Write};
+use std::path::PathBuf;
 use std::process::{Command, Stdio};
 
+fn kls_path() -> String {
+    std::env::var("KANATA_LS_PATH").unwrap_or("./target/debug/kanata-ls".into())
+}
+
 #[test]
 fn lsp_initialize_via_stdio_works() {
-    let kls_path: String =
-        std::env::var("KANATA_LS_PATH").unwrap_or("./target/debug/kanata-ls".into());
-
-    let mut child = Command::new(kls_path)
+    let mut child = Command::new(kls_path())
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
@@ -33,9 +35,17 @@ fn lsp_initialize_via_stdio_works() {
     stdin.write_all(msg.as_bytes()).unwrap();
     stdin.flush().unwrap();
 
-    let mut stdout_buf = vec![0; 4096];
-    let n = stdout.read(&mut stdout_buf).unwrap();
-    let stdout_str = String::from_utf8_lossy(&stdout_buf[..n]);
+    // The header and the body may arrive in separate reads.
+    let mut stdout_buf = vec![];
+    let mut chunk = vec![0; 4096];
+    while !String::from_utf8_lossy(&stdout_buf).contains("capabilities") {
+        let n = stdout.read(&mut chunk).unwrap();
+        if n == 0 {
+            break;
+        }
+        stdout_buf.extend_from_slice(&chunk[..n]);
+    }
+    let stdout_str = String::from_utf8_lossy(&stdout_buf);
     println!("--- stdout start ---\n{}\n... stdout end ...", stdout_str);
 
     let mut stderr_buf = vec![0; 4096];
@@ -45,7 +55,231 @@ fn lsp_initialize_via_stdio_works() {
 
     assert!(stdout_str.contains("jsonrpc"));
     assert!(stdout_str.contains("capabilities"));
-    assert!(stderr_str.contains("no initializationOptions provided, using defaults"));
+    // Logs are forwarded to the client once it has sent `initialize`.
+    assert!(stdout_str.contains("window/logMessage"));
+    assert!(stdout_str.contains("no initializationOptions provided, using defaults"));
+    assert!(stderr_str.contains("kanata-ls starting"));
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+fn temp_dir_with_files(name: &str, files: &[(&str, &str)]) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("kanata-ls-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    for (filename, content) in files {
+        std::fs::write(dir.join(filename), content).unwrap();
+    }
+    dir
+}
+
+#[test]
+fn check_subcommand_reads_includes_from_disk() {
+    let dir = temp_dir_with_files(
+        "check-ok",
+        &[
+            (
+                "main.kbd",
+                "(include other.kbd)\n(defsrc a)\n(deflayer base b)\n",
+            ),
+            ("other.kbd", "(defalias x a)\n"),
+        ],
+    );
+
+    let output = Command::new(kls_path())
+        .args(["check", "--env", "FOO=bar", "--localkeys", "linux"])
+        .arg(dir.join("main.kbd"))
+        .output()
+        .expect("failed to start kanata-ls");
+    println!("{}", String::from_utf8_lossy(&output.stderr));
+    assert!(output.status.success());
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn check_subcommand_reports_errors() {
+    let dir = temp_dir_with_files(
+        "check-err",
+        &[("main.kbd", "(defsrc a)\n(include missing.kbd)\n")],
+    );
+
+    let output = Command::new(kls_path())
+        .arg("check")
+        .arg(dir.join("main.kbd"))
+        .output()
+        .expect("failed to start kanata-ls");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    println!("{}", stderr);
+    assert!(!output.status.success());
+    assert!(stderr.contains("main.kbd:2:"));
+    assert!(stderr.contains("2 | (include missing.kbd)"));
+    assert!(stderr.contains("  | ^"));
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn check_subcommand_machine_readable_output() {
+    let dir = temp_dir_with_files("check-format", &[("main.kbd", "(include missing.kbd)\n")]);
+
+    let output = Command::new(kls_path())
+        .args(["check", "--format", "json"])
+        .arg(dir.join("main.kbd"))
+        .output()
+        .expect("failed to start kanata-ls");
+    assert!(!output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let params: serde_json::Value =
+        serde_json::from_str(stdout.lines().next().expect("a line per file")).unwrap();
+    assert!(params["uri"].as_str().unwrap().ends_with("/main.kbd"));
+    assert_eq!(params["diagnostics"][0]["source"], "kanata-parser");
+
+    let output = Command::new(kls_path())
+        .args(["check", "--format", "sarif"])
+        .arg(dir.join("main.kbd"))
+        .output()
+        .expect("failed to start kanata-ls");
+    assert!(!output.status.success());
+    let log: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(log["version"], "2.1.0");
+    let result = &log["runs"][0]["results"][0];
+    assert_eq!(result["ruleId"], "kanata-parser/error");
+    assert_eq!(
+        result["locations"][0]["physicalLocation"]["region"]["startLine"],
+        1
+    );
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn fmt_subcommand_check_uses_defsrc_of_including_file() {
+    let dir = temp_dir_with_files(
+        "fmt-check",
+        &[
+            ("main.kbd", "(defsrc\n  a    b\n)\n(include layers.kbd)\n"),
+            ("layers.kbd", "(deflayer base\n  x y\n)\n"),
+        ],
+    );
+
+    let output = Command::new(kls_path())
+        .args(["fmt", "--check"])
+        .arg(&dir)
+        .output()
+        .expect("failed to start kanata-ls");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    println!("{stdout}\n{stderr}");
+    assert!(!output.status.success());
+    assert!(stdout.contains("+  x    y"));
+    assert!(!stderr.contains("warning"));
+    // Files aren't changed in check mode.
+    assert_eq!(
+        std::fs::read_to_string(dir.join("layers.kbd")).unwrap(),
+        "(deflayer base\n  x y\n)\n"
+    );
+
+    let output = Command::new(kls_path())
+        .arg("fmt")
+        .arg(dir.join("layers.kbd"))
+        .output()
+        .expect("failed to start kanata-ls");
+    assert!(output.status.success());
+    assert_eq!(
+        std::fs::read_to_string(dir.join("layers.kbd")).unwrap(),
+        "(deflayer base\n  x    y\n)\n"
+    );
+
+    let output = Command::new(kls_path())
+        .args(["fmt", "--check"])
+        .arg(&dir)
+        .output()
+        .expect("failed to start kanata-ls");
+    assert!(output.status.success());
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn fmt_subcommand_formats_stdin() {
+    let fmt_stdin = |args: &[&str], input: &str| {
+        let mut child = Command::new(kls_path())
+            .arg("fmt")
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .expect("failed to start kanata-ls");
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(input.as_bytes())
+            .unwrap();
+        child.wait_with_output().unwrap()
+    };
+    let unformatted = "(defsrc\n  a    b\n)\n(deflayer base\n  x y\n)\n";
+    let formatted = "(defsrc\n  a    b\n)\n(deflayer base\n  x    y\n)\n";
+
+    let output = fmt_stdin(&["-"], unformatted);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), formatted);
+
+    let output = fmt_stdin(&["--check", "-"], unformatted);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("--- <stdin>"));
+
+    let output = fmt_stdin(&["--check", "-"], formatted);
+    assert!(output.status.success());
+    assert!(output.stdout.is_empty());
+}
+
+#[test]
+fn lsp_request_for_untracked_document_returns_error() {
+    let mut child = Command::new(kls_path())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("failed to start kanata-ls");
+
+    let mut stdin = child.stdin.take().unwrap();
+    let mut stdout = child.stdout.take().unwrap();
+
+    let messages = [
+        r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{"processId":null,"rootUri":null,"capabilities":{}}}"#,
+        r#"{"jsonrpc":"2.0","method":"initialized","params":{}}"#,
+        r#"{"jsonrpc":"2.0","id":2,"method":"textDocument/hover","params":{"textDocument":{"uri":"file:///untracked.kbd"},"position":{"line":0,"character":0}}}"#,
+        r#"{"jsonrpc":"2.0","id":3,"method":"textDocument/hover","params":{"textDocument":{}}}"#,
+        r#"{"jsonrpc":"2.0","id":4,"method":"shutdown"}"#,
+    ];
+    for msg in messages {
+        write!(stdin, "Content-Length: {}\r\n\r\n{}", msg.len(), msg).unwrap();
+    }
+    stdin.flush().unwrap();
+
+    // The server is still running if it responds to the shutdown request.
+    let mut stdout_buf = vec![];
+    let mut chunk = vec![0; 4096];
+    while !String::from_utf8_lossy(&stdout_buf).contains(r#""id":4"#) {
+        let n = stdout.read(&mut chunk).unwrap();
+        if n == 0 {
+            break;
+        }
+        stdout_buf.extend_from_slice(&chunk[..n]);
+    }
+    let stdout_str = String::from_utf8_lossy(&stdout_buf);
+    println!("--- stdout start ---\n{}\n... stdout end ...", stdout_str);
+
+    assert!(stdout_str.contains(r#""id":4"#));
+    // RequestFailed
+    assert!(stdout_str.contains(r#""code":-32803"#));
+    assert!(stdout_str.contains("document isn't tracked: file:///untracked.kbd"));
+    // InvalidParams
+    assert!(stdout_str.contains(r#""code":-32602"#));
 
     let _ = child.kill();
     let _ = child.wait();
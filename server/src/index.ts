@@ -1,8 +1,10 @@
 import {
   createConnection,
+  ErrorCodes,
   ProposedFeatures,
   PublishDiagnosticsParams,
   InitializeParams,
+  ResponseError,
 } from "vscode-languageserver/node";
 import { KanataLanguageServer } from "../../out/kanata_ls";
 import { Console } from "console";
@@ -21,29 +23,113 @@ connection.onInitialize((params: InitializeParams) => {
     (params: PublishDiagnosticsParams) => connection.sendDiagnostics(params),
   );
 
-  connection.onNotification((...args) => kls.onNotification(...args));
+  connection.onNotification((...args) =>
+    handle(() => kls.onNotification(...args)),
+  );
+
+  // Workspace folder changes are handled by the connection itself, so they
+  // don't reach the catch-all notification handler above.
+  if (params.capabilities.workspace?.workspaceFolders) {
+    connection.onInitialized(() => {
+      connection.workspace.onDidChangeWorkspaceFolders((event) =>
+        handle(() =>
+          kls.onNotification("workspace/didChangeWorkspaceFolders", { event }),
+        ),
+      );
+    });
+  }
 
   connection.onDocumentFormatting((...args) =>
     // eslint-disable-next-line @typescript-eslint/no-unsafe-return
-    kls.onDocumentFormatting(args[0]),
+    handle(() => kls.onDocumentFormatting(args[0])),
+  );
+
+  connection.onDefinition((...args) =>
+    // eslint-disable-next-line @typescript-eslint/no-unsafe-return
+    handle(() => kls.onDefinition(args[0])),
   );
 
-  // eslint-disable-next-line @typescript-eslint/no-unsafe-return
-  connection.onDefinition((...args) => kls.onDefinition(args[0]));
+  connection.onImplementation((...args) =>
+    // eslint-disable-next-line @typescript-eslint/no-unsafe-return
+    handle(() => kls.onImplementation(args[0])),
+  );
 
   // connection.languages.semanticTokens.on((...args) =>
   //   // eslint-disable-next-line @typescript-eslint/no-unsafe-return, @typescript-eslint/no-unsafe-call
   //   kls.onSemanticTokens(args[0]),
   // );
 
-  // eslint-disable-next-line @typescript-eslint/no-unsafe-return
-  connection.onHover((...args) => kls.onHover(args[0]));
+  connection.onHover((...args) =>
+    // eslint-disable-next-line @typescript-eslint/no-unsafe-return
+    handle(() => kls.onHover(args[0])),
+  );
 
-  connection.onPrepareRename((...args) => kls.onPrepareRenameRequest(args[0]));
-  connection.onRenameRequest((...args) => kls.onRenameRequest(args[0]));
+  connection.onPrepareRename((...args) =>
+    handle(() => kls.onPrepareRenameRequest(args[0])),
+  );
+  connection.onRenameRequest((...args) =>
+    handle(() => kls.onRenameRequest(args[0])),
+  );
+
+  connection.languages.onLinkedEditingRange((...args) =>
+    // eslint-disable-next-line @typescript-eslint/no-unsafe-return
+    handle(() => kls.onLinkedEditingRange(args[0])),
+  );
+
+  connection.onCodeAction((...args) =>
+    // eslint-disable-next-line @typescript-eslint/no-unsafe-return
+    handle(() => kls.onCodeAction(args[0])),
+  );
+
+  connection.onCodeLens((...args) =>
+    // eslint-disable-next-line @typescript-eslint/no-unsafe-return
+    handle(() => kls.onCodeLens(args[0])),
+  );
+
+  connection.onSignatureHelp((...args) =>
+    // eslint-disable-next-line @typescript-eslint/no-unsafe-return
+    handle(() => kls.onSignatureHelp(args[0])),
+  );
+
+  connection.languages.inlayHint.on((...args) =>
+    // eslint-disable-next-line @typescript-eslint/no-unsafe-return
+    handle(() => kls.onInlayHint(args[0])),
+  );
+
+  connection.onDocumentHighlight((...args) =>
+    // eslint-disable-next-line @typescript-eslint/no-unsafe-return
+    handle(() => kls.onDocumentHighlight(args[0])),
+  );
+
+  connection.languages.callHierarchy.onPrepare((...args) =>
+    // eslint-disable-next-line @typescript-eslint/no-unsafe-return
+    handle(() => kls.onPrepareCallHierarchy(args[0])),
+  );
+  connection.languages.callHierarchy.onIncomingCalls((...args) =>
+    // eslint-disable-next-line @typescript-eslint/no-unsafe-return
+    handle(() => kls.onCallHierarchyIncomingCalls(args[0])),
+  );
+  connection.languages.callHierarchy.onOutgoingCalls((...args) =>
+    // eslint-disable-next-line @typescript-eslint/no-unsafe-return
+    handle(() => kls.onCallHierarchyOutgoingCalls(args[0])),
+  );
 
   // eslint-disable-next-line @typescript-eslint/no-unsafe-return, @typescript-eslint/no-unsafe-call
   return kls.initialize(params);
 });
 
+// The server throws `{ code, message }` objects on errors, e.g. invalid params.
+// They're logged and sent to the client as error responses, instead of
+// the connection's generic InternalError.
+function handle<T>(handler: () => T): T | ResponseError<void> {
+  try {
+    return handler();
+  } catch (e) {
+    const err = e as { code?: number; message?: string };
+    const message = err.message ?? String(e);
+    connection.console.error(`kanata-ls: ${message}`);
+    return new ResponseError(err.code ?? ErrorCodes.InternalError, message);
+  }
+}
+
 connection.listen();